@@ -0,0 +1,90 @@
+use std::{
+    fs::{self, File},
+    io::Write,
+    path::Path,
+};
+
+use log::debug;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::errors::PaperoniError;
+
+/// Packages the exported files into a ZIP archive. The files are stored with their paths relative
+/// to the output directory and directories, such as those holding the images of HTML exports,
+/// are added with their contents. Returns the number of files added to the archive.
+pub fn create_zip_bundle(
+    bundle_path: &str,
+    exported_files: &[String],
+    output_directory: &str,
+) -> Result<usize, PaperoniError> {
+    debug!("Creating ZIP bundle {:?}", bundle_path);
+    let mut writer = ZipWriter::new(File::create(bundle_path)?);
+    let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+    let mut file_count = 0;
+    for exported_file in exported_files {
+        file_count += add_to_bundle(
+            &mut writer,
+            Path::new(exported_file),
+            Path::new(output_directory),
+            options,
+        )?;
+    }
+    writer.finish()?;
+    debug!("Created ZIP bundle {:?}", bundle_path);
+    Ok(file_count)
+}
+
+fn add_to_bundle(
+    writer: &mut ZipWriter<File>,
+    path: &Path,
+    output_directory: &Path,
+    options: FileOptions,
+) -> Result<usize, PaperoniError> {
+    if path.is_dir() {
+        let mut file_count = 0;
+        for entry in fs::read_dir(path)? {
+            file_count += add_to_bundle(writer, &entry?.path(), output_directory, options)?;
+        }
+        return Ok(file_count);
+    }
+    writer.start_file(bundle_entry_name(path, output_directory), options)?;
+    writer.write_all(&fs::read(path)?)?;
+    Ok(1)
+}
+
+/// Returns the name of a file in the bundle. Files outside of the output directory are
+/// stored at the root of the bundle.
+fn bundle_entry_name(path: &Path, output_directory: &Path) -> String {
+    path.strip_prefix(output_directory)
+        .ok()
+        .filter(|relative_path| relative_path.file_name().is_some())
+        .or_else(|| path.file_name().map(Path::new))
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::bundle_entry_name;
+
+    #[test]
+    fn test_bundle_entry_name() {
+        assert_eq!(
+            "Article.epub",
+            bundle_entry_name(Path::new("./Article.epub"), Path::new("."))
+        );
+        assert_eq!(
+            "Article/image.png",
+            bundle_entry_name(Path::new("out/Article/image.png"), Path::new("out"))
+        );
+        assert_eq!(
+            "Article.txt",
+            bundle_entry_name(Path::new("elsewhere/Article.txt"), Path::new("out"))
+        );
+    }
+}
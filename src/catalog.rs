@@ -0,0 +1,232 @@
+use std::fs;
+
+use log::debug;
+use serde::Serialize;
+use url::Url;
+
+use crate::cli::AppConfig;
+use crate::errors::{ErrorKind, PaperoniError};
+use crate::extractor::Article;
+use crate::moz_readability::Readability;
+
+/// The metadata of a page that is written to the catalog with `--only-metadata`
+#[derive(Debug, PartialEq, Serialize)]
+pub struct CatalogEntry {
+    pub url: String,
+    pub title: String,
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub excerpt: Option<String>,
+    pub site_name: Option<String>,
+    pub lead_image: Option<String>,
+}
+
+impl CatalogEntry {
+    /// Reads the metadata of a page without extracting the article. The lead image is resolved
+    /// against the URL of the page.
+    pub fn from_html(html_str: &str, url: &str) -> Self {
+        let metadata = Readability::new(html_str).parse_metadata();
+        let lead_image = metadata.lead_image().map(|src| {
+            Url::parse(url)
+                .and_then(|base_url| base_url.join(src))
+                .map_or_else(|_| src.to_owned(), String::from)
+        });
+        CatalogEntry {
+            url: url.to_owned(),
+            title: metadata.title().to_owned(),
+            author: metadata.byline().cloned(),
+            date: metadata.published_time().cloned(),
+            excerpt: metadata.excerpt().cloned(),
+            site_name: metadata.site_name().cloned(),
+            lead_image,
+        }
+    }
+}
+
+/// Writes the entries to the catalog file. Paths ending in `.csv` are written as CSV with a
+/// header row and the rest as a JSON array.
+pub fn write_catalog(path: &str, entries: &[CatalogEntry]) -> Result<(), PaperoniError> {
+    debug!("Creating catalog {:?}", path);
+    let content = if path.to_lowercase().ends_with(".csv") {
+        catalog_to_csv(entries)
+    } else {
+        serde_json::to_string_pretty(entries)
+            .map_err(|err| PaperoniError::from(ErrorKind::IOError(err.to_string())))?
+    };
+    fs::write(path, content)
+        .map_err(|err| ErrorKind::IOError(format!("Unable to create {:?}: {}", path, err)).into())
+}
+
+/// Returns the entries as CSV
+fn catalog_to_csv(entries: &[CatalogEntry]) -> String {
+    let mut csv = csv_row(&[
+        Some("url"),
+        Some("title"),
+        Some("author"),
+        Some("date"),
+        Some("excerpt"),
+        Some("site_name"),
+        Some("lead_image"),
+    ]);
+    for entry in entries {
+        csv.push_str(&csv_row(&[
+            Some(&entry.url),
+            Some(&entry.title),
+            entry.author.as_deref(),
+            entry.date.as_deref(),
+            entry.excerpt.as_deref(),
+            entry.site_name.as_deref(),
+            entry.lead_image.as_deref(),
+        ]));
+    }
+    csv
+}
+
+/// The metadata of an exported article that is written to the CSV file passed to
+/// `--metadata-csv`
+#[derive(Debug, PartialEq)]
+pub struct MetadataRow {
+    pub title: String,
+    pub author: Option<String>,
+    pub date: Option<String>,
+    pub url: String,
+    pub word_count: usize,
+    /// The file the article was exported to
+    pub file: String,
+}
+
+/// Adds the row of an article that was exported to `file` when the metadata CSV is written
+pub fn add_metadata_row(
+    metadata_rows: &mut Vec<MetadataRow>,
+    app_config: &AppConfig,
+    article: &Article,
+    file: &str,
+) {
+    if app_config.metadata_csv.is_none() {
+        return;
+    }
+    metadata_rows.push(MetadataRow {
+        title: article.metadata().title().to_owned(),
+        author: article.metadata().byline().cloned(),
+        date: article.metadata().published_time().cloned(),
+        url: article.url.clone(),
+        word_count: article.sidecar().word_count,
+        file: file.to_owned(),
+    });
+}
+
+/// Writes the rows of the exported articles as CSV with a header row
+pub fn write_metadata_csv(path: &str, metadata_rows: &[MetadataRow]) -> Result<(), PaperoniError> {
+    debug!("Creating metadata CSV {:?}", path);
+    fs::write(path, metadata_to_csv(metadata_rows))
+        .map_err(|err| ErrorKind::IOError(format!("Unable to create {:?}: {}", path, err)).into())
+}
+
+fn metadata_to_csv(metadata_rows: &[MetadataRow]) -> String {
+    let mut csv = csv_row(&[
+        Some("title"),
+        Some("author"),
+        Some("date"),
+        Some("url"),
+        Some("word_count"),
+        Some("file"),
+    ]);
+    for row in metadata_rows {
+        csv.push_str(&csv_row(&[
+            Some(&row.title),
+            row.author.as_deref(),
+            row.date.as_deref(),
+            Some(&row.url),
+            Some(&row.word_count.to_string()),
+            Some(&row.file),
+        ]));
+    }
+    csv
+}
+
+/// Returns a CSV record ending in CRLF as described in RFC 4180. Fields holding commas, quotes
+/// or line breaks are quoted and their quotes are doubled. Missing fields are left empty.
+fn csv_row(fields: &[Option<&str>]) -> String {
+    let mut row = fields
+        .iter()
+        .map(|field| match field {
+            Some(field) if field.contains(&[',', '"', '\n', '\r'][..]) => {
+                format!("\"{}\"", field.replace('"', "\"\""))
+            }
+            Some(field) => (*field).to_owned(),
+            None => String::new(),
+        })
+        .collect::<Vec<_>>()
+        .join(",");
+    row.push_str("\r\n");
+    row
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_catalog_entry() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <head>
+                <title>Pepperoni, explained</title>
+                <meta name="author" content="Jane Doe">
+                <meta name="description" content="All about pepperoni">
+                <meta property="article:published_time" content="2021-06-01T08:00:00Z">
+                <meta property="og:image" content="/img/pepperoni.jpg">
+            </head>
+            <body><p>Pepperoni is a variety of salami.</p></body>
+        </html>
+        "#;
+        let entry = CatalogEntry::from_html(html_str, "https://example.com/posts/pepperoni");
+        assert_eq!(
+            CatalogEntry {
+                url: "https://example.com/posts/pepperoni".into(),
+                title: "Pepperoni, explained".into(),
+                author: Some("Jane Doe".into()),
+                date: Some("2021-06-01T08:00:00Z".into()),
+                excerpt: Some("All about pepperoni".into()),
+                site_name: None,
+                lead_image: Some("https://example.com/img/pepperoni.jpg".into()),
+            },
+            entry
+        );
+        assert_eq!(
+            "url,title,author,date,excerpt,site_name,lead_image\r\n\
+            https://example.com/posts/pepperoni,\"Pepperoni, explained\",Jane Doe,\
+            2021-06-01T08:00:00Z,All about pepperoni,,https://example.com/img/pepperoni.jpg\r\n",
+            catalog_to_csv(&[entry])
+        );
+    }
+
+    #[test]
+    fn test_metadata_to_csv() {
+        let rows = vec![
+            MetadataRow {
+                title: r#"Pepperoni, the "king" of toppings"#.into(),
+                author: None,
+                date: Some("2021-06-01".into()),
+                url: "https://example.com/pepperoni".into(),
+                word_count: 1200,
+                file: "./Pepperoni.epub".into(),
+            },
+            MetadataRow {
+                title: "Salami\nand more".into(),
+                author: Some("Jane Doe".into()),
+                date: None,
+                url: "https://example.com/salami".into(),
+                word_count: 800,
+                file: "./Salami.epub".into(),
+            },
+        ];
+        assert_eq!(
+            "title,author,date,url,word_count,file\r\n\
+            \"Pepperoni, the \"\"king\"\" of toppings\",,2021-06-01,https://example.com/pepperoni,1200,./Pepperoni.epub\r\n\
+            \"Salami\nand more\",Jane Doe,,https://example.com/salami,800,./Salami.epub\r\n",
+            metadata_to_csv(&rows)
+        );
+    }
+}
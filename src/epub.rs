@@ -1,24 +1,99 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fs::File;
+use std::io::{Cursor, Read, Seek, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use async_std::{stream, task};
 use comfy_table::{Attribute, Cell, CellAlignment, Color, ContentArrangement, Table};
-use epub_builder::{EpubBuilder, EpubContent, TocElement, ZipLibrary};
-use html5ever::tendril::fmt::Slice;
+use epub_builder::{EpubBuilder, EpubContent, ReferenceType, TocElement, ZipLibrary};
+use futures::StreamExt;
 use indicatif::{ProgressBar, ProgressStyle};
-use kuchiki::NodeRef;
-use log::{debug, error, info};
-
-use crate::{cli::AppConfig, errors::PaperoniError, extractor::Article};
+use itertools::Itertools;
+use kuchiki::{traits::*, ElementData, NodeDataRef, NodeRef};
+use log::{debug, error, info, warn};
+
+use crate::{
+    catalog::{add_metadata_row, MetadataRow},
+    cli::{AppConfig, CSSConfig, EpubDirection},
+    errors::{ErrorKind, PaperoniError},
+    extractor::{
+        insert_img_placeholder, serialize_body_to_xhtml_string, serialize_to_xhtml_string, Article,
+        Attachment, ResourceInfo, VALID_ATTR_CHARS_REGEX,
+    },
+    progress::{emit_failed, emit_generated},
+};
+
+/// The elements whose content is removed as a whole when shortening articles
+const BUDGET_BLOCKS: [&str; 15] = [
+    "blockquote",
+    "dl",
+    "figure",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "hr",
+    "ol",
+    "p",
+    "pre",
+    "table",
+    "ul",
+];
+const BYTES_PER_MB: u64 = 1024 * 1024;
+/// The number of EPUBs written at the same time with `--concurrent-epub-gen`
+const MAX_CONCURRENT_EPUBS: usize = 8;
+/// The number of EPUBs queued for writing before the downloads wait for the writer
+const MAX_QUEUED_EPUBS: usize = 4;
+/// The landmark the appendix is added under in the guide and the landmarks navigation.
+/// epub-builder has no appendix type so the type is replaced once the EPUB is generated, and no
+/// other content is given this type.
+const APPENDIX_REFERENCE_TYPE: ReferenceType = ReferenceType::Bibliography;
+/// The guide reference epub-builder writes for `APPENDIX_REFERENCE_TYPE` and its replacement.
+/// OPF 2 guide types that are not in the specification take the `other.` prefix.
+const APPENDIX_GUIDE_REFERENCE: (&str, &str) = (
+    r#"<reference type="bibliography""#,
+    r#"<reference type="other.appendix""#,
+);
+/// The landmark epub-builder writes for `APPENDIX_REFERENCE_TYPE` and its replacement
+const APPENDIX_LANDMARK: (&str, &str) = (r#"epub:type="bibliography""#, r#"epub:type="appendix""#);
+/// The primary language subtags of the languages written from right to left
+const RTL_LANGS: [&str; 11] = [
+    "ar", "ckb", "dv", "fa", "he", "iw", "ps", "sd", "ug", "ur", "yi",
+];
 
 lazy_static! {
-    static ref ESC_SEQ_REGEX: regex::Regex = regex::Regex::new(r#"(&|<|>|'|")"#).unwrap();
-    static ref VALID_ATTR_CHARS_REGEX: regex::Regex = regex::Regex::new(r#"[a-z0-9\-_:]"#).unwrap();
+    static ref UUID_REGEX: regex::Regex = regex::Regex::new(
+        r#"[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}"#
+    )
+    .unwrap();
+    static ref TIMESTAMP_REGEX: regex::Regex =
+        regex::Regex::new(r#"\d{4}-\d{2}-\d{2}T\d{2}:\d{2}:\d{2}(\.\d+)?(Z|[+-]\d{2}:\d{2})"#)
+            .unwrap();
+    static ref SELF_CLOSING_TAG_REGEX: regex::Regex =
+        regex::Regex::new(r#"<([a-zA-Z][\w:.\-]*)([^<>]*?)\s*/>"#).unwrap();
+    static ref XML_TOKEN_REGEX: regex::Regex = regex::Regex::new(
+        r#"(?s)<!--.*?-->|<!\[CDATA\[.*?\]\]>|<[!?][^>]*>|</([^\s/>]+)\s*>|<([^\s/>!?]+)(?:[^>"']|"[^"]*"|'[^']*')*?(/?)>"#
+    )
+    .unwrap();
+    static ref XML_ENTITY_REGEX: regex::Regex = regex::Regex::new(r#"&([^;\s<&]*)(;?)"#).unwrap();
+    static ref CHAPTER_TOKEN_REGEX: regex::Regex =
+        regex::Regex::new(r#"\{\{\s*(content|title|author|url|lang)\s*\}\}"#).unwrap();
 }
 
+/// Exports the articles to EPUBs. Errors writing the files that are not EPUBs, such as the index
+/// and the OPDS catalog, are added to `file_errors` since they are not failed articles.
 pub fn generate_epubs(
     articles: Vec<Article>,
     app_config: &AppConfig,
     successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
+    file_errors: &mut Vec<PaperoniError>,
 ) -> Result<(), Vec<PaperoniError>> {
     if articles.is_empty() {
         return Ok(());
@@ -40,8 +115,12 @@ pub fn generate_epubs(
 
     let mut errors: Vec<PaperoniError> = Vec::new();
 
-    match app_config.merged {
-        Some(ref name) => {
+    match app_config
+        .merged
+        .as_ref()
+        .or_else(|| app_config.append_to.as_ref())
+    {
+        Some(name) => {
             successful_articles_table.set_header(vec![Cell::new("Table of Contents")
                 .add_attribute(Attribute::Bold)
                 .set_alignment(CellAlignment::Center)
@@ -80,71 +159,216 @@ pub fn generate_epubs(
                     return Err(errors);
                 }
             }
+
+            // Paths already used in the EPUB. These are tracked so that articles sharing an
+            // image, or articles appended to an existing EPUB, do not add duplicate entries
+            let mut added_paths = HashSet::new();
+            let existing_epub = match app_config.append_to {
+                Some(_) => match ExistingEpub::read(name).and_then(|existing_epub| {
+                    existing_epub.add_to(&mut epub, &mut added_paths, app_config)?;
+                    Ok(existing_epub)
+                }) {
+                    Ok(existing_epub) => Some(existing_epub),
+                    Err(mut paperoni_err) => {
+                        error!("Unable to append to {:?}", name);
+                        paperoni_err.set_article_source(name);
+                        errors.push(paperoni_err);
+                        return Err(errors);
+                    }
+                },
+                None => None,
+            };
+            let book_title = existing_epub
+                .as_ref()
+                .and_then(|existing_epub| existing_epub.title.as_deref())
+                .unwrap_or_else(|| name.as_str());
+            let existing_chapter_count = existing_epub
+                .as_ref()
+                .map_or(0, |existing_epub| existing_epub.chapters.len());
+            let lang = majority_lang(&articles);
+            let page_direction = app_config
+                .epub_direction
+                .as_ref()
+                .and_then(|direction| text_direction(direction, lang.as_deref()));
+            let publisher = common_site_name(&articles);
+            let mut package_changes = PackageChanges {
+                page_direction,
+                publisher: publisher.as_deref(),
+                has_appendix: false,
+            };
+            for section in articles
+                .iter()
+                .filter_map(|article| article.metadata().section())
+                .unique()
+            {
+                if let Err(err) = epub.metadata("subject", replace_escaped_characters(section)) {
+                    let mut paperoni_err: PaperoniError = err.into();
+                    paperoni_err.set_article_source(name);
+                    errors.push(paperoni_err);
+                    return Err(errors);
+                }
+            }
+            if let Some(lang) = lang {
+                if let Err(err) = epub.metadata("lang", lang) {
+                    let mut paperoni_err: PaperoniError = err.into();
+                    paperoni_err.set_article_source(name);
+                    errors.push(paperoni_err);
+                    return Err(errors);
+                }
+            }
+
             articles
                 .iter()
                 .enumerate()
                 .fold(&mut epub, |epub, (idx, article)| {
                     let mut article_result = || -> Result<(), PaperoniError> {
-                        let content_url = format!("article_{}.xhtml", idx);
-                        let mut xhtml_buf = Vec::new();
-                        let header_level_tocs =
-                            get_header_level_toc_vec(&content_url, article.node_ref());
-
-                        serialize_to_xhtml(article.node_ref(), &mut xhtml_buf)?;
-                        let xhtml_str = std::str::from_utf8(&xhtml_buf)?;
-                        let section_name = article.metadata().title();
+                        let skipped_imgs = app_config
+                            .per_article_budget
+                            .map(|budget| {
+                                fit_to_budget(
+                                    article,
+                                    budget,
+                                    app_config.is_keeping_aspect_placeholders,
+                                )
+                            })
+                            .unwrap_or_default();
+                        let content_url = (idx..)
+                            .map(|content_idx| format!("article_{}.xhtml", content_idx))
+                            .find(|content_url| added_paths.insert(content_url.clone()))
+                            .unwrap();
+                        let header_level_tocs = if app_config.is_generating_header_toc {
+                            get_header_level_toc_vec(
+                                &content_url,
+                                article.node_ref(),
+                                app_config.is_clamping_heading_levels,
+                            )
+                        } else {
+                            Vec::new()
+                        };
+
+                        link_attachments(article);
+                        set_chapter_lang(article);
+                        set_chapter_direction(article, app_config);
+                        let xhtml_str = chapter_xhtml(article, app_config)?;
+                        let section_name = match app_config.chapter_template.as_deref() {
+                            None if app_config.is_including_url_in_title => {
+                                article.title_with_url()
+                            }
+                            template => {
+                                chapter_title(template, existing_chapter_count + idx + 1, article)
+                            }
+                        };
                         let mut content = EpubContent::new(&content_url, xhtml_str.as_bytes())
-                            .title(replace_escaped_characters(section_name));
+                            .title(replace_escaped_characters(&section_name));
 
                         for toc_element in header_level_tocs {
                             content = content.child(toc_element);
                         }
 
-                        epub.metadata("title", replace_escaped_characters(name))?;
+                        epub.metadata("title", replace_escaped_characters(book_title))?;
                         epub.add_content(content)?;
                         info!("Adding images for {:?}", name);
-                        article.img_urls.iter().for_each(|img| {
-                            // TODO: Add error handling and return errors as a vec
-                            let mut file_path = std::env::temp_dir();
-                            file_path.push(&img.0);
-
-                            let img_buf = File::open(&file_path).expect("Can't read file");
-                            epub.add_resource(
-                                file_path.file_name().unwrap(),
-                                img_buf,
-                                img.1.as_ref().unwrap(),
-                            )
-                            .unwrap();
+                        let imgs = article.img_urls.iter().filter(|img| {
+                            !skipped_imgs.contains(&img.0) && added_paths.insert(img.0.clone())
                         });
+                        for img in imgs {
+                            add_image(epub, img)?;
+                        }
                         info!("Added images for {:?}", name);
+                        add_attachments(epub, &article.attachments, &mut added_paths)?;
                         Ok(())
                     };
                     if let Err(mut error) = article_result() {
                         error.set_article_source(&article.url);
+                        emit_failed(app_config, &article.url, &error);
                         errors.push(error);
                     }
                     bar.inc(1);
                     successful_articles_table.add_row(vec![article.metadata().title()]);
                     epub
                 });
-            let appendix = generate_appendix(articles.iter().collect());
-            if let Err(err) = epub.add_content(
-                EpubContent::new("appendix.xhtml", appendix.as_bytes())
-                    .title(replace_escaped_characters("Article Sources")),
-            ) {
-                let mut paperoni_err: PaperoniError = err.into();
-                paperoni_err.set_article_source(&name);
-                errors.push(paperoni_err);
-                return Err(errors);
-            }
-
-            let mut out_file = File::create(&name).unwrap();
-            match epub.generate(&mut out_file) {
-                Ok(_) => (),
-                Err(err) => {
+            let previous_sources = existing_epub
+                .as_ref()
+                .and_then(|existing_epub| existing_epub.previous_sources.as_deref())
+                .unwrap_or("");
+            // The appendix of an EPUB that articles are appended to is kept so that the sources
+            // of its earlier articles are not lost
+            if !app_config.is_inlining_sources || !previous_sources.is_empty() {
+                let appendix = generate_appendix_with_previous_sources(
+                    articles.iter().collect(),
+                    previous_sources,
+                );
+                if let Err(err) = epub.add_content(
+                    EpubContent::new("appendix.xhtml", appendix.as_bytes())
+                        .title(replace_escaped_characters("Article Sources"))
+                        .reftype(APPENDIX_REFERENCE_TYPE),
+                ) {
                     let mut paperoni_err: PaperoniError = err.into();
                     paperoni_err.set_article_source(&name);
                     errors.push(paperoni_err);
+                    return Err(errors);
+                }
+                package_changes.has_appendix = true;
+            }
+
+            let article_urls = articles
+                .iter()
+                .map(|article| article.url.as_str())
+                .collect::<Vec<_>>();
+            let result = if app_config.is_writing_to_stdout {
+                generate_epub(
+                    &mut epub,
+                    &mut std::io::stdout(),
+                    &name,
+                    &article_urls,
+                    &package_changes,
+                    app_config,
+                )
+            } else if app_config.append_to.is_some() {
+                // The new EPUB is written next to the one it is appended to and only replaces it
+                // once it is complete so that the existing EPUB is not lost when generation fails
+                let temp_name = format!("{}.part", name);
+                create_epub_file(&temp_name)
+                    .and_then(|mut out_file| {
+                        generate_epub(
+                            &mut epub,
+                            &mut out_file,
+                            &name,
+                            &article_urls,
+                            &package_changes,
+                            app_config,
+                        )
+                    })
+                    .and_then(|_| {
+                        std::fs::rename(&temp_name, name).map_err(|err| {
+                            ErrorKind::IOError(format!("Unable to replace {:?}: {}", name, err))
+                                .into()
+                        })
+                    })
+                    .map_err(|err| {
+                        let _ = std::fs::remove_file(&temp_name);
+                        err
+                    })
+            } else {
+                create_epub_file(&name).and_then(|mut out_file| {
+                    generate_epub(
+                        &mut epub,
+                        &mut out_file,
+                        &name,
+                        &article_urls,
+                        &package_changes,
+                        app_config,
+                    )
+                })
+            };
+            match result {
+                Ok(_) => (),
+                Err(mut paperoni_err) => {
+                    paperoni_err.set_article_source(&name);
+                    for article in &articles {
+                        emit_failed(app_config, &article.url, &paperoni_err);
+                    }
+                    errors.push(paperoni_err);
                     error!("Failed to generate epub: {}", name);
                     bar.finish_with_message("epub generation failed\n");
                     return Err(errors);
@@ -153,94 +377,1085 @@ pub fn generate_epubs(
 
             bar.finish_with_message("Generated epub\n");
             debug!("Created {:?}", name);
-            println!("Created {:?}", name);
+            for article in &articles {
+                let has_failed = errors
+                    .iter()
+                    .any(|error| error.article_source().as_deref() == Some(article.url.as_str()));
+                if !has_failed {
+                    emit_generated(app_config, &article.url, &name);
+                    add_metadata_row(metadata_rows, app_config, article, &name);
+                }
+            }
+            if app_config.can_print_to_stdout() {
+                println!("Created {:?}", name);
+            }
+
+            if let Some(ref opds_path) = app_config.opds_path {
+                let title = name.trim_end_matches(".epub");
+                let entries = vec![OpdsEntry {
+                    path: name,
+                    title,
+                    author: None,
+                    source: None,
+                }];
+                if let Err(err) = write_opds_catalog(opds_path, &entries, app_config) {
+                    file_errors.push(err);
+                }
+            }
         }
         None => {
-            successful_articles_table
-                .set_header(vec![Cell::new("Downloaded articles")
-                    .add_attribute(Attribute::Bold)
-                    .set_alignment(CellAlignment::Center)
-                    .fg(Color::Green)])
-                .set_content_arrangement(ContentArrangement::Dynamic);
-
-            for article in &articles {
-                let mut result = || -> Result<(), PaperoniError> {
-                    let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
-                    let file_name = format!(
-                        "{}/{}.epub",
-                        app_config.output_directory.as_deref().unwrap_or("."),
-                        article
-                            .metadata()
-                            .title()
-                            .replace("/", " ")
-                            .replace("\\", " ")
-                    );
-                    debug!("Creating {:?}", file_name);
-                    let mut out_file = File::create(&file_name).unwrap();
-                    let mut xhtml_buf = Vec::new();
-                    let header_level_tocs =
-                        get_header_level_toc_vec("index.xhtml", article.node_ref());
-                    serialize_to_xhtml(article.node_ref(), &mut xhtml_buf)
-                        .expect("Unable to serialize to xhtml");
-                    let xhtml_str = std::str::from_utf8(&xhtml_buf).unwrap();
-
-                    if let Some(author) = article.metadata().byline() {
-                        epub.metadata("author", replace_escaped_characters(author))?;
+            set_article_table_header(successful_articles_table);
+
+            let mut generated_epubs: Vec<(String, &Article)> = Vec::new();
+            if app_config.is_generating_epubs_concurrently && !app_config.is_writing_to_stdout {
+                let (concurrent_epubs, concurrent_errors) = export_article_epubs_concurrently(
+                    &articles,
+                    app_config,
+                    &bar,
+                    successful_articles_table,
+                    exported_files,
+                    metadata_rows,
+                );
+                generated_epubs = concurrent_epubs;
+                errors.extend(concurrent_errors);
+            } else {
+                for article in &articles {
+                    match export_article_epub(
+                        article,
+                        app_config,
+                        successful_articles_table,
+                        exported_files,
+                        metadata_rows,
+                    ) {
+                        Ok(file_name) => generated_epubs.push((file_name, article)),
+                        Err(error) => errors.push(error),
                     }
+                    bar.inc(1);
+                }
+            }
+            bar.finish_with_message("Generated epubs\n");
 
-                    add_stylesheets(&mut epub, app_config)?;
-                    let title = replace_escaped_characters(article.metadata().title());
-                    epub.metadata("title", &title)?;
+            if app_config.is_generating_index && !generated_epubs.is_empty() {
+                let index_path = Path::new(app_config.output_directory.as_deref().unwrap_or("."))
+                    .join("index.html");
+                debug!("Creating index {:?}", index_path);
+                if let Err(err) = std::fs::write(&index_path, generate_index(&generated_epubs)) {
+                    error!("Failed to create index: {:?}", index_path);
+                    let mut paperoni_err: PaperoniError = err.into();
+                    paperoni_err.set_article_source(&index_path.to_string_lossy());
+                    file_errors.push(paperoni_err);
+                } else if app_config.can_print_to_stdout() {
+                    println!("Created {:?}", index_path);
+                }
+            }
 
-                    let mut content =
-                        EpubContent::new("index.xhtml", xhtml_str.as_bytes()).title(title);
+            if let Some(ref opds_path) = app_config.opds_path {
+                let entries = generated_epubs
+                    .iter()
+                    .map(|(file_name, article)| OpdsEntry {
+                        path: file_name,
+                        title: article.metadata().title(),
+                        author: article.metadata().byline().map(String::as_str),
+                        source: Some(article.url.as_str()),
+                    })
+                    .collect::<Vec<_>>();
+                if let Err(err) = write_opds_catalog(opds_path, &entries, app_config) {
+                    file_errors.push(err);
+                }
+            }
+        }
+    }
 
-                    for toc_element in header_level_tocs {
-                        content = content.child(toc_element);
-                    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Sets the header of the summary table when each article is exported to its own EPUB
+pub fn set_article_table_header(successful_articles_table: &mut Table) {
+    successful_articles_table
+        .set_header(vec![Cell::new("Downloaded articles")
+            .add_attribute(Attribute::Bold)
+            .set_alignment(CellAlignment::Center)
+            .fg(Color::Green)])
+        .set_content_arrangement(ContentArrangement::Dynamic);
+}
+
+/// Exports an article to its own EPUB and returns the path of the EPUB. The article is added to
+/// the summary table and the exported files when it succeeds.
+fn export_article_epub(
+    article: &Article,
+    app_config: &AppConfig,
+    successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
+) -> Result<String, PaperoniError> {
+    let (file_name, is_renamed) = article_epub_file_name(article, app_config, exported_files);
+    let result = ArticleEpub::from_article(article, app_config)
+        .and_then(|article_epub| article_epub.write(app_config, &file_name));
+    record_article_epub(
+        result,
+        article,
+        &file_name,
+        is_renamed,
+        app_config,
+        successful_articles_table,
+        exported_files,
+        metadata_rows,
+    )
+}
+
+/// Exports each article to its own EPUB with the EPUBs written from a pool of blocking threads.
+/// The content of the EPUBs is prepared beforehand since the articles' DOM trees cannot be sent
+/// between threads, and the results are recorded in the order of the articles.
+fn export_article_epubs_concurrently<'a>(
+    articles: &'a [Article],
+    app_config: &AppConfig,
+    bar: &ProgressBar,
+    successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
+) -> (Vec<(String, &'a Article)>, Vec<PaperoniError>) {
+    // The names are reserved as the jobs are queued so that articles sharing a title are not
+    // written to the same file at the same time
+    let mut reserved_files = exported_files.clone();
+    let epub_jobs = articles.iter().map(|article| {
+        let (file_name, is_renamed) = article_epub_file_name(article, app_config, &reserved_files);
+        reserved_files.push(file_name.clone());
+        let article_epub = ArticleEpub::from_article(article, app_config);
+        (file_name, is_renamed, article_epub)
+    });
+
+    let shared_config = Arc::new(app_config.clone());
+    let results = task::block_on(
+        stream::from_iter(epub_jobs.map(|(file_name, is_renamed, article_epub)| {
+            let (shared_config, bar) = (shared_config.clone(), bar.clone());
+            task::spawn_blocking(move || {
+                let result = article_epub
+                    .and_then(|article_epub| article_epub.write(&shared_config, &file_name));
+                bar.inc(1);
+                (file_name, is_renamed, result)
+            })
+        }))
+        .buffered(MAX_CONCURRENT_EPUBS)
+        .collect::<Vec<_>>(),
+    );
+
+    let mut generated_epubs = Vec::new();
+    let mut errors = Vec::new();
+    for (article, (file_name, is_renamed, result)) in articles.iter().zip(results) {
+        match record_article_epub(
+            result,
+            article,
+            &file_name,
+            is_renamed,
+            app_config,
+            successful_articles_table,
+            exported_files,
+            metadata_rows,
+        ) {
+            Ok(file_name) => generated_epubs.push((file_name, article)),
+            Err(error) => errors.push(error),
+        }
+    }
+    (generated_epubs, errors)
+}
+
+/// An article whose EPUB was queued by the `ArticleEpubWriter`
+struct QueuedEpub {
+    article: Article,
+    file_name: String,
+    is_renamed: bool,
+    /// The time spent preparing the content of the EPUB
+    preparation: Duration,
+}
+
+/// The outcome of an EPUB written by the `ArticleEpubWriter`
+pub struct WrittenEpub {
+    pub url: String,
+    /// The time spent preparing and writing the EPUB
+    pub generation: Duration,
+    pub result: Result<String, PaperoniError>,
+}
+
+/// Writes the EPUBs of articles exported while the next ones download. The content of each EPUB
+/// is prepared on the calling thread since the articles' DOM trees cannot be sent between
+/// threads, then the EPUB is written on a blocking thread fed through a bounded channel. The
+/// downloads only wait for the writer when `MAX_QUEUED_EPUBS` EPUBs are queued, which also
+/// bounds the number of articles kept in memory.
+pub struct ArticleEpubWriter {
+    jobs: SyncSender<(String, Result<ArticleEpub, PaperoniError>)>,
+    results: Receiver<(Result<(), PaperoniError>, Duration)>,
+    queued: VecDeque<QueuedEpub>,
+    /// The names are reserved as the EPUBs are queued so that articles sharing a title are not
+    /// written to the same file
+    reserved_files: Vec<String>,
+}
+
+impl ArticleEpubWriter {
+    pub fn new(app_config: &AppConfig, exported_files: &[String]) -> Self {
+        let (jobs, job_receiver) =
+            mpsc::sync_channel::<(String, Result<ArticleEpub, PaperoniError>)>(MAX_QUEUED_EPUBS);
+        let (result_sender, results) = mpsc::channel();
+        let shared_config = Arc::new(app_config.clone());
+        task::spawn_blocking(move || {
+            for (file_name, article_epub) in job_receiver {
+                let write_start = Instant::now();
+                let result = article_epub
+                    .and_then(|article_epub| article_epub.write(&shared_config, &file_name));
+                if result_sender.send((result, write_start.elapsed())).is_err() {
+                    break;
+                }
+            }
+        });
+        Self {
+            jobs,
+            results,
+            queued: VecDeque::new(),
+            reserved_files: exported_files.to_vec(),
+        }
+    }
+
+    /// Queues the EPUB of an article and returns the EPUBs written since the last call
+    pub fn export(
+        &mut self,
+        article: Article,
+        app_config: &AppConfig,
+        successful_articles_table: &mut Table,
+        exported_files: &mut Vec<String>,
+        metadata_rows: &mut Vec<MetadataRow>,
+    ) -> Vec<WrittenEpub> {
+        let mut written_epubs = Vec::new();
+        while let Ok(written) = self.results.try_recv() {
+            written_epubs.push(self.record(
+                written,
+                app_config,
+                successful_articles_table,
+                exported_files,
+                metadata_rows,
+            ));
+        }
+
+        let preparation_start = Instant::now();
+        let (file_name, is_renamed) =
+            article_epub_file_name(&article, app_config, &self.reserved_files);
+        self.reserved_files.push(file_name.clone());
+        let article_epub = ArticleEpub::from_article(&article, app_config);
+        self.queued.push_back(QueuedEpub {
+            article,
+            file_name: file_name.clone(),
+            is_renamed,
+            preparation: preparation_start.elapsed(),
+        });
+        if self.jobs.send((file_name, article_epub)).is_err() {
+            while !self.queued.is_empty() {
+                let written = self.next_written();
+                written_epubs.push(self.record(
+                    written,
+                    app_config,
+                    successful_articles_table,
+                    exported_files,
+                    metadata_rows,
+                ));
+            }
+        }
+        written_epubs
+    }
+
+    /// Waits for the queued EPUBs to be written and returns them
+    pub fn finish(
+        mut self,
+        app_config: &AppConfig,
+        successful_articles_table: &mut Table,
+        exported_files: &mut Vec<String>,
+        metadata_rows: &mut Vec<MetadataRow>,
+    ) -> Vec<WrittenEpub> {
+        let mut written_epubs = Vec::new();
+        while !self.queued.is_empty() {
+            let written = self.next_written();
+            written_epubs.push(self.record(
+                written,
+                app_config,
+                successful_articles_table,
+                exported_files,
+                metadata_rows,
+            ));
+        }
+        written_epubs
+    }
+
+    /// Waits for the next EPUB to be written. The EPUBs the writer did not get to because it
+    /// stopped are failed so that the other articles of the run are still exported.
+    fn next_written(&self) -> (Result<(), PaperoniError>, Duration) {
+        self.results.recv().unwrap_or_else(|_| {
+            (
+                Err(ErrorKind::EpubError("The EPUB writer stopped".into()).into()),
+                Duration::default(),
+            )
+        })
+    }
+
+    /// Adds the oldest queued article to the summary once its EPUB is written. The writer
+    /// handles the EPUBs in the order they were queued.
+    fn record(
+        &mut self,
+        (result, write_time): (Result<(), PaperoniError>, Duration),
+        app_config: &AppConfig,
+        successful_articles_table: &mut Table,
+        exported_files: &mut Vec<String>,
+        metadata_rows: &mut Vec<MetadataRow>,
+    ) -> WrittenEpub {
+        let queued = self
+            .queued
+            .pop_front()
+            .expect("An EPUB was written without being queued");
+        let result = record_article_epub(
+            result,
+            &queued.article,
+            &queued.file_name,
+            queued.is_renamed,
+            app_config,
+            successful_articles_table,
+            exported_files,
+            metadata_rows,
+        );
+        WrittenEpub {
+            url: queued.article.url.clone(),
+            generation: queued.preparation + write_time,
+            result,
+        }
+    }
+}
+
+/// Returns the path the EPUB of an article is written to and whether it was renamed because an
+/// earlier article has the same title. With `--number`, the file stem is prefixed with the
+/// position of the article's URL.
+fn article_epub_file_name(
+    article: &Article,
+    app_config: &AppConfig,
+    exported_files: &[String],
+) -> (String, bool) {
+    let output_dir = app_config.output_directory.as_deref().unwrap_or(".");
+    let file_stem = app_config.numbered_file_stem(
+        article.file_stem(app_config.is_including_url_in_title),
+        article.url_index + 1,
+    );
+    let file_name = unique_epub_file_name(output_dir, &file_stem, exported_files);
+    let is_renamed = file_name != format!("{}/{}.epub", output_dir, file_stem);
+    if is_renamed && !app_config.is_writing_to_stdout {
+        warn!(
+            "{} has the same title as an earlier article. Saving it to {:?} instead",
+            article.url, file_name
+        );
+    }
+    (file_name, is_renamed)
+}
+
+/// Adds an article to the summary table and the exported files when its EPUB was written, and
+/// returns the path of the EPUB
+#[allow(clippy::too_many_arguments)]
+fn record_article_epub(
+    result: Result<(), PaperoniError>,
+    article: &Article,
+    file_name: &str,
+    is_renamed: bool,
+    app_config: &AppConfig,
+    successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
+) -> Result<String, PaperoniError> {
+    match result {
+        Ok(()) => {
+            emit_generated(app_config, &article.url, file_name);
+            if is_renamed {
+                successful_articles_table.add_row(vec![format!(
+                    "{}\n(saved as {:?} since an earlier article has the same title)",
+                    article.metadata().title(),
+                    file_name
+                )]);
+            } else {
+                successful_articles_table.add_row(vec![article.metadata().title()]);
+            }
+            exported_files.push(file_name.to_owned());
+            add_metadata_row(metadata_rows, app_config, article, file_name);
+            Ok(file_name.to_owned())
+        }
+        Err(mut error) => {
+            error.set_article_source(&article.url);
+            emit_failed(app_config, &article.url, &error);
+            Err(error)
+        }
+    }
+}
+
+/// Creates the file an EPUB is written to. The error includes the path since the OS error alone,
+/// such as a permission error for a read-only output directory, does not say which file failed.
+fn create_epub_file(path: &str) -> Result<File, PaperoniError> {
+    File::create(path)
+        .map_err(|err| ErrorKind::IOError(format!("Unable to create {:?}: {}", path, err)).into())
+}
+
+/// Returns the path of the EPUB of an article. An index is added to the file stem when an EPUB
+/// with the same path has already been exported in this run, which happens when articles share
+/// a title, so that the earlier EPUB is not overwritten.
+fn unique_epub_file_name(output_dir: &str, file_stem: &str, exported_files: &[String]) -> String {
+    let mut file_name = format!("{}/{}.epub", output_dir, file_stem);
+    let mut index = 1;
+    while exported_files.contains(&file_name) {
+        file_name = format!("{}/{}_{}.epub", output_dir, file_stem, index);
+        index += 1;
+    }
+    file_name
+}
+
+/// The content of the EPUB of a single article. Unlike [Article], it does not hold a DOM tree
+/// and can therefore be written from another thread.
+struct ArticleEpub {
+    url: String,
+    title: String,
+    author: Option<String>,
+    lang: Option<String>,
+    publisher: Option<String>,
+    subject: Option<String>,
+    xhtml: String,
+    header_level_tocs: Vec<TocElement>,
+    cover: Option<ResourceInfo>,
+    img_urls: Vec<ResourceInfo>,
+    attachments: Vec<Attachment>,
+    appendix: String,
+}
+
+impl ArticleEpub {
+    fn from_article(article: &Article, app_config: &AppConfig) -> Result<Self, PaperoniError> {
+        let header_level_tocs = if app_config.is_generating_header_toc {
+            get_header_level_toc_vec(
+                "index.xhtml",
+                article.node_ref(),
+                app_config.is_clamping_heading_levels,
+            )
+        } else {
+            Vec::new()
+        };
+        link_attachments(article);
+        set_chapter_lang(article);
+        set_chapter_direction(article, app_config);
+        Ok(Self {
+            url: article.url.clone(),
+            title: replace_escaped_characters(article.metadata().title()),
+            author: article
+                .metadata()
+                .byline()
+                .cloned()
+                .or_else(|| {
+                    Some(article.domain())
+                        .filter(|domain| app_config.is_using_domain_as_author && !domain.is_empty())
+                })
+                .as_deref()
+                .map(replace_escaped_characters),
+            lang: article.metadata().lang().cloned(),
+            publisher: article.metadata().site_name().cloned(),
+            subject: article
+                .metadata()
+                .section()
+                .map(|section| replace_escaped_characters(section)),
+            xhtml: chapter_xhtml(article, app_config)?,
+            header_level_tocs,
+            cover: article.cover.clone(),
+            img_urls: article.img_urls.clone(),
+            attachments: article.attachments.clone(),
+            appendix: generate_appendix(vec![article]),
+        })
+    }
+
+    fn write(self, app_config: &AppConfig, file_name: &str) -> Result<(), PaperoniError> {
+        let mut epub = EpubBuilder::new(ZipLibrary::new()?)?;
+        debug!("Creating {:?}", file_name);
+
+        if let Some(author) = &self.author {
+            epub.metadata("author", author)?;
+        }
+        if let Some(lang) = &self.lang {
+            epub.metadata("lang", lang)?;
+        }
+        if let Some(subject) = &self.subject {
+            epub.metadata("subject", subject)?;
+        }
+        let mut package_changes = PackageChanges {
+            page_direction: app_config
+                .epub_direction
+                .as_ref()
+                .and_then(|direction| text_direction(direction, self.lang.as_deref())),
+            publisher: self.publisher.as_deref(),
+            has_appendix: false,
+        };
+
+        add_stylesheets(&mut epub, app_config)?;
+        epub.metadata("title", &self.title)?;
+
+        let mut content = EpubContent::new("index.xhtml", self.xhtml.as_bytes()).title(&self.title);
+
+        for toc_element in self.header_level_tocs {
+            content = content.child(toc_element);
+        }
+
+        epub.add_content(content)?;
+
+        // The cover is added under its own name since the same image may also be in the content
+        if let Some((cover_path, cover_mime)) = &self.cover {
+            let cover_buf = File::open(std::env::temp_dir().join(cover_path))?;
+            epub.add_cover_image(
+                format!("cover_{}", cover_path),
+                cover_buf,
+                cover_mime.as_deref().unwrap_or("image/jpeg"),
+            )?;
+        }
+        for img in &self.img_urls {
+            add_image(&mut epub, img)?;
+        }
+        add_attachments(&mut epub, &self.attachments, &mut HashSet::new())?;
+        epub.add_content(
+            EpubContent::new("appendix.xhtml", self.appendix.as_bytes())
+                .title(replace_escaped_characters("Article Source"))
+                .reftype(APPENDIX_REFERENCE_TYPE),
+        )?;
+        package_changes.has_appendix = true;
+        if app_config.is_writing_to_stdout {
+            generate_epub(
+                &mut epub,
+                &mut std::io::stdout(),
+                "-",
+                &[self.url.as_str()],
+                &package_changes,
+                app_config,
+            )?;
+        } else {
+            generate_epub(
+                &mut epub,
+                &mut create_epub_file(file_name)?,
+                file_name,
+                &[self.url.as_str()],
+                &package_changes,
+                app_config,
+            )?;
+        }
+        debug!("Created {:?}", file_name);
+        Ok(())
+    }
+}
+
+/// The changes made to the package document of a generated EPUB for the metadata that
+/// epub-builder has no option for
+struct PackageChanges<'a> {
+    page_direction: Option<&'a str>,
+    publisher: Option<&'a str>,
+    /// Whether the EPUB has an appendix whose landmark is changed to `appendix`
+    has_appendix: bool,
+}
+
+impl PackageChanges<'_> {
+    fn is_empty(&self) -> bool {
+        self.page_direction.is_none() && self.publisher.is_none() && !self.has_appendix
+    }
+}
+
+/// Writes the EPUB to the output file. When changing the package document, generating
+/// reproducible EPUBs or validating them, the EPUB is generated in memory first so that it can
+/// be processed before it is written.
+fn generate_epub<T: epub_builder::Zip, W: Write>(
+    epub: &mut EpubBuilder<T>,
+    out_file: &mut W,
+    name: &str,
+    article_urls: &[&str],
+    package_changes: &PackageChanges,
+    app_config: &AppConfig,
+) -> Result<(), PaperoniError> {
+    if !package_changes.is_empty() || app_config.is_reproducible || app_config.is_validating {
+        let mut epub_buf = Vec::new();
+        epub.generate(&mut epub_buf)?;
+        if !package_changes.is_empty() {
+            epub_buf = update_package_document(&epub_buf, package_changes)?;
+        }
+        if app_config.is_reproducible {
+            let identifier = generate_identifier(article_urls);
+            epub_buf = make_reproducible(&epub_buf, &identifier)?;
+        }
+        if app_config.is_validating {
+            let issues = validate_epub(&epub_buf)?;
+            for issue in &issues {
+                warn!("{}: {}", name, issue);
+            }
+            debug!(
+                "Found {} issue(s) while validating {:?}",
+                issues.len(),
+                name
+            );
+        }
+        out_file.write_all(&epub_buf)?;
+    } else {
+        epub.generate(out_file)?;
+    }
+    Ok(())
+}
+
+/// Sets the page progression direction of the spine and the publisher in the package document
+/// of a generated EPUB, and the type of the appendix landmark in the guide and the navigation
+/// document. epub-builder has no option for them so these documents are rewritten.
+fn update_package_document(
+    epub_buf: &[u8],
+    changes: &PackageChanges,
+) -> Result<Vec<u8>, PaperoniError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(epub_buf))?;
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for idx in 0..archive.len() {
+        let mut entry = archive.by_index(idx)?;
+        let name = entry.name().to_owned();
+        let options = zip::write::FileOptions::default()
+            .compression_method(entry.compression())
+            .last_modified_time(entry.last_modified());
+        let mut content = Vec::new();
+        entry.read_to_end(&mut content)?;
+        if name.ends_with(".opf") {
+            let mut document = std::str::from_utf8(&content)?.to_owned();
+            if let Some(direction) = changes.page_direction {
+                document = document.replacen(
+                    "<spine",
+                    &format!(r#"<spine page-progression-direction="{}""#, direction),
+                    1,
+                );
+            }
+            if let Some(publisher) = changes.publisher {
+                document = document.replacen(
+                    "</metadata>",
+                    &format!(
+                        "<dc:publisher>{}</dc:publisher></metadata>",
+                        replace_escaped_characters(publisher)
+                    ),
+                    1,
+                );
+            }
+            if changes.has_appendix {
+                let (reference, appendix_reference) = APPENDIX_GUIDE_REFERENCE;
+                document = document.replace(reference, appendix_reference);
+            }
+            content = document.into_bytes();
+        } else if changes.has_appendix && name.ends_with("nav.xhtml") {
+            let (landmark, appendix_landmark) = APPENDIX_LANDMARK;
+            content = std::str::from_utf8(&content)?
+                .replace(landmark, appendix_landmark)
+                .into_bytes();
+        }
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(&content)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
+
+/// Generates a UUID formatted identifier from the hash of the article URLs
+fn generate_identifier(article_urls: &[&str]) -> String {
+    let hash = format!("{:x}", md5::compute(article_urls.join("\n")));
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hash[..8],
+        &hash[8..12],
+        &hash[12..16],
+        &hash[16..20],
+        &hash[20..]
+    )
+}
 
-                    epub.add_content(content)?;
+/// Rewrites a generated EPUB so that the same articles always produce the same bytes.
+/// The random identifier and the generation timestamps in the package and navigation documents
+/// are replaced, the modification times of the entries are reset and the entries are sorted
+/// by name with the mimetype first as required by the EPUB specification.
+fn make_reproducible(epub_buf: &[u8], identifier: &str) -> Result<Vec<u8>, PaperoniError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(epub_buf))?;
+    let mut entry_names = (0..archive.len())
+        .map(|idx| archive.by_index(idx).map(|entry| entry.name().to_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    entry_names.sort_by_key(|name| (name != "mimetype", name.to_owned()));
+
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for name in entry_names {
+        let mut content = Vec::new();
+        archive.by_name(&name)?.read_to_end(&mut content)?;
+        if name.ends_with(".opf") || name.ends_with(".ncx") {
+            let document = std::str::from_utf8(&content)?;
+            let document = UUID_REGEX.replace_all(document, identifier);
+            let document = TIMESTAMP_REGEX.replace_all(&document, "1980-01-01T00:00:00Z");
+            content = document.into_owned().into_bytes();
+        }
+        let options = zip::write::FileOptions::default()
+            .compression_method(if name == "mimetype" {
+                zip::CompressionMethod::Stored
+            } else {
+                zip::CompressionMethod::Deflated
+            })
+            .last_modified_time(zip::DateTime::default())
+            .unix_permissions(0o644);
+        writer.start_file(name.as_str(), options)?;
+        writer.write_all(&content)?;
+    }
+    Ok(writer.finish()?.into_inner())
+}
 
-                    for img in &article.img_urls {
-                        let mut file_path = std::env::temp_dir();
-                        file_path.push(&img.0);
+/// Runs a lightweight check of the structure of a generated EPUB. The package document is checked
+/// against the files in the archive and the XHTML documents are checked for well-formedness and
+/// references to missing files. Returns a description of each issue found.
+fn validate_epub(epub_buf: &[u8]) -> Result<Vec<String>, PaperoniError> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(epub_buf))?;
+    let entry_names = (0..archive.len())
+        .map(|idx| archive.by_index(idx).map(|entry| entry.name().to_owned()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut issues = Vec::new();
+
+    if entry_names.first().map(String::as_str) != Some("mimetype") {
+        issues.push("The mimetype file is not the first entry of the archive".to_owned());
+    } else if read_zip_entry(&mut archive, "mimetype")? != b"application/epub+zip" {
+        issues.push("The mimetype file does not contain application/epub+zip".to_owned());
+    }
 
-                        let img_buf = File::open(&file_path).expect("Can't read image file");
-                        epub.add_resource(
-                            file_path.file_name().unwrap(),
-                            img_buf,
-                            img.1.as_ref().unwrap(),
-                        )?;
+    let opf_path = if entry_names
+        .iter()
+        .any(|name| name == "META-INF/container.xml")
+    {
+        let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+        find_xml_elements(&parse_xml(&String::from_utf8_lossy(&container)), "rootfile").find_map(
+            |rootfile| {
+                rootfile
+                    .attributes
+                    .borrow()
+                    .get("full-path")
+                    .map(String::from)
+            },
+        )
+    } else {
+        issues.push("META-INF/container.xml is missing".to_owned());
+        None
+    };
+    match opf_path {
+        Some(ref opf_path) if entry_names.contains(opf_path) => {
+            let opf_dir = opf_path
+                .rfind('/')
+                .map(|idx| &opf_path[..=idx])
+                .unwrap_or("");
+            let opf = parse_xml(&String::from_utf8_lossy(&read_zip_entry(
+                &mut archive,
+                opf_path,
+            )?));
+            let mut manifest_ids = HashSet::new();
+            for item in find_xml_elements(&opf, "item") {
+                let attrs = item.attributes.borrow();
+                if let Some(id) = attrs.get("id") {
+                    manifest_ids.insert(id.to_owned());
+                }
+                match attrs.get("href") {
+                    Some(href) => {
+                        let path = resolve_epub_path(opf_dir, href);
+                        if !entry_names.contains(&path) {
+                            issues.push(format!(
+                                "{} is listed in the manifest but missing from the archive",
+                                path
+                            ));
+                        }
                     }
-                    let appendix = generate_appendix(vec![&article]);
-                    epub.add_content(
-                        EpubContent::new("appendix.xhtml", appendix.as_bytes())
-                            .title(replace_escaped_characters("Article Source")),
-                    )?;
-                    epub.generate(&mut out_file)?;
-                    bar.inc(1);
+                    None => issues.push("A manifest item has no href".to_owned()),
+                }
+            }
+            for itemref in find_xml_elements(&opf, "itemref") {
+                let attrs = itemref.attributes.borrow();
+                match attrs.get("idref") {
+                    Some(idref) if manifest_ids.contains(idref) => (),
+                    Some(idref) => issues.push(format!(
+                        "The spine refers to {} which is not in the manifest",
+                        idref
+                    )),
+                    None => issues.push("A spine item has no idref".to_owned()),
+                }
+            }
+        }
+        Some(opf_path) => issues.push(format!("The package document {} is missing", opf_path)),
+        None => issues.push("The package document is not listed in the container".to_owned()),
+    }
 
-                    successful_articles_table.add_row(vec![article.metadata().title()]);
+    for name in entry_names
+        .iter()
+        .filter(|name| name.ends_with(".xhtml") || name.ends_with(".ncx"))
+    {
+        let content = read_zip_entry(&mut archive, name)?;
+        let document = match std::str::from_utf8(&content) {
+            Ok(document) => document,
+            Err(_) => {
+                issues.push(format!("{} is not valid UTF-8", name));
+                continue;
+            }
+        };
+        issues.extend(
+            check_xhtml(document)
+                .into_iter()
+                .map(|issue| format!("{}: {}", name, issue)),
+        );
 
-                    debug!("Created {:?}", file_name);
-                    Ok(())
+        let dir = name.rfind('/').map(|idx| &name[..=idx]).unwrap_or("");
+        let file_name = name.rsplit('/').next().unwrap_or(name);
+        let document = parse_xml(document);
+        // Only the links of the navigation documents are checked as the links in articles may
+        // point to pages that were not downloaded
+        let mut references = vec![("img", "src"), ("link", "href"), ("content", "src")];
+        if GENERATED_EPUB_FILES.contains(&file_name) {
+            references.push(("a", "href"));
+        }
+        for (tag_name, attr_name) in references {
+            for element in find_xml_elements(&document, tag_name) {
+                let attrs = element.attributes.borrow();
+                let href = match attrs.get(attr_name) {
+                    Some(href) => href,
+                    None => continue,
                 };
-                if let Err(mut error) = result() {
-                    error.set_article_source(&article.url);
-                    errors.push(error);
+                let href = href
+                    .split(|c: char| c == '#' || c == '?')
+                    .next()
+                    .unwrap_or("");
+                let is_external = href
+                    .find(':')
+                    .map_or(false, |idx| !href[..idx].contains('/'));
+                if href.is_empty() || is_external {
+                    continue;
+                }
+                let path = resolve_epub_path(dir, href);
+                if !entry_names.contains(&path) {
+                    issues.push(format!("{} refers to {} which is missing", name, path));
                 }
             }
-            bar.finish_with_message("Generated epubs\n");
         }
     }
+    Ok(issues)
+}
 
-    if errors.is_empty() {
-        Ok(())
+/// Checks that the tags of an XHTML document are balanced and that only the entities defined
+/// by XML are used.
+fn check_xhtml(xhtml: &str) -> Vec<String> {
+    let mut issues = Vec::new();
+    let mut open_tags: Vec<&str> = Vec::new();
+    let mut last_end = 0;
+    for token in XML_TOKEN_REGEX.captures_iter(xhtml) {
+        let whole = token.get(0).unwrap();
+        check_xml_entities(&xhtml[last_end..whole.start()], &mut issues);
+        last_end = whole.end();
+        if let Some(name) = token.get(1) {
+            let name = name.as_str();
+            if open_tags.contains(&name) {
+                while let Some(open_tag) = open_tags.pop() {
+                    if open_tag == name {
+                        break;
+                    }
+                    issues.push(format!("<{}> is not closed", open_tag));
+                }
+            } else {
+                issues.push(format!("</{}> has no matching start tag", name));
+            }
+        } else if let Some(name) = token.get(2) {
+            check_xml_entities(whole.as_str(), &mut issues);
+            if token.get(3).map_or(true, |slash| slash.as_str().is_empty()) {
+                open_tags.push(name.as_str());
+            }
+        }
+    }
+    check_xml_entities(&xhtml[last_end..], &mut issues);
+    issues.extend(
+        open_tags
+            .into_iter()
+            .rev()
+            .map(|open_tag| format!("<{}> is not closed", open_tag)),
+    );
+    issues
+}
+
+fn check_xml_entities(text: &str, issues: &mut Vec<String>) {
+    for entity in XML_ENTITY_REGEX.captures_iter(text) {
+        let name = &entity[1];
+        let is_valid = &entity[2] == ";"
+            && (["amp", "lt", "gt", "quot", "apos"].contains(&name)
+                || (name.starts_with("#x")
+                    && name.len() > 2
+                    && name[2..].chars().all(|c| c.is_ascii_hexdigit()))
+                || (name.starts_with('#')
+                    && name.len() > 1
+                    && name[1..].chars().all(|c| c.is_ascii_digit())));
+        if !is_valid {
+            issues.push(format!("{} is not a valid XML entity", &entity[0]));
+        }
+    }
+}
+
+/// Resolves a relative reference from a file in the directory `dir` to a path in the EPUB
+fn resolve_epub_path(dir: &str, href: &str) -> String {
+    let path = format!("{}{}", dir, href);
+    let mut components: Vec<&str> = Vec::new();
+    for component in path.split('/') {
+        match component {
+            "" | "." => (),
+            ".." => {
+                components.pop();
+            }
+            component => components.push(component),
+        }
+    }
+    components.join("/")
+}
+
+/// Returns the most common of the languages declared by the articles, which is used as the
+/// language of a merged EPUB. Ties go to the language of the earlier article.
+fn majority_lang(articles: &[Article]) -> Option<String> {
+    let mut lang_counts: Vec<(String, usize)> = Vec::new();
+    for lang in articles
+        .iter()
+        .filter_map(|article| article.metadata().lang())
+    {
+        let lang = lang.to_lowercase();
+        match lang_counts
+            .iter_mut()
+            .find(|(counted_lang, _)| *counted_lang == lang)
+        {
+            Some((_, count)) => *count += 1,
+            None => lang_counts.push((lang, 1)),
+        }
+    }
+    lang_counts
+        .into_iter()
+        .fold(None, |majority, (lang, count)| match majority {
+            Some((_, majority_count)) if majority_count >= count => majority,
+            _ => Some((lang, count)),
+        })
+        .map(|(lang, _)| lang)
+}
+
+/// Returns the name of the site the articles were published on when they all name the same one
+fn common_site_name(articles: &[Article]) -> Option<String> {
+    let mut site_names = articles
+        .iter()
+        .map(|article| article.metadata().site_name());
+    let first = site_names.next()??;
+    if site_names.all(|site_name| site_name == Some(first)) {
+        Some(first.clone())
     } else {
-        Err(errors)
+        None
+    }
+}
+
+/// Sets the language of an article's chapter to the one declared by the article so that
+/// reading systems hyphenate and read it aloud correctly when it differs from the EPUB's language
+fn set_chapter_lang(article: &Article) {
+    let (lang, html_elem) = match (
+        article.metadata().lang(),
+        article.node_ref().select_first("html"),
+    ) {
+        (Some(lang), Ok(html_elem)) => (lang, html_elem),
+        _ => return,
+    };
+    let mut attrs = html_elem.attributes.borrow_mut();
+    attrs.insert("lang", lang.clone());
+    attrs.insert("xml:lang", lang.clone());
+}
+
+/// Returns the text direction, rtl or ltr, of the `--epub-direction` option. The automatic
+/// direction follows the language and is `None` when the language is not known.
+fn text_direction(direction: &EpubDirection, lang: Option<&str>) -> Option<&'static str> {
+    match direction {
+        EpubDirection::Ltr => Some("ltr"),
+        EpubDirection::Rtl => Some("rtl"),
+        EpubDirection::Auto => lang.map(|lang| {
+            let primary_subtag = lang.split(|c| c == '-' || c == '_').next().unwrap_or("");
+            if RTL_LANGS.contains(&primary_subtag.to_lowercase().as_str()) {
+                "rtl"
+            } else {
+                "ltr"
+            }
+        }),
+    }
+}
+
+/// Sets the direction of an article's chapter from `--epub-direction`. Chapters of merged EPUBs
+/// follow their own language with the automatic direction.
+fn set_chapter_direction(article: &Article, app_config: &AppConfig) {
+    let direction = match app_config.epub_direction.as_ref().and_then(|direction| {
+        text_direction(direction, article.metadata().lang().map(String::as_str))
+    }) {
+        Some(direction) => direction,
+        None => return,
+    };
+    if let Ok(html_elem) = article.node_ref().select_first("html") {
+        html_elem
+            .attributes
+            .borrow_mut()
+            .insert("dir", direction.to_owned());
+    }
+}
+
+/// Returns the title of an article's chapter in a merged EPUB. The {index}, {title} and {domain}
+/// tokens of the template are replaced with the chapter number, the article's title and the
+/// host of the article's URL. The article's title is used when there is no template.
+fn chapter_title(template: Option<&str>, index: usize, article: &Article) -> String {
+    let title = article.metadata().title();
+    let template = match template {
+        Some(template) => template,
+        None => return title.to_owned(),
+    };
+    template
+        .replace("{index}", &index.to_string())
+        .replace("{domain}", &article.domain())
+        .replace("{title}", title)
+}
+
+/// Returns the XHTML of an article's chapter. When a chapter template is passed with
+/// `--template-dir`, the content of the article is placed in the template instead of the
+/// document it was extracted into.
+fn chapter_xhtml(article: &Article, app_config: &AppConfig) -> Result<String, PaperoniError> {
+    match &app_config.chapter_xhtml_template {
+        Some(template) => {
+            let content = serialize_body_to_xhtml_string(article.node_ref())?;
+            Ok(render_chapter_xhtml(
+                template,
+                article,
+                &content,
+                app_config.user_css.as_deref(),
+            ))
+        }
+        None => serialize_to_xhtml_string(article.node_ref()),
     }
 }
 
+/// Replaces the {{content}}, {{title}}, {{author}}, {{url}} and {{lang}} tokens of a chapter
+/// template. The values other than the content are escaped so that they can be used in
+/// attributes. The template is scanned once so tokens within the content are left as is. The
+/// styles of `--user-css-inline` are added at the end of the template's head, as they are for
+/// chapters without a template.
+fn render_chapter_xhtml(
+    template: &str,
+    article: &Article,
+    content: &str,
+    user_css: Option<&str>,
+) -> String {
+    let escape = |value: &str| replace_escaped_characters(value).replace('"', "&quot;");
+    let mut xhtml = CHAPTER_TOKEN_REGEX
+        .replace_all(template, |captures: &regex::Captures| match &captures[1] {
+            "content" => content.to_owned(),
+            "title" => escape(article.metadata().title()),
+            "author" => escape(article.metadata().byline().map_or("", String::as_str)),
+            "url" => escape(&article.url),
+            _ => escape(article.metadata().lang().map_or("en", String::as_str)),
+        })
+        .into_owned();
+    if let (Some(css), Some(head_end)) = (user_css, xhtml.find("</head>")) {
+        xhtml.insert_str(
+            head_end,
+            &format!("<style>{}</style>", replace_escaped_characters(css)),
+        );
+    }
+    xhtml
+}
+
 /// Replaces characters that have to be escaped before adding to the epub's metadata
 fn replace_escaped_characters(value: &str) -> String {
     value
@@ -249,27 +1464,437 @@ fn replace_escaped_characters(value: &str) -> String {
         .replace(">", "&gt;")
 }
 
-fn add_stylesheets<T: epub_builder::Zip>(
-    epub: &mut EpubBuilder<T>,
-    app_config: &AppConfig,
-) -> Result<(), epub_builder::Error> {
-    let body_stylesheet: &[u8] = include_bytes!("./assets/body.min.css");
-    let header_stylesheet: &[u8] = include_bytes!("./assets/headers.min.css");
-    match app_config.css_config {
-        crate::cli::CSSConfig::All => {
-            epub.stylesheet([header_stylesheet, body_stylesheet].concat().as_bytes())?;
-            Ok(())
-        }
-        crate::cli::CSSConfig::NoHeaders => {
-            epub.stylesheet(body_stylesheet.as_bytes())?;
-            Ok(())
-        }
-        _ => Ok(()),
-    }
+fn add_stylesheets<T: epub_builder::Zip>(
+    epub: &mut EpubBuilder<T>,
+    app_config: &AppConfig,
+) -> Result<(), epub_builder::Error> {
+    let body_stylesheet: &[u8] = include_bytes!("./assets/body.min.css");
+    let header_stylesheet: &[u8] = include_bytes!("./assets/headers.min.css");
+    let mut stylesheet = match app_config.css_config {
+        crate::cli::CSSConfig::All => [header_stylesheet, body_stylesheet].concat(),
+        crate::cli::CSSConfig::NoHeaders => body_stylesheet.to_vec(),
+        crate::cli::CSSConfig::None => Vec::new(),
+    };
+    if let (Some(body_width), false) = (&app_config.body_width, stylesheet.is_empty()) {
+        stylesheet.extend_from_slice(crate::cli::body_width_stylesheet(body_width).as_bytes());
+    }
+    if let Some(theme) = &app_config.highlight_theme {
+        stylesheet.extend_from_slice(theme.stylesheet().as_bytes());
+    }
+    if !stylesheet.is_empty() {
+        epub.stylesheet(stylesheet.as_slice())?;
+    }
+    Ok(())
+}
+
+/// Shortens an article so that its content and images take at most `budget` bytes in the EPUB.
+/// The blocks of content past the budget are removed first, then the images that no longer fit.
+/// A note is added to the end of shortened articles and the removed images are replaced with
+/// placeholders when `is_keeping_placeholders` is set. Returns the paths of the images that were
+/// removed so that they are left out of the EPUB.
+fn fit_to_budget(article: &Article, budget: u64, is_keeping_placeholders: bool) -> HashSet<String> {
+    let is_block = |elem: &NodeDataRef<ElementData>| BUDGET_BLOCKS.contains(&&*elem.name.local);
+    let body = match article.node_ref().select_first("body") {
+        Ok(body) => body,
+        Err(_) => return HashSet::new(),
+    };
+    let mut used = 0;
+    let mut is_shortened = false;
+    let blocks = body
+        .as_node()
+        .descendants()
+        .elements()
+        .filter(|elem| {
+            is_block(elem)
+                && !elem
+                    .as_node()
+                    .ancestors()
+                    .elements()
+                    .any(|ancestor| is_block(&ancestor))
+        })
+        .collect::<Vec<_>>();
+    for block in blocks {
+        let size = block.as_node().to_string().len() as u64;
+        if is_shortened || used + size > budget {
+            block.as_node().detach();
+            is_shortened = true;
+        } else {
+            used += size;
+        }
+    }
+
+    let img_sizes: HashMap<&str, u64> = article
+        .img_urls
+        .iter()
+        .map(|(img_path, _)| {
+            let size = std::fs::metadata(std::env::temp_dir().join(img_path))
+                .map_or(0, |metadata| metadata.len());
+            (img_path.as_str(), size)
+        })
+        .collect();
+    let mut kept_imgs = HashSet::new();
+    for img in body.as_node().select("img").unwrap().collect::<Vec<_>>() {
+        let src = match img.attributes.borrow().get("src") {
+            Some(src) => src.to_owned(),
+            None => continue,
+        };
+        let size = match img_sizes.get(src.as_str()) {
+            Some(size) => *size,
+            None => continue,
+        };
+        if kept_imgs.contains(&src) {
+            continue;
+        }
+        if used + size > budget {
+            if is_keeping_placeholders {
+                insert_img_placeholder(&img, img.as_node());
+            }
+            img.as_node().detach();
+            is_shortened = true;
+        } else {
+            used += size;
+            kept_imgs.insert(src);
+        }
+    }
+
+    if is_shortened {
+        warn!("Shortened {} to fit the per-article budget", article.url);
+        let note = kuchiki::parse_html().one(format!(
+            "<p><em>This article was shortened to fit the per-article budget of {:.1} MB.</em></p>",
+            budget as f64 / BYTES_PER_MB as f64
+        ));
+        if let Ok(note_elem) = note.select_first("p") {
+            body.as_node().append(note_elem.as_node().clone());
+        }
+    }
+    article
+        .img_urls
+        .iter()
+        .map(|(img_path, _)| img_path)
+        .filter(|img_path| !kept_imgs.contains(*img_path))
+        .cloned()
+        .collect()
+}
+
+/// Points the links and audio sources of downloaded attachments at their copies in the EPUB
+fn link_attachments(article: &Article) {
+    if article.attachments.is_empty() {
+        return;
+    }
+    let base_url = url::Url::parse(&article.url).ok();
+    for &(selector, attr_name) in &[
+        ("a[href]", "href"),
+        ("audio[src], audio source[src]", "src"),
+    ] {
+        for elem in article.node_ref().select(selector).unwrap() {
+            let mut attrs = elem.attributes.borrow_mut();
+            let url = attrs.get(attr_name).and_then(|value| {
+                url::Url::options()
+                    .base_url(base_url.as_ref())
+                    .parse(value)
+                    .ok()
+            });
+            if let Some(attachment) = url.and_then(|url| {
+                article
+                    .attachments
+                    .iter()
+                    .find(|attachment| attachment.url == url.as_str())
+            }) {
+                attrs.insert(attr_name, attachment.file_name.clone());
+            }
+        }
+    }
+}
+
+/// Adds a downloaded image from the temp directory to the EPUB
+fn add_image<T: epub_builder::Zip>(
+    epub: &mut EpubBuilder<T>,
+    img: &ResourceInfo,
+) -> Result<(), PaperoniError> {
+    let file_path = std::env::temp_dir().join(&img.0);
+    let img_buf = File::open(&file_path)
+        .map_err(|err| ErrorKind::IOError(format!("Unable to read {:?}: {}", file_path, err)))?;
+    epub.add_resource(&img.0, img_buf, img.1.as_deref().unwrap_or("image/jpeg"))?;
+    Ok(())
+}
+
+fn add_attachments<T: epub_builder::Zip>(
+    epub: &mut EpubBuilder<T>,
+    attachments: &[Attachment],
+    added_paths: &mut HashSet<String>,
+) -> Result<(), PaperoniError> {
+    for attachment in attachments {
+        if !added_paths.insert(attachment.file_name.clone()) {
+            continue;
+        }
+        let file_path = std::env::temp_dir().join(&attachment.file_name);
+        let attachment_buf = File::open(&file_path)?;
+        epub.add_resource(&attachment.file_name, attachment_buf, &attachment.mime_type)?;
+    }
+    Ok(())
+}
+
+/// The chapters and resources of an existing EPUB that articles are appended to
+struct ExistingEpub {
+    title: Option<String>,
+    chapters: Vec<ExistingChapter>,
+    /// The paths, media types and contents of the non-chapter files such as images
+    resources: Vec<(String, String, Vec<u8>)>,
+    /// The article source links listed in the appendix of an EPUB created by Paperoni
+    previous_sources: Option<String>,
+}
+
+struct ExistingChapter {
+    path: String,
+    content: Vec<u8>,
+    nav_point: Option<NavPoint>,
+}
+
+/// An entry in the navigation map of an EPUB's toc.ncx file
+#[derive(Debug, PartialEq)]
+struct NavPoint {
+    src: String,
+    title: String,
+    children: Vec<NavPoint>,
+}
+
+impl NavPoint {
+    fn to_toc_element(&self) -> TocElement {
+        self.children.iter().fold(
+            TocElement::new(self.src.clone(), self.title.clone()),
+            |toc, child| toc.child(child.to_toc_element()),
+        )
+    }
+}
+
+/// Files that are generated by epub_builder and are therefore not copied from an existing EPUB
+const GENERATED_EPUB_FILES: [&str; 3] = ["nav.xhtml", "toc.xhtml", "toc.ncx"];
+
+impl ExistingEpub {
+    fn read(path: &str) -> Result<Self, PaperoniError> {
+        let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+        let container = read_zip_entry(&mut archive, "META-INF/container.xml")?;
+        let opf_path = find_xml_elements(&parse_xml(std::str::from_utf8(&container)?), "rootfile")
+            .find_map(|rootfile| {
+                rootfile
+                    .attributes
+                    .borrow()
+                    .get("full-path")
+                    .map(String::from)
+            })
+            .ok_or_else(|| ErrorKind::EpubError("Unable to find the package document".into()))?;
+        let opf_dir = opf_path
+            .rfind('/')
+            .map(|idx| &opf_path[..=idx])
+            .unwrap_or("");
+        let opf = parse_xml(std::str::from_utf8(&read_zip_entry(
+            &mut archive,
+            &opf_path,
+        )?)?);
+
+        let title = find_xml_elements(&opf, "dc:title")
+            .next()
+            .map(|title| title.text_contents().trim().to_owned());
+        // The ids of the files in the manifest with their paths, media types and properties. These
+        // are kept in document order so that the resources are always added in the same order
+        let manifest: Vec<(String, (String, String, String))> = find_xml_elements(&opf, "item")
+            .filter_map(|item| {
+                let attrs = item.attributes.borrow();
+                Some((
+                    attrs.get("id")?.to_owned(),
+                    (
+                        attrs.get("href")?.to_owned(),
+                        attrs.get("media-type").unwrap_or_default().to_owned(),
+                        attrs.get("properties").unwrap_or_default().to_owned(),
+                    ),
+                ))
+            })
+            .collect();
+        let manifest_files: HashMap<&str, &(String, String, String)> = manifest
+            .iter()
+            .map(|(id, file)| (id.as_str(), file))
+            .collect();
+        let spine: Vec<String> = find_xml_elements(&opf, "itemref")
+            .filter_map(|itemref| itemref.attributes.borrow().get("idref").map(String::from))
+            .collect();
+
+        let mut nav_points = match manifest
+            .iter()
+            .map(|(_, file)| file)
+            .find(|(_, media_type, _)| media_type == "application/x-dtbncx+xml")
+        {
+            Some((ncx_path, _, _)) => {
+                let ncx = read_zip_entry(&mut archive, &format!("{}{}", opf_dir, ncx_path))?;
+                read_nav_points(std::str::from_utf8(&ncx)?)
+            }
+            None => Vec::new(),
+        };
+
+        let mut chapters = Vec::new();
+        let mut previous_sources = None;
+        let spine_files = spine
+            .iter()
+            .filter_map(|idref| manifest_files.get(idref.as_str()));
+        for (path, _, _) in spine_files {
+            if GENERATED_EPUB_FILES.contains(&path.as_str()) {
+                continue;
+            }
+            let content = read_zip_entry(&mut archive, &format!("{}{}", opf_dir, path))?;
+            if path == "appendix.xhtml" {
+                // The appendix is regenerated with the sources of the appended articles added
+                previous_sources = read_appendix_sources(std::str::from_utf8(&content)?);
+                continue;
+            }
+            let nav_point = nav_points
+                .iter()
+                .position(|nav_point| nav_point.src.split('#').next() == Some(path.as_str()))
+                .map(|idx| nav_points.remove(idx));
+            chapters.push(ExistingChapter {
+                path: path.to_owned(),
+                content,
+                nav_point,
+            });
+        }
+
+        let mut resources = Vec::new();
+        for (id, (path, media_type, properties)) in &manifest {
+            if spine.contains(id)
+                || media_type == "application/x-dtbncx+xml"
+                || properties
+                    .split_whitespace()
+                    .any(|property| property == "nav")
+                || GENERATED_EPUB_FILES.contains(&path.as_str())
+            {
+                continue;
+            }
+            let content = read_zip_entry(&mut archive, &format!("{}{}", opf_dir, path))?;
+            resources.push((path.to_owned(), media_type.to_owned(), content));
+        }
+
+        Ok(Self {
+            title,
+            chapters,
+            resources,
+            previous_sources,
+        })
+    }
+
+    fn add_to<T: epub_builder::Zip>(
+        &self,
+        epub: &mut EpubBuilder<T>,
+        added_paths: &mut HashSet<String>,
+        app_config: &AppConfig,
+    ) -> Result<(), PaperoniError> {
+        for chapter in &self.chapters {
+            let mut content = EpubContent::new(&chapter.path, chapter.content.as_slice());
+            if let Some(ref nav_point) = chapter.nav_point {
+                content = content.title(&nav_point.title);
+                for child in &nav_point.children {
+                    content = content.child(child.to_toc_element());
+                }
+            }
+            epub.add_content(content)?;
+            added_paths.insert(chapter.path.clone());
+        }
+        for (path, media_type, content) in &self.resources {
+            // The stylesheet is replaced by the one from the current run unless it has no styles
+            if path == "stylesheet.css"
+                && (!matches!(app_config.css_config, CSSConfig::None)
+                    || app_config.highlight_theme.is_some())
+            {
+                continue;
+            }
+            epub.add_resource(path, content.as_slice(), media_type)?;
+            added_paths.insert(path.to_owned());
+        }
+        Ok(())
+    }
+}
+
+fn read_zip_entry<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    name: &str,
+) -> Result<Vec<u8>, PaperoniError> {
+    let mut buf = Vec::new();
+    archive.by_name(name)?.read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Parses an XML document using the HTML parser. Self-closing tags are expanded first as the
+/// HTML parser would otherwise treat the elements following them as their children.
+fn parse_xml(xml: &str) -> NodeRef {
+    kuchiki::parse_html().one(
+        SELF_CLOSING_TAG_REGEX
+            .replace_all(xml, "<$1$2></$1>")
+            .as_ref(),
+    )
+}
+
+/// Finds the elements with the given tag name. The HTML parser lower cases tag names so the name
+/// must be in lower case.
+fn find_xml_elements<'a>(
+    node_ref: &NodeRef,
+    name: &'a str,
+) -> impl Iterator<Item = kuchiki::NodeDataRef<kuchiki::ElementData>> + 'a {
+    node_ref
+        .descendants()
+        .elements()
+        .filter(move |element| &*element.name.local == name)
+}
+
+/// Reads the top level entries of the navigation map in a toc.ncx file
+fn read_nav_points(ncx: &str) -> Vec<NavPoint> {
+    fn read_nav_point(nav_point: &NodeRef) -> Option<NavPoint> {
+        let child_elements = || nav_point.children().elements();
+        let title = child_elements()
+            .find(|element| &*element.name.local == "navlabel")?
+            .text_contents();
+        let src = child_elements()
+            .find(|element| &*element.name.local == "content")?
+            .attributes
+            .borrow()
+            .get("src")?
+            .to_owned();
+        Some(NavPoint {
+            src,
+            title: replace_escaped_characters(title.trim()),
+            children: child_elements()
+                .filter(|element| &*element.name.local == "navpoint")
+                .filter_map(|child| read_nav_point(child.as_node()))
+                .collect(),
+        })
+    }
+
+    find_xml_elements(&parse_xml(ncx), "navmap")
+        .flat_map(|nav_map| nav_map.as_node().children().elements().collect::<Vec<_>>())
+        .filter(|element| &*element.name.local == "navpoint")
+        .filter_map(|nav_point| read_nav_point(nav_point.as_node()))
+        .collect()
+}
+
+/// Reads the article source links from the appendix of an EPUB created by Paperoni
+fn read_appendix_sources(appendix: &str) -> Option<String> {
+    let sources_heading = "<h3>Article sources</h3>";
+    let sources_start = appendix.find(sources_heading)? + sources_heading.len();
+    // The sources of appendices written before they were wrapped in a section end with the body
+    let sources_end = appendix[sources_start..]
+        .find("</section>")
+        .or_else(|| appendix[sources_start..].find("</body>"))?
+        + sources_start;
+    Some(appendix[sources_start..sources_end].trim().to_owned())
 }
 
-//TODO: The type signature of the argument should change as it requires that merged articles create an entirely new Vec of references
 fn generate_appendix(articles: Vec<&Article>) -> String {
+    generate_appendix_with_previous_sources(articles, "")
+}
+
+//TODO: The type signature of the argument should change as it requires that merged articles create an entirely new Vec of references
+/// Generates the appendix listing the article sources. The previous sources are the links from
+/// the appendix of an existing EPUB that the articles are appended to.
+fn generate_appendix_with_previous_sources(
+    articles: Vec<&Article>,
+    previous_sources: &str,
+) -> String {
     let link_tags: String = articles
         .iter()
         .map(|article| {
@@ -278,10 +1903,22 @@ fn generate_appendix(articles: Vec<&Article>) -> String {
             } else {
                 &article.url
             };
+            let attachment_tags: String = article
+                .attachments
+                .iter()
+                .map(|attachment| {
+                    format!(
+                        "Attached: <a href=\"{}\">{}</a><br></br>",
+                        replace_escaped_characters(&attachment.file_name),
+                        replace_escaped_characters(&attachment.url)
+                    )
+                })
+                .collect();
             format!(
-                "<a href=\"{}\">{}</a><br></br>",
+                "<a href=\"{}\">{}</a><br></br>{}",
                 replace_escaped_characters(&article.url),
-                replace_escaped_characters(article_name)
+                replace_escaped_characters(article_name),
+                attachment_tags
             )
         })
         .collect();
@@ -291,15 +1928,155 @@ fn generate_appendix(articles: Vec<&Article>) -> String {
         <link rel="stylesheet" href="stylesheet.css" type="text/css"></link>
     </head>
     <body>
+        <section epub:type="appendix" role="doc-appendix">
         <h2>Appendix</h2><h3>Article sources</h3>
-        {}
+        {}{}
+        </section>
     </body>
 </html>"#,
-        link_tags
+        previous_sources, link_tags
     );
     template
 }
 
+/// Generates a standalone HTML page linking to each generated EPUB and the source of its article
+fn generate_index(generated_epubs: &[(String, &Article)]) -> String {
+    let list_items: String = generated_epubs
+        .iter()
+        .map(|(file_name, article)| {
+            let epub_name = Path::new(file_name)
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| file_name.to_owned());
+            let article_name = if !article.metadata().title().is_empty() {
+                article.metadata().title()
+            } else {
+                &article.url
+            };
+            format!(
+                "<li><a href=\"{}\">{}</a> (<a href=\"{}\">source</a>)</li>",
+                replace_escaped_characters(&epub_name).replace('"', "&quot;"),
+                replace_escaped_characters(article_name),
+                replace_escaped_characters(&article.url).replace('"', "&quot;")
+            )
+        })
+        .collect();
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+    <head>
+        <meta charset="UTF-8"/>
+        <title>Articles</title>
+    </head>
+    <body>
+        <h1>Articles</h1>
+        <ul>{}</ul>
+    </body>
+</html>"#,
+        list_items
+    )
+}
+
+/// An EPUB generated during the run that is listed in the OPDS catalog
+struct OpdsEntry<'a> {
+    path: &'a str,
+    title: &'a str,
+    author: Option<&'a str>,
+    source: Option<&'a str>,
+}
+
+fn write_opds_catalog(
+    opds_path: &str,
+    entries: &[OpdsEntry],
+    app_config: &AppConfig,
+) -> Result<(), PaperoniError> {
+    debug!("Creating OPDS catalog {:?}", opds_path);
+    let catalog = generate_opds_catalog(
+        entries,
+        Path::new(opds_path),
+        &app_config.start_time.to_rfc3339(),
+    );
+    std::fs::write(opds_path, catalog).map_err(|err| {
+        error!("Failed to create OPDS catalog: {:?}", opds_path);
+        let mut paperoni_err: PaperoniError = err.into();
+        paperoni_err.set_article_source(opds_path);
+        paperoni_err
+    })?;
+    if app_config.can_print_to_stdout() {
+        println!("Created {:?}", opds_path);
+    }
+    Ok(())
+}
+
+/// Generates an OPDS acquisition feed listing the generated EPUBs. The links to the EPUBs are
+/// relative to the directory of the catalog where possible so that they can be served together.
+fn generate_opds_catalog(entries: &[OpdsEntry], catalog_path: &Path, updated: &str) -> String {
+    let catalog_dir = catalog_path.parent().unwrap_or_else(|| Path::new(""));
+    let escape_attr = |value: &str| replace_escaped_characters(value).replace('"', "&quot;");
+    let entry_tags: String = entries
+        .iter()
+        .map(|entry| {
+            let href = Path::new(entry.path)
+                .strip_prefix(catalog_dir)
+                .unwrap_or_else(|_| Path::new(entry.path))
+                .to_string_lossy()
+                .to_string();
+            let author_tag = entry
+                .author
+                .map(|author| {
+                    format!(
+                        "<author><name>{}</name></author>",
+                        replace_escaped_characters(author)
+                    )
+                })
+                .unwrap_or_default();
+            let source_tag = entry
+                .source
+                .map(|source| {
+                    format!(
+                        r#"<link rel="alternate" type="text/html" href="{}"/>"#,
+                        escape_attr(source)
+                    )
+                })
+                .unwrap_or_default();
+            format!(
+                r#"
+    <entry>
+        <title>{title}</title>
+        <id>urn:paperoni:{id:x}</id>
+        <updated>{updated}</updated>
+        {author}
+        <link rel="http://opds-spec.org/acquisition" type="application/epub+zip" href="{href}"/>
+        {source}
+    </entry>"#,
+                title = replace_escaped_characters(entry.title),
+                id = md5::compute(entry.source.unwrap_or(entry.path)),
+                updated = updated,
+                author = author_tag,
+                href = escape_attr(&href),
+                source = source_tag
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<feed xmlns="http://www.w3.org/2005/Atom">
+    <id>urn:paperoni:catalog</id>
+    <title>Paperoni</title>
+    <updated>{}</updated>
+    <link rel="self" type="application/atom+xml;profile=opds-catalog;kind=acquisition" href="{}"/>{}
+</feed>"#,
+        updated,
+        escape_attr(
+            &catalog_path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default()
+        ),
+        entry_tags
+    )
+}
+
 /// Adds an id attribute to header elements and assigns a value based on
 /// the hash of the text content. Headers with id attributes are not modified.
 /// The headers here are known to have text because the grabbed article from
@@ -328,7 +2105,11 @@ fn generate_header_ids(root_node: &NodeRef) {
 }
 
 /// Returns a vector of `TocElement` from a NodeRef used for adding to the Table of Contents for navigation
-fn get_header_level_toc_vec(content_url: &str, article: &NodeRef) -> Vec<TocElement> {
+fn get_header_level_toc_vec(
+    content_url: &str,
+    article: &NodeRef,
+    is_clamping_levels: bool,
+) -> Vec<TocElement> {
     // Depth starts from 1
     const HEADER_LEVEL_MAX_DEPTH: usize = 4;
     let mut headers_vec: Vec<TocElement> = Vec::new();
@@ -343,7 +2124,25 @@ fn get_header_level_toc_vec(content_url: &str, article: &NodeRef) -> Vec<TocElem
 
     let headings = article
         .select("h1, h2, h3, h4")
-        .expect("Unable to create selector for headings");
+        .expect("Unable to create selector for headings")
+        .collect::<Vec<_>>();
+
+    // When clamping, the levels used by the article are renumbered from 1 in order so that
+    // skipped levels, such as an h4 straight after an h1, do not leave gaps in the outline
+    if is_clamping_levels {
+        let used_levels = headings
+            .iter()
+            .map(|heading| header_levels[&*heading.name.local])
+            .collect::<std::collections::BTreeSet<_>>();
+        for level in header_levels.values_mut() {
+            if let Some(rank) = used_levels
+                .iter()
+                .position(|used_level| used_level == level)
+            {
+                *level = rank + 1;
+            }
+        }
+    }
 
     // The header list will be generated using some sort of backtracking algorithm
     // There will be a stack of maximum size 4 (since it only goes to h4 now)
@@ -427,76 +2226,26 @@ fn get_header_level_toc_vec(content_url: &str, article: &NodeRef) -> Vec<TocElem
     headers_vec
 }
 
-/// Serializes a NodeRef to a string that is XHTML compatible
-/// The only DOM nodes serialized are Text and Element nodes
-fn serialize_to_xhtml<W: std::io::Write>(
-    node_ref: &NodeRef,
-    mut w: &mut W,
-) -> Result<(), PaperoniError> {
-    {
-        // Add XHTML attributes
-        let html_elem = node_ref
-            .select_first("html")
-            .expect("Unable to get <html> element in article");
-        let mut html_attrs = html_elem.attributes.borrow_mut();
-        html_attrs.insert("xmlns", "http://www.w3.org/1999/xhtml".into());
-        html_attrs.insert("xmlns:epub", "http://www.idpf.org/2007/ops".into());
-    }
-    let mut escape_map = HashMap::new();
-    escape_map.insert("<", "&lt;");
-    escape_map.insert(">", "&gt;");
-    escape_map.insert("&", "&amp;");
-    escape_map.insert("\"", "&quot;");
-    escape_map.insert("'", "&apos;");
-    for edge in node_ref.traverse_inclusive() {
-        match edge {
-            kuchiki::iter::NodeEdge::Start(n) => match n.data() {
-                kuchiki::NodeData::Text(rc_text) => {
-                    let text = rc_text.borrow();
-                    let esc_text = ESC_SEQ_REGEX
-                        .replace_all(&text, |captures: &regex::Captures| escape_map[&captures[1]]);
-                    write!(&mut w, "{}", esc_text)?;
-                }
-                kuchiki::NodeData::Element(elem_data) => {
-                    let attrs = elem_data.attributes.borrow();
-                    let attrs_str = attrs
-                        .map
-                        .iter()
-                        .filter(|(k, _)| {
-                            let attr_key: &str = &k.local;
-                            attr_key.is_ascii() && VALID_ATTR_CHARS_REGEX.is_match(attr_key)
-                        })
-                        .map(|(k, v)| {
-                            format!(
-                                "{}=\"{}\"",
-                                k.local,
-                                ESC_SEQ_REGEX
-                                    .replace_all(&v.value, |captures: &regex::Captures| {
-                                        escape_map[&captures[1]]
-                                    })
-                            )
-                        })
-                        .fold("".to_string(), |acc, val| acc + " " + &val);
-                    write!(&mut w, "<{}{}>", &elem_data.name.local, attrs_str)?;
-                }
-                _ => (),
-            },
-            kuchiki::iter::NodeEdge::End(n) => match n.data() {
-                kuchiki::NodeData::Element(elem_data) => {
-                    write!(&mut w, "</{}>", &elem_data.name.local)?;
-                }
-                _ => (),
-            },
-        }
-    }
-    Ok(())
-}
-
 #[cfg(test)]
 mod test {
+    use comfy_table::Table;
+    use epub_builder::{EpubBuilder, EpubContent, EpubVersion, ZipLibrary};
     use kuchiki::traits::*;
 
-    use super::{generate_header_ids, get_header_level_toc_vec, replace_escaped_characters};
+    use std::path::Path;
+
+    use super::{
+        chapter_title, create_epub_file, fit_to_budget, generate_appendix,
+        generate_appendix_with_previous_sources, generate_epubs, generate_header_ids,
+        generate_identifier, generate_index, generate_opds_catalog, get_header_level_toc_vec,
+        majority_lang, make_reproducible, read_appendix_sources, read_nav_points,
+        render_chapter_xhtml, replace_escaped_characters, resolve_epub_path, set_chapter_lang,
+        text_direction, unique_epub_file_name, update_package_document, validate_epub,
+        ExistingEpub, NavPoint, OpdsEntry, PackageChanges, APPENDIX_REFERENCE_TYPE,
+    };
+    use crate::cli::{AppConfig, EpubDirection};
+    use crate::extractor::{serialize_body_to_xhtml_string, serialize_to_xhtml_string, Article};
+    use crate::logs::display_summary;
 
     #[test]
     fn test_replace_escaped_characters() {
@@ -567,7 +2316,7 @@ mod test {
         "#;
         let doc = kuchiki::parse_html().one(html_str);
 
-        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc);
+        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc, false);
         assert_eq!(0, toc_vec.len());
 
         let html_str = r#"
@@ -590,7 +2339,7 @@ mod test {
         "#;
         let doc = kuchiki::parse_html().one(html_str);
 
-        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc);
+        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc, false);
         assert_eq!(2, toc_vec.len());
 
         let first_h1_toc = toc_vec.first().unwrap();
@@ -631,7 +2380,7 @@ mod test {
         "#;
         let doc = kuchiki::parse_html().one(html_str);
 
-        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc);
+        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc, false);
         assert_eq!(1, toc_vec.len());
 
         let h1_toc = toc_vec.first().unwrap();
@@ -645,5 +2394,675 @@ mod test {
         let h3_toc = first_h2_toc.children.first().unwrap();
         assert_eq!("Subheading 3", h3_toc.title);
         assert_eq!(0, h3_toc.children.len());
+
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <h2 id="heading-2">Heading 2</h2>
+                <p>Lorem ipsum</p>
+                <h4 id="subheading-4">Subheading 4</h4>
+                <p>Lorem ipsum</p>
+                <h4 id="second-subheading-4">Second Subheading 4</h4>
+                <h2 id="conclusion">Conclusion</h2>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+
+        // The h4 headings are clamped to the level below the h2 headings
+        let toc_vec = get_header_level_toc_vec("index.xhtml", &doc, true);
+        assert_eq!(2, toc_vec.len());
+
+        let first_h2_toc = toc_vec.first().unwrap();
+        assert_eq!("Heading 2", first_h2_toc.title);
+        assert_eq!(2, first_h2_toc.children.len());
+        assert_eq!("Subheading 4", first_h2_toc.children[0].title);
+        assert_eq!("Second Subheading 4", first_h2_toc.children[1].title);
+
+        let last_h2_toc = toc_vec.last().unwrap();
+        assert_eq!("Conclusion", last_h2_toc.title);
+        assert_eq!(0, last_h2_toc.children.len());
+    }
+
+    #[test]
+    fn test_make_reproducible() {
+        use std::io::{Cursor, Read, Write};
+
+        let build_epub = |uuid: &str, timestamp: &str, year: u16| {
+            let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let last_modified = zip::DateTime::from_date_and_time(year, 1, 1, 0, 0, 0).unwrap();
+            let options = zip::write::FileOptions::default().last_modified_time(last_modified);
+            writer.start_file("OEBPS/index.xhtml", options).unwrap();
+            writer.write_all(b"<p>Salami</p>").unwrap();
+            writer.start_file("mimetype", options).unwrap();
+            writer.write_all(b"application/epub+zip").unwrap();
+            writer.start_file("OEBPS/content.opf", options).unwrap();
+            write!(
+                writer,
+                r#"<dc:identifier>urn:uuid:{}</dc:identifier><meta property="dcterms:modified">{}</meta>"#,
+                uuid, timestamp
+            )
+            .unwrap();
+            writer.finish().unwrap().into_inner()
+        };
+        let identifier = generate_identifier(&["http://example.org/salami"]);
+        let first = make_reproducible(
+            &build_epub(
+                "0b6ab5e4-4c4e-4f2e-9d5b-7f0b1d1e8c11",
+                "2021-06-01T10:00:00Z",
+                2021,
+            ),
+            &identifier,
+        )
+        .unwrap();
+        let second = make_reproducible(
+            &build_epub(
+                "9a3f7c2d-1b8e-4d6a-a5c4-3e2f1d0c9b8a",
+                "2022-02-03T04:05:06Z",
+                2022,
+            ),
+            &identifier,
+        )
+        .unwrap();
+        assert_eq!(first, second);
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(first)).unwrap();
+        let names = (0..archive.len())
+            .map(|idx| archive.by_index(idx).unwrap().name().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["mimetype", "OEBPS/content.opf", "OEBPS/index.xhtml"],
+            names
+        );
+        let mut opf = String::new();
+        archive
+            .by_name("OEBPS/content.opf")
+            .unwrap()
+            .read_to_string(&mut opf)
+            .unwrap();
+        assert_eq!(
+            format!(
+                r#"<dc:identifier>urn:uuid:{}</dc:identifier><meta property="dcterms:modified">1980-01-01T00:00:00Z</meta>"#,
+                identifier
+            ),
+            opf
+        );
+    }
+
+    #[test]
+    fn test_generate_identifier() {
+        let identifier = generate_identifier(&["http://example.org/salami"]);
+        assert_eq!(36, identifier.len());
+        assert_eq!(
+            identifier,
+            generate_identifier(&["http://example.org/salami"])
+        );
+        assert_ne!(
+            identifier,
+            generate_identifier(&["http://example.org/salami", "http://example.org/pepperoni"])
+        );
+    }
+
+    #[test]
+    fn test_read_nav_points() {
+        let ncx = r#"<?xml version="1.0" encoding="UTF-8"?>
+<ncx xmlns="http://www.daisy.org/z3986/2005/ncx/" version="2005-1">
+    <head>
+        <meta name="dtb:uid" content="urn:uuid:1234"/>
+    </head>
+    <docTitle><text>weekly.epub</text></docTitle>
+    <navMap>
+        <navPoint id="navPoint-1">
+            <navLabel><text>Salami &amp; Pepperoni</text></navLabel>
+            <content src="article_0.xhtml"/>
+            <navPoint id="navPoint-2">
+                <navLabel><text>Curing</text></navLabel>
+                <content src="article_0.xhtml#curing"/>
+            </navPoint>
+        </navPoint>
+        <navPoint id="navPoint-3">
+            <navLabel><text>Article Sources</text></navLabel>
+            <content src="appendix.xhtml"/>
+        </navPoint>
+    </navMap>
+</ncx>"#;
+        let nav_points = read_nav_points(ncx);
+
+        assert_eq!(
+            vec![
+                NavPoint {
+                    src: "article_0.xhtml".into(),
+                    title: "Salami &amp; Pepperoni".into(),
+                    children: vec![NavPoint {
+                        src: "article_0.xhtml#curing".into(),
+                        title: "Curing".into(),
+                        children: Vec::new(),
+                    }],
+                },
+                NavPoint {
+                    src: "appendix.xhtml".into(),
+                    title: "Article Sources".into(),
+                    children: Vec::new(),
+                },
+            ],
+            nav_points
+        );
+    }
+
+    #[test]
+    fn test_chapter_lang() {
+        let article_in = |lang: &str| {
+            let html_str = format!(
+                r#"<html lang="{}"><body><p>Lorem ipsum dolor sit amet.</p></body></html>"#,
+                lang
+            );
+            let mut article = Article::from_html(&html_str, "https://example.org/");
+            article.extract_content().unwrap();
+            article
+        };
+        let articles = vec![
+            article_in("fr"),
+            article_in("de"),
+            article_in("DE"),
+            article_in("fr"),
+        ];
+        assert_eq!(Some("fr".to_owned()), majority_lang(&articles));
+        assert_eq!(None, majority_lang(&[]));
+
+        set_chapter_lang(&articles[1]);
+        let xhtml = serialize_to_xhtml_string(articles[1].node_ref()).unwrap();
+        assert!(xhtml.contains(r#"lang="de""#));
+        assert!(xhtml.contains(r#"xml:lang="de""#));
+    }
+
+    #[test]
+    fn test_text_direction() {
+        assert_eq!(Some("rtl"), text_direction(&EpubDirection::Rtl, Some("en")));
+        assert_eq!(Some("ltr"), text_direction(&EpubDirection::Ltr, None));
+        assert_eq!(
+            Some("rtl"),
+            text_direction(&EpubDirection::Auto, Some("ar"))
+        );
+        assert_eq!(
+            Some("rtl"),
+            text_direction(&EpubDirection::Auto, Some("he-IL"))
+        );
+        assert_eq!(
+            Some("rtl"),
+            text_direction(&EpubDirection::Auto, Some("FA_ir"))
+        );
+        assert_eq!(
+            Some("ltr"),
+            text_direction(&EpubDirection::Auto, Some("en-US"))
+        );
+        assert_eq!(None, text_direction(&EpubDirection::Auto, None));
+    }
+
+    #[test]
+    fn test_update_package_document() {
+        use std::io::{Cursor, Read, Write};
+
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        let options = zip::write::FileOptions::default();
+        writer.start_file("mimetype", options).unwrap();
+        writer.write_all(b"application/epub+zip").unwrap();
+        writer.start_file("OEBPS/content.opf", options).unwrap();
+        writer
+            .write_all(
+                br#"<package><metadata></metadata><spine toc="ncx"><itemref idref="index"/></spine></package>"#,
+            )
+            .unwrap();
+        let epub_buf = writer.finish().unwrap().into_inner();
+
+        let changes = PackageChanges {
+            page_direction: Some("rtl"),
+            publisher: Some("Tom & Jerry's"),
+            has_appendix: false,
+        };
+        let epub_buf = update_package_document(&epub_buf, &changes).unwrap();
+        let mut archive = zip::ZipArchive::new(Cursor::new(epub_buf)).unwrap();
+        assert_eq!("mimetype", archive.by_index(0).unwrap().name());
+        let mut opf = String::new();
+        archive
+            .by_name("OEBPS/content.opf")
+            .unwrap()
+            .read_to_string(&mut opf)
+            .unwrap();
+        assert_eq!(
+            r#"<package><metadata><dc:publisher>Tom &amp; Jerry's</dc:publisher></metadata><spine page-progression-direction="rtl" toc="ncx"><itemref idref="index"/></spine></package>"#,
+            opf
+        );
+
+        // The appendix is marked as the appendix in the guide and the landmarks navigation
+        // instead of the bibliography. The landmarks navigation is checked in an EPUB 3.
+        let read_appendix_documents = |version| {
+            let mut epub = EpubBuilder::new(ZipLibrary::new().unwrap()).unwrap();
+            epub.epub_version(version);
+            epub.add_content(
+                EpubContent::new("appendix.xhtml", "<html><body></body></html>".as_bytes())
+                    .title("Article Sources")
+                    .reftype(APPENDIX_REFERENCE_TYPE),
+            )
+            .unwrap();
+            let mut epub_buf = Vec::new();
+            epub.generate(&mut epub_buf).unwrap();
+            let changes = PackageChanges {
+                page_direction: None,
+                publisher: None,
+                has_appendix: true,
+            };
+            let epub_buf = update_package_document(&epub_buf, &changes).unwrap();
+            let mut archive = zip::ZipArchive::new(Cursor::new(epub_buf)).unwrap();
+            let mut read_entry = |name| {
+                let mut document = String::new();
+                archive
+                    .by_name(name)
+                    .unwrap()
+                    .read_to_string(&mut document)
+                    .unwrap();
+                document
+            };
+            (
+                read_entry("OEBPS/content.opf"),
+                read_entry("OEBPS/nav.xhtml"),
+            )
+        };
+        let (opf, _) = read_appendix_documents(EpubVersion::V20);
+        assert!(opf.contains(
+            r#"<reference type="other.appendix" title="Article Sources" href="appendix.xhtml" />"#
+        ));
+        assert!(!opf.contains("bibliography"));
+        let (_, nav) = read_appendix_documents(EpubVersion::V30);
+        assert!(
+            nav.contains(r#"<a epub:type="appendix" href="appendix.xhtml">Article Sources</a>"#)
+        );
+        assert!(!nav.contains("bibliography"));
+    }
+
+    #[test]
+    fn test_render_chapter_xhtml() {
+        let html_str = r#"
+        <html lang="en">
+            <head>
+                <title>Salt & "Pepper"</title>
+                <meta name="author" content="Jane Doe">
+            </head>
+            <body><p>Lorem ipsum dolor sit amet, {{title}}.</p></body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "https://example.org/?a=1&b=2");
+        article.extract_content().unwrap();
+        let content = serialize_body_to_xhtml_string(article.node_ref()).unwrap();
+        assert!(!content.contains("<body"));
+        let template = r#"<html lang="{{lang}}"><head><meta name="source" content="{{ url }}"/><title>{{title}}</title></head><body><h1>{{title}}</h1><p>{{author}}</p>{{content}}</body></html>"#;
+        let xhtml = render_chapter_xhtml(template, &article, &content, None);
+        assert!(xhtml.starts_with(
+            r#"<html lang="en"><head><meta name="source" content="https://example.org/?a=1&amp;b=2"/><title>Salt &amp; &quot;Pepper&quot;</title></head><body><h1>Salt &amp; &quot;Pepper&quot;</h1><p>Jane Doe</p>"#
+        ));
+        assert!(xhtml.ends_with(&format!("{}</body></html>", content)));
+        assert!(xhtml.contains("Lorem ipsum dolor sit amet, {{title}}."));
+
+        let xhtml = render_chapter_xhtml(
+            template,
+            &article,
+            &content,
+            Some("p > a { color: red } /* {{title}} </style> */"),
+        );
+        assert!(xhtml.contains(
+            "<title>Salt &amp; &quot;Pepper&quot;</title><style>p &gt; a { color: red } /* {{title}} &lt;/style&gt; */</style></head><body>"
+        ));
+    }
+
+    #[test]
+    fn test_chapter_title() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Salami</title></head>
+            <body>
+                <article>
+                    <p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "https://www.example.org/salami");
+        article.extract_content().unwrap();
+        assert_eq!("Salami", chapter_title(None, 3, &article));
+        assert_eq!(
+            "3. Salami - example.org",
+            chapter_title(Some("{index}. {title} - {domain}"), 3, &article)
+        );
+        assert_eq!(
+            "Salami {date}",
+            chapter_title(Some("{title} {date}"), 1, &article)
+        );
+    }
+
+    #[test]
+    fn test_generate_appendix_with_previous_sources() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Salami</title></head>
+            <body>
+                <article>
+                    <p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.org/salami");
+        article.extract_content().unwrap();
+        let previous_appendix = generate_appendix(vec![&article]);
+        let previous_sources = read_appendix_sources(&previous_appendix).unwrap();
+        assert_eq!(
+            r#"<a href="http://example.org/salami">Salami</a><br></br>"#,
+            previous_sources
+        );
+
+        let appendix = generate_appendix_with_previous_sources(vec![&article], &previous_sources);
+        assert_eq!(
+            Some(format!("{}{}", previous_sources, previous_sources)),
+            read_appendix_sources(&appendix)
+        );
+        assert_eq!(None, read_appendix_sources("<html><body></body></html>"));
+        assert_eq!(
+            Some(previous_sources.clone()),
+            read_appendix_sources(&format!(
+                "<html><body><h2>Appendix</h2><h3>Article sources</h3>{}</body></html>",
+                previous_sources
+            ))
+        );
+    }
+
+    #[test]
+    fn test_unique_epub_file_name() {
+        let mut exported_files = Vec::new();
+        for _ in 0..3 {
+            let file_name = unique_epub_file_name("out", "Pepperoni", &exported_files);
+            exported_files.push(file_name);
+        }
+        assert_eq!(
+            vec![
+                "out/Pepperoni.epub",
+                "out/Pepperoni_1.epub",
+                "out/Pepperoni_2.epub"
+            ],
+            exported_files
+        );
+        assert_eq!(
+            "out/Salami.epub",
+            unique_epub_file_name("out", "Salami", &exported_files)
+        );
+    }
+
+    #[test]
+    fn test_create_epub_file() {
+        let path = std::env::temp_dir()
+            .join("paperoni-missing-dir")
+            .join("Salami.epub");
+        let err = create_epub_file(path.to_str().unwrap()).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains(&format!("Unable to create {:?}", path.to_str().unwrap())));
+    }
+
+    #[test]
+    fn test_fit_to_budget() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Salami</title></head>
+            <body>
+                <article>
+                    <h2>Curing</h2>
+                    <p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>
+                    <p>Sed do eiusmod tempor incididunt ut labore et dolore magna aliqua.</p>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.org/salami");
+        article.extract_content().unwrap();
+        assert!(fit_to_budget(&article, 1024 * 1024, false).is_empty());
+        assert!(!article.node_ref().text_contents().contains("shortened"));
+
+        fit_to_budget(&article, 120, false);
+        let text = article.node_ref().text_contents();
+        assert!(text.contains("Lorem ipsum"));
+        assert!(!text.contains("Sed do eiusmod"));
+        assert!(text.contains("This article was shortened to fit the per-article budget"));
+    }
+
+    #[test]
+    fn test_generate_index() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Salami & Pepperoni</title></head>
+            <body>
+                <article>
+                    <h2>Curing</h2>
+                    <p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.org/salami?a=1&b=2");
+        article.extract_content().unwrap();
+        let index = generate_index(&[("./articles/Salami & Pepperoni.epub".into(), &article)]);
+        let doc = kuchiki::parse_html().one(index);
+
+        let links = doc.select("li > a").unwrap().collect::<Vec<_>>();
+        assert_eq!(2, links.len());
+        assert_eq!("Salami & Pepperoni", links[0].text_contents());
+        assert_eq!(
+            Some("Salami & Pepperoni.epub"),
+            links[0].attributes.borrow().get("href")
+        );
+        assert_eq!(
+            Some("http://example.org/salami?a=1&b=2"),
+            links[1].attributes.borrow().get("href")
+        );
+    }
+
+    #[test]
+    fn test_generate_epubs_file_errors() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Salami</title></head>
+            <body>
+                <article>
+                    <p>Lorem ipsum dolor sit amet, consectetur adipiscing elit.</p>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.org/salami");
+        article.extract_content().unwrap();
+        let output_dir = std::env::temp_dir().join("paperoni-file-errors");
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let opds_path = output_dir.join("missing-dir").join("catalog.xml");
+        let app_config = AppConfig::from_args(&[
+            "paperoni",
+            "http://example.org/salami",
+            "--output-dir",
+            output_dir.to_str().unwrap(),
+            "--opds",
+            opds_path.to_str().unwrap(),
+        ]);
+        let mut successful_articles_table = Table::new();
+        let (mut exported_files, mut metadata_rows, mut file_errors) =
+            (Vec::new(), Vec::new(), Vec::new());
+        let result = generate_epubs(
+            vec![article],
+            &app_config,
+            &mut successful_articles_table,
+            &mut exported_files,
+            &mut metadata_rows,
+            &mut file_errors,
+        );
+        std::fs::remove_dir_all(&output_dir).unwrap();
+        assert!(result.is_ok());
+        assert_eq!(1, exported_files.len());
+        assert_eq!(1, file_errors.len());
+
+        // The catalog is not counted as a failed article
+        let mut out = Vec::new();
+        display_summary(
+            1,
+            successful_articles_table,
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            file_errors,
+            None,
+            &mut out,
+        )
+        .unwrap();
+        let summary = String::from_utf8(out).unwrap();
+        assert!(summary.contains("Article downloaded successfully"));
+        assert!(summary.contains("Failed files"));
+    }
+
+    #[test]
+    fn test_generate_opds_catalog() {
+        let entries = vec![
+            OpdsEntry {
+                path: "archive/Salami & Pepperoni.epub",
+                title: "Salami & Pepperoni",
+                author: Some("Paperoni"),
+                source: Some("http://example.org/salami"),
+            },
+            OpdsEntry {
+                path: "/tmp/merged.epub",
+                title: "merged",
+                author: None,
+                source: None,
+            },
+        ];
+        let catalog = generate_opds_catalog(
+            &entries,
+            Path::new("archive/catalog.xml"),
+            "2021-06-01T12:00:00+00:00",
+        );
+        let doc = kuchiki::parse_html().one(catalog);
+
+        let titles = doc
+            .select("entry > title")
+            .unwrap()
+            .map(|title| title.text_contents())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["Salami & Pepperoni", "merged"], titles);
+        assert_eq!(1, doc.select("entry > author").unwrap().count());
+
+        let acquisition_links = doc
+            .select(r#"entry > link[rel="http://opds-spec.org/acquisition"]"#)
+            .unwrap()
+            .map(|link| link.attributes.borrow().get("href").unwrap().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec!["Salami & Pepperoni.epub", "/tmp/merged.epub"],
+            acquisition_links
+        );
+    }
+
+    #[test]
+    fn test_resolve_epub_path() {
+        assert_eq!(
+            "OEBPS/index.xhtml",
+            resolve_epub_path("OEBPS/", "index.xhtml")
+        );
+        assert_eq!(
+            "images/a.png",
+            resolve_epub_path("OEBPS/", "../images/a.png")
+        );
+        assert_eq!("OEBPS/a.png", resolve_epub_path("OEBPS/", "./a.png"));
+        assert_eq!("content.opf", resolve_epub_path("", "content.opf"));
+    }
+
+    #[test]
+    fn test_validate_epub() {
+        use std::io::{Cursor, Write};
+
+        let build_epub = |chapter: &str| {
+            let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+            let options = zip::write::FileOptions::default();
+            writer.start_file("mimetype", options).unwrap();
+            writer.write_all(b"application/epub+zip").unwrap();
+            writer
+                .start_file("META-INF/container.xml", options)
+                .unwrap();
+            writer
+                .write_all(br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf" media-type="application/oebps-package+xml"/></rootfiles></container>"#)
+                .unwrap();
+            writer.start_file("OEBPS/content.opf", options).unwrap();
+            writer
+                .write_all(br#"<package><manifest><item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/></manifest><spine><itemref idref="chapter"/></spine></package>"#)
+                .unwrap();
+            writer.start_file("OEBPS/chapter.xhtml", options).unwrap();
+            writer.write_all(chapter.as_bytes()).unwrap();
+            writer.finish().unwrap().into_inner()
+        };
+
+        let valid = build_epub(
+            r#"<?xml version="1.0" encoding="UTF-8"?><html><body><!-- <p> --><p>Salami &amp; eggs&#160;<br/></p></body></html>"#,
+        );
+        assert_eq!(Vec::<String>::new(), validate_epub(&valid).unwrap());
+
+        let invalid = build_epub(
+            r#"<html><body><p>Salami &nbsp; eggs<br></p><img src="images/missing.png"/></body></html>"#,
+        );
+        assert_eq!(
+            vec![
+                "OEBPS/chapter.xhtml: &nbsp; is not a valid XML entity",
+                "OEBPS/chapter.xhtml: <br> is not closed",
+                "OEBPS/chapter.xhtml refers to OEBPS/images/missing.png which is missing",
+            ],
+            validate_epub(&invalid).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_existing_epub_read() {
+        use std::io::Write;
+
+        let path = std::env::temp_dir().join("paperoni-existing-epub.epub");
+        let mut writer = zip::ZipWriter::new(std::fs::File::create(&path).unwrap());
+        let options = zip::write::FileOptions::default();
+        writer
+            .start_file("META-INF/container.xml", options)
+            .unwrap();
+        writer
+            .write_all(br#"<container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#)
+            .unwrap();
+        writer.start_file("OEBPS/content.opf", options).unwrap();
+        writer
+            .write_all(br#"<package><manifest><item id="c" href="c.png" media-type="image/png"/><item id="chapter" href="chapter.xhtml" media-type="application/xhtml+xml"/><item id="a" href="a.png" media-type="image/png"/><item id="b" href="b.png" media-type="image/png"/></manifest><spine><itemref idref="chapter"/></spine></package>"#)
+            .unwrap();
+        for file in &["chapter.xhtml", "a.png", "b.png", "c.png"] {
+            writer
+                .start_file(format!("OEBPS/{}", file), options)
+                .unwrap();
+            writer.write_all(file.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let existing_epub = ExistingEpub::read(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(1, existing_epub.chapters.len());
+        assert_eq!("chapter.xhtml", existing_epub.chapters[0].path);
+        assert_eq!(
+            vec!["c.png", "a.png", "b.png"],
+            existing_epub
+                .resources
+                .iter()
+                .map(|(path, _, _)| path.as_str())
+                .collect::<Vec<_>>()
+        );
     }
 }
@@ -15,6 +15,65 @@ pub enum ErrorKind {
     UTF8Error(String),
     #[error("[ReadabilityError]: {0}")]
     ReadabilityError(String),
+    #[error("[TimeoutError]: {0}")]
+    TimeoutError(String),
+    #[error("[InsecureImageError]: {0}")]
+    InsecureImageError(String),
+    #[error("[NetrcError]: {0}")]
+    NetrcError(String),
+    #[error("[PartialDownloadError]: {0}")]
+    PartialDownloadError(String),
+}
+
+impl ErrorKind {
+    /// Returns the stable, machine-readable category of the error
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            ErrorKind::EpubError(_) => ErrorCode::EpubBuild,
+            ErrorKind::HTTPError(_) => ErrorCode::Http,
+            ErrorKind::IOError(_) => ErrorCode::Io,
+            ErrorKind::UTF8Error(_) => ErrorCode::Utf8,
+            ErrorKind::ReadabilityError(_) => ErrorCode::Extraction,
+            ErrorKind::TimeoutError(_) => ErrorCode::Timeout,
+            ErrorKind::InsecureImageError(_) => ErrorCode::Http,
+            ErrorKind::NetrcError(_) => ErrorCode::Io,
+            ErrorKind::PartialDownloadError(_) => ErrorCode::PartialDownload,
+        }
+    }
+}
+
+/// A stable identifier for the category of an error. Unlike the error messages, these
+/// values are not expected to change and can be relied on when scripting around failures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum ErrorCode {
+    Http,
+    Extraction,
+    Io,
+    Utf8,
+    EpubBuild,
+    Timeout,
+    /// Some of the images or attachments of an article failed to download with `--fail-on-partial`
+    PartialDownload,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Http => "Http",
+            ErrorCode::Extraction => "Extraction",
+            ErrorCode::Io => "Io",
+            ErrorCode::Utf8 => "Utf8",
+            ErrorCode::EpubBuild => "EpubBuild",
+            ErrorCode::Timeout => "Timeout",
+            ErrorCode::PartialDownload => "PartialDownload",
+        }
+    }
+}
+
+impl Display for ErrorCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -38,6 +97,10 @@ impl ImgError {
     pub fn url(&self) -> &Option<String> {
         &self.url
     }
+
+    pub fn kind(&self) -> &ErrorKind {
+        &self.kind
+    }
 }
 
 impl From<ErrorKind> for ImgError {
@@ -83,6 +146,10 @@ impl PaperoniError {
         &self.kind
     }
 
+    pub fn code(&self) -> ErrorCode {
+        self.kind.code()
+    }
+
     pub fn article_source(&self) -> &Option<String> {
         &self.article_source
     }
@@ -104,6 +171,12 @@ impl From<epub_builder::Error> for PaperoniError {
     }
 }
 
+impl From<zip::result::ZipError> for PaperoniError {
+    fn from(err: zip::result::ZipError) -> Self {
+        PaperoniError::with_kind(ErrorKind::EpubError(err.to_string()))
+    }
+}
+
 impl From<surf::Error> for PaperoniError {
     fn from(err: surf::Error) -> Self {
         PaperoniError::with_kind(ErrorKind::HTTPError(err.to_string()))
@@ -152,12 +225,76 @@ pub enum CliError<BuilderError: Debug + Display> {
     UrlFileError(#[from] std::io::Error),
     #[error("Failed to parse max connection value: {0}")]
     InvalidMaxConnectionCount(#[from] std::num::ParseIntError),
+    #[error("Failed to parse concurrency per host value: {0}")]
+    InvalidConcurrencyPerHost(std::num::ParseIntError),
+    #[error("Failed to parse image concurrency value: {0}")]
+    InvalidImageConcurrency(std::num::ParseIntError),
+    #[error("Failed to parse image timeout value: {0}")]
+    InvalidImageTimeout(std::num::ParseIntError),
+    #[error("Failed to parse minimum image size value: {0}")]
+    InvalidMinImageSize(std::num::ParseIntError),
+    #[error("Failed to parse minimum score value: {0}")]
+    InvalidMinScore(std::num::ParseFloatError),
+    #[error("Failed to parse minimum paragraphs value: {0}")]
+    InvalidMinParagraphs(std::num::ParseIntError),
+    #[error("Failed to parse character threshold value: {0}")]
+    InvalidCharThreshold(std::num::ParseIntError),
+    #[error("Failed to parse top candidates value: {0}")]
+    InvalidTopCandidates(std::num::ParseIntError),
+    #[error("Failed to parse minimum paragraph length value: {0}")]
+    InvalidMinParagraphLength(std::num::ParseIntError),
+    #[error("Failed to parse crawl depth value: {0}")]
+    InvalidCrawlDepth(std::num::ParseIntError),
+    #[error("Invalid link selector: {0}")]
+    InvalidLinkSelector(String),
+    #[error("Invalid interstitial selector: {0}")]
+    InvalidInterstitialSelector(String),
+    #[error("Invalid sitemap filter: {0}")]
+    InvalidSitemapFilter(String),
+    #[error("Invalid URL filter: {0}. It must be a valid regex")]
+    InvalidUrlFilter(String),
+    #[error("Invalid proxy: {0}. It must be a URL with a port and one of the http, https, socks4, socks4a, socks5 or socks5h schemes e.g socks5h://127.0.0.1:9050")]
+    InvalidProxy(String),
+    #[error("Failed to parse sitemap limit value: {0}")]
+    InvalidSitemapLimit(std::num::ParseIntError),
+    #[error("Invalid --last value: {0}. It must be a number followed by h, d or w e.g 7d")]
+    InvalidTimeWindow(String),
+    #[error("Failed to parse limit value: {0}")]
+    InvalidLimit(std::num::ParseIntError),
+    #[error("Failed to parse start position value: {0}")]
+    InvalidStartAt(std::num::ParseIntError),
+    #[error("Failed to load the netrc file: {0}")]
+    InvalidNetrc(PaperoniError),
+    #[error("Invalid per-article budget: {0}. It must be a positive number of megabytes")]
+    InvalidPerArticleBudget(String),
+    #[error("Invalid image quality: {0}. It must be a number from 1 to 100")]
+    InvalidImageQuality(String),
+    #[error("--image-format-target {0} can only be used when exporting to epub or html")]
+    WrongExportImageFormatTarget(String),
+    #[error("Invalid dedupe distance: {0}. It must be a number of bits from 0 to 64")]
+    InvalidDedupeDistance(String),
+    #[error("Invalid body width: {0}. It must be a positive length in ch, em, px or rem e.g 40em")]
+    InvalidBodyWidth(String),
+    #[error("Failed to parse shuffle seed value: {0}")]
+    InvalidShuffleSeed(std::num::ParseIntError),
+    #[error("Invalid chapter template: {0}")]
+    InvalidChapterXhtmlTemplate(String),
+    #[error("Failed to read config file {0}")]
+    ConfigFileError(String),
     #[error("No urls were provided")]
     NoUrls,
     #[error("Failed to build cli application: {0}")]
     AppBuildError(BuilderError),
     #[error("Invalid output path name for merged epubs: {0}")]
     InvalidOutputPath(String),
+    #[error("The --merge-title-from-first flag names the merged export so --merge cannot be given a name")]
+    MergeTitleWithName,
+    #[error("The EPUB to append to does not exist: {0}")]
+    AppendFileNotExists(String),
+    #[error("Several articles can only be written to stdout when merged. Pass --merge - instead")]
+    StdoutRequiresMerge,
+    #[error("The --{0} flag cannot be used when writing to stdout")]
+    StdoutConflict(String),
     #[error("Wrong output directory")]
     WrongOutputDirectory,
     #[error("Output directory does not exist")]
@@ -168,6 +305,56 @@ pub enum CliError<BuilderError: Debug + Display> {
     WrongExportInliningToC,
     #[error("The --inline-images flag can only be used when exporting to html")]
     WrongExportInliningImages,
+    #[error("The --flatten-images flag can only be used when exporting to html")]
+    WrongExportFlattenImages,
+    #[error("The --no-toc flag can only be used when exporting to epub")]
+    WrongExportNoToc,
+    #[error("The --index flag can only be used when exporting to epub")]
+    WrongExportIndex,
+    #[error("The --fetch-linked-pdfs flag can only be used when exporting to epub")]
+    WrongExportLinkedPdfs,
+    #[error("The --fetch-audio flag can only be used when exporting to epub")]
+    WrongExportAudio,
+    #[error("The --append-to flag can only be used when exporting to epub")]
+    WrongExportAppend,
+    #[error("The --reproducible flag can only be used when exporting to epub")]
+    WrongExportReproducible,
+    #[error("The --chapter-template flag can only be used when exporting to epub")]
+    WrongExportChapterTemplate,
+    #[error("The --template-dir flag can only be used when exporting to epub")]
+    WrongExportTemplateDir,
+    #[error("The --validate flag can only be used when exporting to epub")]
+    WrongExportValidate,
+    #[error("The --highlight-theme flag can only be used when exporting to epub or html")]
+    WrongExportHighlightTheme,
+    #[error("The --images flag can only be used when exporting to epub or html")]
+    WrongExportImages,
+    #[error("The --favicons flag can only be used when exporting to epub or html")]
+    WrongExportFavicons,
+    #[error("The --qr flag can only be used when exporting to epub or html")]
+    WrongExportQr,
+    #[error("The --inline-source flag can only be used when exporting to epub or html")]
+    WrongExportInlineSource,
+    #[error("The --break-long-words flag can only be used when exporting to epub or html")]
+    WrongExportBreakLongWords,
+    #[error("The --author-from-domain flag can only be used when exporting to epub")]
+    WrongExportAuthorFromDomain,
+    #[error("The --concurrent-epub-gen flag can only be used when exporting to epub")]
+    WrongExportConcurrentEpubGen,
+    #[error("The --per-article-budget flag can only be used when merging articles into an epub")]
+    WrongExportBudget,
+    #[error("Writing to stdout is only supported when exporting to epub")]
+    WrongExportStdout,
+    #[error("The --opds flag can only be used when exporting to epub")]
+    WrongExportOpds,
+    #[error("The --epub-direction flag can only be used when exporting to epub")]
+    WrongExportEpubDirection,
+    #[error("The --body-width flag can only be used when exporting to epub or html")]
+    WrongExportBodyWidth,
+    #[error("The --clamp-heading-levels flag can only be used when exporting to epub")]
+    WrongExportClampHeadingLevels,
+    #[error("The --user-css-inline option can only be used when exporting to epub or html")]
+    WrongExportUserCss,
 }
 
 // dumb hack to allow for comparing errors in testing.
@@ -177,3 +364,26 @@ impl<T: Debug + Display> PartialEq for CliError<T> {
         format!("{:?}", self) == format!("{:?}", other)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let err: PaperoniError = ErrorKind::HTTPError("Request failed".into()).into();
+        assert_eq!(ErrorCode::Http, err.code());
+
+        let err: PaperoniError =
+            std::io::Error::new(std::io::ErrorKind::NotFound, "No such file").into();
+        assert_eq!(ErrorCode::Io, err.code());
+
+        let err: PaperoniError = ErrorKind::ReadabilityError("No content".into()).into();
+        assert_eq!(ErrorCode::Extraction, err.code());
+        assert_eq!("Extraction", err.code().to_string());
+
+        let err: PaperoniError =
+            ErrorKind::PartialDownloadError("1 image failed to download".into()).into();
+        assert_eq!("PartialDownload", err.code().to_string());
+    }
+}
@@ -1,17 +1,151 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::time::Duration;
+
+use html5ever::{LocalName, Namespace, QualName};
 use itertools::Itertools;
-use kuchiki::{traits::*, NodeRef};
+use kuchiki::{traits::*, ElementData, NodeDataRef, NodeRef};
+use serde::Serialize;
+use url::Url;
 
+use crate::cli::{ArticleOrder, ImagesMode};
 use crate::errors::PaperoniError;
-use crate::moz_readability::{MetaData, Readability};
+use crate::moz_readability::{MetaData, Readability, ReadabilityOptions};
 
 /// A tuple of the url and an Option of the resource's MIME type
 pub type ResourceInfo = (String, Option<String>);
 
+/// The ratio of link text to all text above which an article is considered to be mostly links
+const MAX_LINK_DENSITY: f32 = 0.5;
+/// Articles with fewer paragraphs than this are likely to be a fragment of the actual article
+const MIN_PARAGRAPH_COUNT: usize = 3;
+/// Subscription boilerplate is only considered suspicious in articles shorter than this
+const BOILERPLATE_MAX_TEXT_LEN: usize = 1500;
+/// Blocks with more text than this are never removed as interstitials
+const INTERSTITIAL_MAX_TEXT_LEN: usize = 300;
+/// The elements that are checked when removing interstitials
+const INTERSTITIAL_CANDIDATES: &str = "aside, div, form, p, section";
+/// The elements that are removed when cleaning articles aggressively as they usually hold site
+/// navigation and related links rather than the article
+const BOILERPLATE_ELEMENTS: &str = "nav, aside, footer, [role=navigation], [role=complementary], [role=contentinfo], [role=banner]";
+/// Words longer than this number of characters are given break points with `--break-long-words`
+const LONG_WORD_LEN: usize = 30;
+/// The longest run of characters left without a break point in a long word
+const MAX_UNBROKEN_LEN: usize = 20;
+/// Characters in URLs and paths that a long word can be broken after
+const WORD_BREAK_CHARS: [char; 11] = ['/', '.', '-', '_', '?', '&', '=', '#', ',', ';', ':'];
+/// Elements whose text is left without break points since it is code or not displayed
+const NO_BREAK_ELEMENTS: &str = "pre, code, kbd, samp, script, style, textarea, math";
+/// Images sized below this number of pixels are not picked as the lead image of an article
+const LEAD_IMAGE_MIN_SIZE: u32 = 200;
+/// Extensions of the linked audio files that are downloaded with `--fetch-audio`
+const AUDIO_EXTS: [&str; 7] = ["aac", "m4a", "mp3", "oga", "ogg", "opus", "wav"];
+/// The image MIME types that EPUB reading systems are required to support
+const SUPPORTED_IMAGE_TYPES: [&str; 4] = ["image/gif", "image/jpeg", "image/png", "image/svg+xml"];
+/// Extensions of image formats that most EPUB reading systems cannot display
+const UNSUPPORTED_IMAGE_EXTS: [&str; 3] = ["avif", "jxl", "webp"];
+/// Query parameters that are commonly used for cache busting and do not change the image served
+const CACHE_BUSTING_PARAMS: [&str; 11] = [
+    "_",
+    "cache",
+    "cachebust",
+    "cb",
+    "rev",
+    "t",
+    "timestamp",
+    "ts",
+    "v",
+    "ver",
+    "version",
+];
+const MATHML_NS: &str = "http://www.w3.org/1998/Math/MathML";
+/// Separators that sites put between the headline and their name in page titles
+const TITLE_SITE_SEPARATORS: [&str; 7] = [" | ", " - ", " – ", " — ", " · ", " :: ", " » "];
+/// Words that are not capitalized in title case unless they start or end the title
+const TITLE_CASE_MINOR_WORDS: [&str; 14] = [
+    "a", "an", "and", "as", "at", "but", "by", "for", "in", "nor", "of", "on", "or", "the",
+];
+
+lazy_static! {
+    static ref ESC_SEQ_REGEX: regex::Regex = regex::Regex::new(r#"(&|<|>|'|")"#).unwrap();
+    pub static ref VALID_ATTR_CHARS_REGEX: regex::Regex = regex::Regex::new(r#"[a-z0-9\-_:]"#).unwrap();
+    static ref BOILERPLATE_REGEX: regex::Regex = regex::Regex::new(
+        r"(?i)subscribe|sign in|sign up|log in|create a free account|already a member|paywall"
+    )
+    .unwrap();
+    static ref BOILERPLATE_CLASS_REGEX: regex::Regex = regex::Regex::new(
+        r"(?i)\b(sidebar|related|share|sharing|social|breadcrumbs?|comments?|promo|advert|advertisement|navbar|navigation|menu|site-footer|site-header)\b"
+    )
+    .unwrap();
+    /// Pictographs shown as emoji by default or followed by the emoji variation selector, along
+    /// with the modifiers, tags and joined pictographs that combine them into a single emoji.
+    /// Symbols such as ✓ and ♯ that are shown as text by default are left in place.
+    static ref EMOJI_REGEX: regex::Regex = regex::Regex::new(
+        r"(?:\p{Emoji_Presentation}|\p{Extended_Pictographic}\x{FE0F})[\x{FE0E}\x{FE0F}\x{1F3FB}-\x{1F3FF}\x{E0020}-\x{E007F}]*(?:\x{200D}\p{Extended_Pictographic}[\x{FE0E}\x{FE0F}\x{1F3FB}-\x{1F3FF}]*)*"
+    )
+    .unwrap();
+    static ref INTERSTITIAL_REGEX: regex::Regex = regex::Regex::new(
+        r"(?i)\b(subscribe to|sign up for|join) (our|the|my) (free |weekly |daily )?(newsletter|mailing list)|\b(we|this site|this website) uses? cookies|\baccept all cookies|\bmanage (cookie|privacy) (settings|preferences)|\benter your email address"
+    )
+    .unwrap();
+}
+
+/// The metadata of an article written alongside its exported file
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ArticleSidecar<'a> {
+    pub title: &'a str,
+    pub author: Option<&'a str>,
+    pub site_name: Option<&'a str>,
+    pub excerpt: Option<&'a str>,
+    pub source_url: &'a str,
+    pub word_count: usize,
+    pub image_count: usize,
+    /// The URL that was requested when the article was redirected to `source_url`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub http_status: Option<u16>,
+}
+
+/// How the HTML of an article was served
+#[derive(Debug, PartialEq)]
+pub struct ResponseInfo {
+    /// The URL that was requested before any redirects were followed
+    pub requested_url: String,
+    /// The HTTP status of the response. It is missing for articles rendered in a browser
+    pub status: Option<u16>,
+    pub timings: ArticleTimings,
+}
+
+/// How long an article took to download, including its images, and to extract
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ArticleTimings {
+    pub download: Duration,
+    pub extraction: Duration,
+}
+
+/// A document linked from an article that has been downloaded to the temp directory
+#[derive(Clone)]
+pub struct Attachment {
+    pub url: String,
+    pub file_name: String,
+    pub mime_type: String,
+}
+
 pub struct Article {
     node_ref_opt: Option<NodeRef>,
     pub img_urls: Vec<ResourceInfo>,
-    readability: Readability,
+    pub attachments: Vec<Attachment>,
+    /// The image of the article's metadata, used as the cover of its EPUB
+    pub cover: Option<ResourceInfo>,
+    /// This is consumed once the content has been extracted
+    readability: Option<Readability>,
+    metadata: MetaData,
     pub url: String,
+    /// The position of the article's URL in the URLs of the run, which `--number` numbers it by
+    pub url_index: usize,
+    /// This is set once the article has been downloaded
+    pub response: Option<ResponseInfo>,
 }
 
 impl Article {
@@ -20,16 +154,33 @@ impl Article {
         Self {
             node_ref_opt: None,
             img_urls: Vec::new(),
-            readability: Readability::new(html_str),
+            attachments: Vec::new(),
+            cover: None,
+            readability: Some(Readability::new(html_str)),
+            metadata: MetaData::new(),
             url: url.to_string(),
+            url_index: 0,
+            response: None,
+        }
+    }
+
+    /// Sets the options used by readability when extracting the content
+    pub fn set_readability_options(&mut self, options: ReadabilityOptions) {
+        if let Some(readability) = &mut self.readability {
+            readability.options = options;
         }
     }
 
     /// Locates and extracts the HTML in a document which is determined to be
     /// the source of the content
     pub fn extract_content(&mut self) -> Result<(), PaperoniError> {
-        self.readability.parse(&self.url)?;
-        if let Some(article_node_ref) = &self.readability.article_node {
+        let mut readability = self
+            .readability
+            .take()
+            .expect("Article content has already been extracted");
+        readability.parse(&self.url)?;
+        self.metadata = readability.metadata;
+        if let Some(article_node_ref) = readability.article_node {
             let template = r#"
             <!DOCTYPE html>
             <html>
@@ -42,7 +193,7 @@ impl Article {
             "#;
             let doc = kuchiki::parse_html().one(template);
             let body = doc.select_first("body").unwrap();
-            body.as_node().append(article_node_ref.clone());
+            body.as_node().append(article_node_ref);
             self.node_ref_opt = Some(doc);
         }
         Ok(())
@@ -51,6 +202,7 @@ impl Article {
     /// Traverses the DOM tree of the content and retrieves the IMG URLs
     pub fn extract_img_urls(&mut self) {
         if let Some(content_ref) = &self.node_ref_opt {
+            resolve_picture_sources(content_ref);
             self.img_urls = content_ref
                 .select("img")
                 .unwrap()
@@ -58,7 +210,10 @@ impl Article {
                     let attrs = img_ref.attributes.borrow();
                     attrs
                         .get("src")
-                        .filter(|val| !(val.is_empty() || val.starts_with("data:image")))
+                        .filter(|val| {
+                            !(val.is_empty()
+                                || (val.starts_with("data:") && !val.starts_with("data:image/")))
+                        })
                         .map(ToString::to_string)
                 })
                 .unique()
@@ -67,6 +222,236 @@ impl Article {
         }
     }
 
+    /// Points the links to other parts of the article, such as in-text citations linking to the
+    /// references, at ids that are valid in the exports. The targets are given an id generated
+    /// from the hash of the article URL and the fragment so that the ids of articles merged into
+    /// one document do not clash. Links to parts of the page that were not extracted are pointed
+    /// back at the page.
+    pub fn link_internal_anchors(&mut self) {
+        let content_ref = match &self.node_ref_opt {
+            Some(content_ref) => content_ref,
+            None => return,
+        };
+        let article_url = Url::parse(&self.url).ok();
+        let page_url = article_url.as_ref().map(|article_url| {
+            let mut page_url = article_url.clone();
+            page_url.set_fragment(None);
+            page_url
+        });
+        let mut target_ids: HashMap<String, Option<String>> = HashMap::new();
+        for link_ref in content_ref.select("a[href]").unwrap().collect_vec() {
+            let href = link_ref.attributes.borrow().get("href").unwrap().to_owned();
+            let fragment = if let Some(fragment) = href.strip_prefix('#') {
+                fragment.to_owned()
+            } else {
+                match (&page_url, Url::parse(&href)) {
+                    (Some(page_url), Ok(mut link_url)) => {
+                        let fragment = link_url.fragment().map(ToOwned::to_owned);
+                        link_url.set_fragment(None);
+                        match fragment {
+                            Some(fragment) if &link_url == page_url => fragment,
+                            _ => continue,
+                        }
+                    }
+                    _ => continue,
+                }
+            };
+            if fragment.is_empty() {
+                continue;
+            }
+            let target_id = target_ids
+                .entry(fragment.clone())
+                .or_insert_with(|| {
+                    let target = content_ref.descendants().elements().find(|elem| {
+                        let attrs = elem.attributes.borrow();
+                        attrs.get("id") == Some(fragment.as_str())
+                            || (&elem.name.local == "a"
+                                && attrs.get("name") == Some(fragment.as_str()))
+                    })?;
+                    // The id begins with an underscore like the ids of headers since the
+                    // hexadecimal digest might start with a number
+                    let id = format!("_{:x}", md5::compute(format!("{}#{}", self.url, fragment)));
+                    target.attributes.borrow_mut().insert("id", id.clone());
+                    Some(id)
+                })
+                .clone();
+            let new_href = match (target_id, &article_url) {
+                (Some(target_id), _) => format!("#{}", target_id),
+                (None, Some(article_url)) => match article_url.join(&format!("#{}", fragment)) {
+                    Ok(page_link) => page_link.to_string(),
+                    Err(_) => continue,
+                },
+                (None, None) => continue,
+            };
+            link_ref.attributes.borrow_mut().insert("href", new_href);
+        }
+    }
+
+    /// Removes cache busting query parameters from the image URLs so that the same image served
+    /// with different cache busting values is only downloaded once. Other query parameters,
+    /// such as those for resizing, are kept.
+    pub fn strip_img_cache_busters(&mut self) {
+        let content_ref = match &self.node_ref_opt {
+            Some(content_ref) => content_ref,
+            None => return,
+        };
+        let base_url = match Url::parse(&self.url) {
+            Ok(base_url) => base_url,
+            Err(_) => return,
+        };
+        resolve_picture_sources(content_ref);
+        for img_ref in content_ref.select("img[src]").unwrap() {
+            let mut attrs = img_ref.attributes.borrow_mut();
+            let stripped_src = match attrs.get("src") {
+                Some(src) if !src.starts_with("data:") => strip_cache_busters(&base_url, src),
+                _ => None,
+            };
+            if let Some(stripped_src) = stripped_src {
+                attrs.insert("src", stripped_src);
+            }
+        }
+    }
+
+    /// Removes the images of the content that are left out by the images mode. With
+    /// `ImagesMode::Lead`, only the article's lead image is kept. It is the image of the
+    /// article's metadata, which is added at the start of the content if it is missing, or else
+    /// the first image that is not sized below `LEAD_IMAGE_MIN_SIZE`. Figures holding removed
+    /// images are removed along with their captions unless they hold other images. When
+    /// `is_keeping_placeholders` is set, the removed images are replaced with placeholders of the
+    /// same size.
+    pub fn select_images(&mut self, mode: &ImagesMode, is_keeping_placeholders: bool) {
+        let content_ref = match &self.node_ref_opt {
+            Some(content_ref) => content_ref,
+            None => return,
+        };
+        if matches!(mode, ImagesMode::All) {
+            return;
+        }
+        resolve_picture_sources(content_ref);
+        let mut imgs = content_ref.select("img").unwrap().collect_vec();
+        if matches!(mode, ImagesMode::Lead) {
+            let base_url = Url::parse(&self.url).ok();
+            let resolve = |src: &str| match &base_url {
+                Some(base_url) => base_url
+                    .join(src)
+                    .map_or_else(|_| src.to_owned(), String::from),
+                None => src.to_owned(),
+            };
+            let lead_image = self.metadata.lead_image().map(|src| resolve(src));
+            let lead_pos = match &lead_image {
+                Some(lead_image) => imgs.iter().position(|img| {
+                    img.attributes
+                        .borrow()
+                        .get("src")
+                        .map(|src| resolve(src))
+                        .as_ref()
+                        == Some(lead_image)
+                }),
+                None => imgs.iter().position(|img| {
+                    let attrs = img.attributes.borrow();
+                    let is_large = |attr| {
+                        attrs
+                            .get(attr)
+                            .and_then(|size| size.trim_end_matches("px").parse::<u32>().ok())
+                            .map_or(true, |size| size >= LEAD_IMAGE_MIN_SIZE)
+                    };
+                    attrs.get("src").is_some() && is_large("width") && is_large("height")
+                }),
+            };
+            match lead_pos {
+                Some(lead_pos) => {
+                    imgs.remove(lead_pos);
+                }
+                None => {
+                    if let (Some(lead_image), Ok(body)) =
+                        (lead_image, content_ref.select_first("body"))
+                    {
+                        let doc = kuchiki::parse_html().one("<img>");
+                        let img = doc.select_first("img").unwrap();
+                        {
+                            let mut attrs = img.attributes.borrow_mut();
+                            attrs.insert("src", lead_image);
+                            attrs.insert("alt", self.metadata.title().to_owned());
+                        }
+                        body.as_node().prepend(img.as_node().clone());
+                    }
+                }
+            }
+        }
+        for img in imgs {
+            let figure = img
+                .as_node()
+                .ancestors()
+                .elements()
+                .find(|elem| &elem.name.local == "figure")
+                .filter(|figure| figure.as_node().select("img").unwrap().count() == 1);
+            let removed_node =
+                figure.map_or_else(|| img.as_node().clone(), |figure| figure.as_node().clone());
+            if is_keeping_placeholders {
+                insert_img_placeholder(&img, &removed_node);
+            }
+            removed_node.detach();
+        }
+    }
+
+    /// Returns the absolute URLs of the PDFs linked from the content
+    pub fn linked_pdf_urls(&self) -> Vec<String> {
+        self.node_ref()
+            .select("a[href]")
+            .unwrap()
+            .filter_map(|link_ref| {
+                let attrs = link_ref.attributes.borrow();
+                attrs.get("href").and_then(|href| Url::parse(href).ok())
+            })
+            .filter(|url| {
+                (url.scheme() == "http" || url.scheme() == "https")
+                    && url.path().to_lowercase().ends_with(".pdf")
+            })
+            .map(String::from)
+            .unique()
+            .collect()
+    }
+
+    /// Returns the URLs of the audio played or linked in the article. These are the sources of
+    /// audio elements and links to audio files.
+    pub fn audio_urls(&self) -> Vec<String> {
+        let base_url = Url::parse(&self.url).ok();
+        let parse_url = |url: &str| {
+            Url::options()
+                .base_url(base_url.as_ref())
+                .parse(url)
+                .ok()
+                .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        };
+        let audio_sources = self
+            .node_ref()
+            .select("audio[src], audio source[src]")
+            .unwrap()
+            .filter_map(|elem| {
+                let attrs = elem.attributes.borrow();
+                attrs.get("src").and_then(parse_url)
+            });
+        let audio_links = self
+            .node_ref()
+            .select("a[href]")
+            .unwrap()
+            .filter_map(|link_ref| {
+                let attrs = link_ref.attributes.borrow();
+                attrs.get("href").and_then(parse_url)
+            })
+            .filter(|url| {
+                let path = url.path().to_lowercase();
+                AUDIO_EXTS
+                    .iter()
+                    .any(|ext| path.ends_with(&format!(".{}", ext)))
+            });
+        audio_sources
+            .chain(audio_links)
+            .map(String::from)
+            .unique()
+            .collect()
+    }
+
     /// Returns the extracted article [NodeRef]. It should only be called *AFTER* calling parse
     pub fn node_ref(&self) -> &NodeRef {
         self.node_ref_opt.as_ref().expect(
@@ -75,8 +460,1088 @@ impl Article {
     }
 
     pub fn metadata(&self) -> &MetaData {
-        &self.readability.metadata
+        &self.metadata
+    }
+
+    /// Returns the title followed by the domain and a short hash of the URL. This tells apart
+    /// articles that share a title, such as the homepages of different sites.
+    pub fn title_with_url(&self) -> String {
+        let url_hash = format!("{:x}", md5::compute(self.url.as_bytes()));
+        format!(
+            "{} ({} {})",
+            self.metadata().title(),
+            self.domain(),
+            &url_hash[..8]
+        )
+    }
+
+    /// Returns the host of the article's URL without a leading www. It is empty when the URL
+    /// has no host.
+    pub fn domain(&self) -> String {
+        Url::parse(&self.url)
+            .ok()
+            .and_then(|url| {
+                url.host_str()
+                    .map(|host| host.trim_start_matches("www.").to_owned())
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the name, without an extension, of the files the article is exported to. The URL
+    /// is included in the name when `is_including_url` is set.
+    pub fn file_stem(&self, is_including_url: bool) -> String {
+        let title = if is_including_url {
+            self.title_with_url()
+        } else {
+            self.metadata().title().to_owned()
+        };
+        title.replace("/", " ").replace("\\", " ")
+    }
+
+    /// Replaces the title picked by readability with the og:title of the page, or its `<title>`
+    /// when it has none, without the name of the site that is often appended to them
+    pub fn use_og_title(&mut self) {
+        let domain = self.domain();
+        let title = self
+            .metadata
+            .og_title()
+            .or_else(|| self.metadata.page_title())
+            .map(|title| {
+                strip_site_suffix(
+                    title,
+                    self.metadata.site_name().map(String::as_str),
+                    &domain,
+                )
+            })
+            .filter(|title| !title.is_empty());
+        if let Some(title) = title {
+            self.metadata.set_title(title);
+        }
+    }
+
+    /// Converts the title to title case if it is entirely in upper case. Titles with any lower
+    /// case letters are left as is since their capitalization is likely intentional.
+    pub fn normalize_title(&mut self) {
+        let title = self.metadata.title();
+        let has_letters = title.chars().any(char::is_alphabetic);
+        if has_letters && !title.chars().any(char::is_lowercase) {
+            let normalized_title = to_title_case(title);
+            self.metadata.set_title(normalized_title);
+        }
+    }
+
+    /// Removes emoji from the title and the text of the article since e-readers without emoji
+    /// fonts display them as boxes. The title is kept as is if it only has emoji.
+    pub fn strip_emoji(&mut self) {
+        let title = EMOJI_REGEX
+            .replace_all(self.metadata.title(), "")
+            .split_whitespace()
+            .join(" ");
+        if !title.is_empty() {
+            self.metadata.set_title(title);
+        }
+        for text_node in self.node_ref().inclusive_descendants().text_nodes() {
+            let mut text = text_node.borrow_mut();
+            if EMOJI_REGEX.is_match(&text) {
+                *text = EMOJI_REGEX.replace_all(&text, "").into_owned();
+            }
+        }
+    }
+
+    /// Adds the header and footer snippets at the start and the end of the article's content.
+    /// The {title}, {url} and {date} tokens of the snippets are replaced with the HTML escaped
+    /// title and URL of the article and the given date. Each snippet is wrapped in a `<div>` with
+    /// the `paperoni-header` or `paperoni-footer` class so that it can be styled.
+    pub fn insert_header_footer(&mut self, header: Option<&str>, footer: Option<&str>, date: &str) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let escape = |value: &str| {
+            value
+                .replace("&", "&amp;")
+                .replace("<", "&lt;")
+                .replace(">", "&gt;")
+                .replace("\"", "&quot;")
+        };
+        let (title, url) = (escape(self.metadata.title()), escape(&self.url));
+        let snippet_elem = |snippet: &str, class: &str| {
+            let snippet = snippet
+                .replace("{title}", &title)
+                .replace("{url}", &url)
+                .replace("{date}", &escape(date));
+            kuchiki::parse_html()
+                .one(format!(r#"<div class="{}">{}</div>"#, class, snippet))
+                .select_first("div")
+                .ok()
+                .map(|elem| elem.as_node().clone())
+        };
+        if let Some(header_elem) = header.and_then(|header| snippet_elem(header, "paperoni-header"))
+        {
+            body.as_node().prepend(header_elem);
+        }
+        if let Some(footer_elem) = footer.and_then(|footer| snippet_elem(footer, "paperoni-footer"))
+        {
+            body.as_node().append(footer_elem);
+        }
+    }
+
+    /// Adds `<wbr>` break points to long words such as URLs so that readers can wrap them
+    /// instead of letting them overflow narrow screens. Words are broken after URL punctuation
+    /// and, failing that, every [MAX_UNBROKEN_LEN] characters. Code is left as is.
+    pub fn break_long_words(&mut self) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let text_nodes = body
+            .as_node()
+            .descendants()
+            .text_nodes()
+            .filter(|text| {
+                !text.as_node().ancestors().elements().any(|elem| {
+                    NO_BREAK_ELEMENTS
+                        .split(", ")
+                        .any(|name| name == &*elem.name.local)
+                })
+            })
+            .collect_vec();
+        for text in text_nodes {
+            let segments = word_break_segments(&text.borrow());
+            if segments.len() < 2 {
+                continue;
+            }
+            let text_node = text.as_node();
+            for (idx, segment) in segments.into_iter().enumerate() {
+                if idx > 0 {
+                    let wbr_name = QualName::new(
+                        None,
+                        Namespace::from("http://www.w3.org/1999/xhtml"),
+                        LocalName::from("wbr"),
+                    );
+                    text_node.insert_before(NodeRef::new_element(wbr_name, Vec::new()));
+                }
+                text_node.insert_before(NodeRef::new_text(segment));
+            }
+            text_node.detach();
+        }
+    }
+
+    /// Collapses the runs of whitespace in `<pre>` blocks, including the code nested in them, into
+    /// single spaces and trims the start and end of the blocks. The whitespace of `<pre>` blocks
+    /// is otherwise kept as is, which lays out minified content as one very long line.
+    pub fn collapse_whitespace_in_pre(&mut self) {
+        let pres = match self.node_ref().select("pre") {
+            Ok(pres) => pres
+                .filter(|pre| {
+                    !pre.as_node()
+                        .ancestors()
+                        .elements()
+                        .any(|elem| &*elem.name.local == "pre")
+                })
+                .collect_vec(),
+            Err(_) => return,
+        };
+        for pre in pres {
+            let text_nodes = pre.as_node().descendants().text_nodes().collect_vec();
+            // Whitespace at the start of the block is dropped as though it followed a space
+            let mut ends_in_space = true;
+            for text in &text_nodes {
+                let collapsed = collapse_whitespace(&text.borrow(), &mut ends_in_space);
+                *text.borrow_mut() = collapsed;
+            }
+            for text in text_nodes.iter().rev() {
+                let trimmed = text.borrow().trim_end().to_owned();
+                let is_empty = trimmed.is_empty();
+                *text.borrow_mut() = trimmed;
+                if !is_empty {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Adds the QR code image stored at `qr_code_src` to the end of the content with a caption
+    /// holding the URL of the article
+    pub fn insert_qr_code(&mut self, qr_code_src: &str) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let qr_code_elem = match kuchiki::parse_html()
+            .one(r#"<figure class="paperoni-qr"><img width="120" height="120"><figcaption>Read on the web: </figcaption></figure>"#)
+            .select_first("figure")
+        {
+            Ok(qr_code_elem) => qr_code_elem.as_node().clone(),
+            Err(_) => return,
+        };
+        if let Ok(img) = qr_code_elem.select_first("img") {
+            let mut attrs = img.attributes.borrow_mut();
+            attrs.insert("src", qr_code_src.to_owned());
+            attrs.insert("alt", format!("QR code linking to {}", self.url));
+        }
+        if let Ok(caption) = qr_code_elem.select_first("figcaption") {
+            caption
+                .as_node()
+                .append(NodeRef::new_text(self.url.clone()));
+        }
+        body.as_node().append(qr_code_elem);
+    }
+
+    /// Adds a line linking to the article's source at the end of its content. It is placed inside
+    /// the readability page so that it stays with the article when articles are merged.
+    pub fn insert_source_link(&mut self) {
+        let container = match self
+            .node_ref()
+            .select_first("div#readability-page-1")
+            .or_else(|_| self.node_ref().select_first("body"))
+        {
+            Ok(container) => container,
+            Err(_) => return,
+        };
+        let source_elem = match kuchiki::parse_html()
+            .one(r#"<p class="paperoni-source-link">Source: <a></a></p>"#)
+            .select_first("p")
+        {
+            Ok(source_elem) => source_elem.as_node().clone(),
+            Err(_) => return,
+        };
+        if let Ok(link) = source_elem.select_first("a") {
+            link.attributes
+                .borrow_mut()
+                .insert("href", self.url.clone());
+            let link_text = if self.metadata.title().is_empty() {
+                self.url.clone()
+            } else {
+                self.metadata.title().to_owned()
+            };
+            link.as_node().append(NodeRef::new_text(link_text));
+        }
+        container.as_node().append(source_elem);
+    }
+
+    /// Adds the styles of `--user-css-inline` in a `<style>` element at the end of the `<head>` so
+    /// that they override the default stylesheet. The CSS is added as text so that it is escaped
+    /// in XHTML, and closing tags in it are escaped for HTML where the `<style>` is written as is.
+    pub fn insert_user_css(&mut self, css: &str) {
+        let head = match self.node_ref().select_first("head") {
+            Ok(head) => head,
+            Err(_) => return,
+        };
+        let style_name = QualName::new(
+            None,
+            Namespace::from("http://www.w3.org/1999/xhtml"),
+            LocalName::from("style"),
+        );
+        let style = NodeRef::new_element(style_name, Vec::new());
+        style.append(NodeRef::new_text(css.replace("</", "<\\/")));
+        head.as_node().append(style);
+    }
+
+    /// Removes the HTML comments, such as conditional comments, ad placeholders and CMS markers,
+    /// from the content
+    pub fn strip_comments(&mut self) {
+        let comments = self
+            .node_ref()
+            .descendants()
+            .filter(|node| node.as_comment().is_some())
+            .collect_vec();
+        for comment in comments {
+            comment.detach();
+        }
+    }
+
+    /// Replaces the links in the content with their text. Links that are also the target of
+    /// other links, such as footnote anchors, only lose their href so that they can still be
+    /// linked to.
+    pub fn strip_links(&mut self) {
+        let links = self.node_ref().select("a[href]").unwrap().collect_vec();
+        for link in links {
+            let has_id = {
+                let mut attrs = link.attributes.borrow_mut();
+                attrs.remove("href");
+                attrs.contains("id") || attrs.contains("name")
+            };
+            if has_id {
+                continue;
+            }
+            let link_node = link.as_node();
+            for child in link_node.children().collect_vec() {
+                link_node.insert_before(child);
+            }
+            link_node.detach();
+        }
+    }
+
+    /// Adds a line at the start of the content showing the favicon stored at `favicon_src`
+    /// followed by the name of the site, or its host when the page does not name the site
+    pub fn insert_favicon(&mut self, favicon_src: &str) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let site_name = self.metadata.site_name().cloned().or_else(|| {
+            Url::parse(&self.url)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_owned))
+        });
+        let source_elem = match kuchiki::parse_html()
+            .one(r#"<p class="paperoni-source"><img class="paperoni-favicon" alt="" width="16" height="16"></p>"#)
+            .select_first("p")
+        {
+            Ok(source_elem) => source_elem.as_node().clone(),
+            Err(_) => return,
+        };
+        if let Ok(img) = source_elem.select_first("img") {
+            img.attributes
+                .borrow_mut()
+                .insert("src", favicon_src.to_owned());
+        }
+        if let Some(site_name) = site_name {
+            source_elem.append(NodeRef::new_text(format!(" {}", site_name)));
+        }
+        body.as_node().prepend(source_elem);
+    }
+
+    /// Removes newsletter sign ups, cookie notices and similar blocks left in the content.
+    /// Only short blocks containing one of the phrases, sign up forms and elements matching the
+    /// selector are removed so that paragraphs merely mentioning the phrases are kept.
+    pub fn remove_interstitials(&mut self, extra_phrases: &[String], selector: Option<&str>) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let count_chars = |text: &str| text.chars().filter(|c| !c.is_whitespace()).count();
+        let text_len = count_chars(&body.text_contents());
+        let extra_phrases = extra_phrases
+            .iter()
+            .map(|phrase| phrase.to_lowercase())
+            .collect_vec();
+
+        let mut interstitials = selector
+            .and_then(|selector| body.as_node().select(selector).ok())
+            .map(|elems| elems.collect_vec())
+            .unwrap_or_default();
+        for block in body.as_node().select(INTERSTITIAL_CANDIDATES).unwrap() {
+            let block_text = block.text_contents();
+            let block_len = count_chars(&block_text);
+            // Blocks holding most of the text are likely the article itself
+            if block_len > INTERSTITIAL_MAX_TEXT_LEN || block_len * 2 > text_len {
+                continue;
+            }
+            let is_signup_form = &block.name.local == "form"
+                && block.as_node().select_first("input[type=email]").is_ok();
+            let block_text = block_text.to_lowercase();
+            if is_signup_form
+                || INTERSTITIAL_REGEX.is_match(&block_text)
+                || extra_phrases
+                    .iter()
+                    .any(|phrase| block_text.contains(phrase.as_str()))
+            {
+                interstitials.push(block);
+            }
+        }
+        for interstitial in interstitials {
+            interstitial.as_node().detach();
+        }
+    }
+
+    /// Removes the navigation, asides, footers and elements whose class or id suggests that
+    /// they are boilerplate, such as sidebars and share buttons, from the extracted article.
+    /// Elements holding most of the article's text are kept.
+    pub fn remove_boilerplate(&mut self) {
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return,
+        };
+        let count_chars = |text: &str| text.chars().filter(|c| !c.is_whitespace()).count();
+        let text_len = count_chars(&body.text_contents());
+
+        let mut boilerplate = body
+            .as_node()
+            .select(BOILERPLATE_ELEMENTS)
+            .unwrap()
+            .collect_vec();
+        for element in body.as_node().descendants().elements() {
+            let class_and_id = {
+                let attrs = element.attributes.borrow();
+                format!(
+                    "{} {}",
+                    attrs.get("class").unwrap_or(""),
+                    attrs.get("id").unwrap_or("")
+                )
+            };
+            if BOILERPLATE_CLASS_REGEX.is_match(&class_and_id) {
+                boilerplate.push(element);
+            }
+        }
+        for element in boilerplate {
+            if count_chars(&element.text_contents()) * 2 <= text_len {
+                element.as_node().detach();
+            }
+        }
+    }
+
+    /// Returns the simhash of the article's text, which is close to that of articles with nearly
+    /// the same text
+    pub fn content_fingerprint(&self) -> Option<u64> {
+        let body = self.node_ref().select_first("body").ok()?;
+        simhash(&body.text_contents())
+    }
+
+    /// Returns the number of paragraphs holding text in the content
+    pub fn paragraph_count(&self) -> usize {
+        self.node_ref()
+            .select("body p")
+            .unwrap()
+            .filter(|p| !p.text_contents().trim().is_empty())
+            .count()
+    }
+
+    /// Collects the metadata written to the sidecar file of the article
+    pub fn sidecar(&self) -> ArticleSidecar {
+        let (word_count, image_count) = match self.node_ref().select_first("body") {
+            Ok(body) => (
+                body.text_contents().split_whitespace().count(),
+                body.as_node().select("img").unwrap().count(),
+            ),
+            Err(_) => (0, 0),
+        };
+        ArticleSidecar {
+            title: self.metadata.title(),
+            author: self.metadata.byline().map(String::as_str),
+            site_name: self.metadata.site_name().map(String::as_str),
+            excerpt: self.metadata.excerpt().map(String::as_str),
+            source_url: &self.url,
+            word_count,
+            image_count,
+            requested_url: self.redirected_from(),
+            http_status: self.response.as_ref().and_then(|response| response.status),
+        }
+    }
+
+    /// Returns the requested URL when it was redirected to a different URL
+    pub fn redirected_from(&self) -> Option<&str> {
+        self.response
+            .as_ref()
+            .map(|response| response.requested_url.as_str())
+            .filter(|&requested_url| requested_url != self.url)
+    }
+
+    /// Runs soft heuristics on the extracted content to flag articles that were probably
+    /// extracted poorly. These do not fail the article.
+    pub fn quality_warnings(&self) -> Vec<QualityWarning> {
+        let mut warnings = Vec::new();
+        let body = match self.node_ref().select_first("body") {
+            Ok(body) => body,
+            Err(_) => return warnings,
+        };
+        let text = body.text_contents();
+        let count_chars = |text: &str| text.chars().filter(|c| !c.is_whitespace()).count();
+        let text_len = count_chars(&text);
+
+        let link_text_len: usize = body
+            .as_node()
+            .select("a")
+            .unwrap()
+            .map(|link| count_chars(&link.text_contents()))
+            .sum();
+        if text_len == 0 || link_text_len as f32 / text_len as f32 > MAX_LINK_DENSITY {
+            warnings.push(QualityWarning::HighLinkDensity);
+        }
+
+        if text_len < BOILERPLATE_MAX_TEXT_LEN && BOILERPLATE_REGEX.is_match(&text) {
+            warnings.push(QualityWarning::SubscriptionBoilerplate);
+        }
+
+        let paragraph_count = self.paragraph_count();
+        if paragraph_count < MIN_PARAGRAPH_COUNT {
+            warnings.push(QualityWarning::FewParagraphs(paragraph_count));
+        }
+
+        warnings
+    }
+}
+
+/// Converts a string to title case where every word other than minor words such as articles
+/// and conjunctions is capitalized
+fn to_title_case(value: &str) -> String {
+    let capitalize = |word: &str| {
+        let mut chars = word.chars();
+        match chars.next() {
+            Some(first) => first.to_uppercase().chain(chars).collect::<String>(),
+            None => String::new(),
+        }
+    };
+    let words = value.split_whitespace().collect::<Vec<_>>();
+    let last_idx = words.len().saturating_sub(1);
+    words
+        .iter()
+        .enumerate()
+        .map(|(idx, word)| {
+            let word = word.to_lowercase();
+            if idx != 0 && idx != last_idx && TITLE_CASE_MINOR_WORDS.contains(&word.as_str()) {
+                word
+            } else {
+                word.split('-').map(capitalize).join("-")
+            }
+        })
+        .join(" ")
+}
+
+/// Indicators of an article that probably extracted poorly
+#[derive(Debug, PartialEq)]
+pub enum QualityWarning {
+    HighLinkDensity,
+    SubscriptionBoilerplate,
+    FewParagraphs(usize),
+}
+
+impl Display for QualityWarning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QualityWarning::HighLinkDensity => write!(f, "Mostly made up of links"),
+            QualityWarning::SubscriptionBoilerplate => {
+                write!(f, "Contains subscription or sign in text")
+            }
+            QualityWarning::FewParagraphs(count) => write!(
+                f,
+                "Only {} paragraph{}",
+                count,
+                if *count == 1 { "" } else { "s" }
+            ),
+        }
+    }
+}
+
+/// An article flagged by [Article::quality_warnings]
+pub struct LowQualityExtraction {
+    pub link: String,
+    /// The link that was requested when it redirected to `link`
+    pub redirected_from: Option<String>,
+    pub status: Option<u16>,
+    pub title: String,
+    pub warnings: Vec<QualityWarning>,
+}
+
+impl LowQualityExtraction {
+    /// Returns `None` if none of the quality heuristics flagged the article
+    pub fn from_article(article: &Article) -> Option<Self> {
+        let warnings = article.quality_warnings();
+        if warnings.is_empty() {
+            None
+        } else {
+            Some(Self {
+                link: article.url.clone(),
+                redirected_from: article.redirected_from().map(ToOwned::to_owned),
+                status: article
+                    .response
+                    .as_ref()
+                    .and_then(|response| response.status),
+                title: article.metadata().title().to_owned(),
+                warnings,
+            })
+        }
+    }
+}
+
+/// An article skipped by [DuplicateFilter] since its content is nearly the same as an article
+/// downloaded before it
+pub struct DuplicateArticle {
+    pub link: String,
+    pub title: String,
+    /// The link of the article it duplicates
+    pub duplicate_of: String,
+}
+
+/// Finds the articles whose content is nearly the same as that of an article seen before them.
+/// Only the fingerprints of the articles are kept so that it can be used while articles are
+/// exported as they download.
+pub struct DuplicateFilter {
+    max_distance: u32,
+    fingerprints: Vec<(u64, String)>,
+}
+
+impl DuplicateFilter {
+    pub fn new(max_distance: u32) -> Self {
+        Self {
+            max_distance,
+            fingerprints: Vec::new(),
+        }
+    }
+
+    /// Returns the duplicate if the article's fingerprint is within the maximum distance of an
+    /// article checked before. Otherwise the article is remembered and `None` is returned.
+    pub fn check(&mut self, article: &Article) -> Option<DuplicateArticle> {
+        let fingerprint = article.content_fingerprint()?;
+        let max_distance = self.max_distance;
+        let original = self
+            .fingerprints
+            .iter()
+            .find(|(seen, _)| (seen ^ fingerprint).count_ones() <= max_distance);
+        match original {
+            Some((_, original_url)) => Some(DuplicateArticle {
+                link: article.url.clone(),
+                title: article.metadata().title().to_owned(),
+                duplicate_of: original_url.clone(),
+            }),
+            None => {
+                self.fingerprints.push((fingerprint, article.url.clone()));
+                None
+            }
+        }
+    }
+}
+
+/// Returns the simhash of the text. Each run of three words is hashed and every bit of the
+/// fingerprint is set to the bit most of the hashes agree on, so texts that share most of their
+/// words get fingerprints that differ in few bits. Returns `None` for text without words.
+fn simhash(text: &str) -> Option<u64> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(str::to_lowercase)
+        .collect_vec();
+    if words.is_empty() {
+        return None;
+    }
+    let shingles = if words.len() < 3 {
+        vec![words.join(" ")]
+    } else {
+        words.windows(3).map(|shingle| shingle.join(" ")).collect()
+    };
+    let mut bit_weights = [0i64; 64];
+    for shingle in shingles {
+        let digest = md5::compute(shingle.as_bytes());
+        let hash = digest.0[..8]
+            .iter()
+            .rev()
+            .fold(0u64, |hash, byte| (hash << 8) | *byte as u64);
+        for (bit, weight) in bit_weights.iter_mut().enumerate() {
+            if (hash >> bit) & 1 == 1 {
+                *weight += 1;
+            } else {
+                *weight -= 1;
+            }
+        }
+    }
+    Some(
+        bit_weights
+            .iter()
+            .enumerate()
+            .filter(|(_, weight)| **weight > 0)
+            .fold(0, |fingerprint, (bit, _)| fingerprint | (1 << bit)),
+    )
+}
+
+/// An article whose content has been extracted and serialized. Unlike [Article], it does not
+/// hold a DOM tree and can therefore be sent between threads.
+pub struct ExtractedArticle {
+    url: String,
+    content: String,
+    metadata: MetaData,
+}
+
+impl ExtractedArticle {
+    /// Runs readability on an HTML document. This is CPU bound so it should be called
+    /// from a blocking task rather than the async executor.
+    pub fn extract(
+        html_str: &str,
+        url: &str,
+        options: ReadabilityOptions,
+    ) -> Result<Self, PaperoniError> {
+        let mut article = Article::from_html(html_str, url);
+        article.set_readability_options(options);
+        article.extract_content()?;
+        let mut content = Vec::new();
+        article.node_ref().serialize(&mut content)?;
+        Ok(Self {
+            url: article.url,
+            content: String::from_utf8(content).map_err(|err| err.utf8_error())?,
+            metadata: article.metadata,
+        })
+    }
+}
+
+impl From<ExtractedArticle> for Article {
+    fn from(extracted: ExtractedArticle) -> Self {
+        Self {
+            node_ref_opt: Some(kuchiki::parse_html().one(extracted.content)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: extracted.metadata,
+            url: extracted.url,
+            url_index: 0,
+            response: None,
+        }
+    }
+}
+
+/// Removes the name of the site from the end of a title such as "Headline | The Example Times".
+/// The last part of the title is taken to be the site's name when it matches the og:site_name
+/// or the domain of the article. Failing that, a part after a `|` is removed since the pipe is
+/// seldom used in headlines.
+fn strip_site_suffix(title: &str, site_name: Option<&str>, domain: &str) -> String {
+    let compact = |value: &str| {
+        value
+            .chars()
+            .filter(|c| c.is_alphanumeric())
+            .flat_map(char::to_lowercase)
+            .collect::<String>()
+    };
+    let site_name = site_name.map(compact).filter(|name| !name.is_empty());
+    let domain_labels = domain
+        .rsplitn(2, '.')
+        .last()
+        .map(|labels| labels.split('.').map(compact).collect_vec())
+        .unwrap_or_default();
+    let is_site_name = |part: &str| {
+        let part = compact(part);
+        !part.is_empty()
+            && (site_name.as_deref() == Some(part.as_str())
+                || domain_labels
+                    .iter()
+                    .any(|label| label.len() >= 4 && part.contains(label.as_str())))
+    };
+    fn split_last_part(title: &str) -> Option<(&str, &str)> {
+        TITLE_SITE_SEPARATORS
+            .iter()
+            .filter_map(|separator| {
+                title
+                    .rfind(separator)
+                    .map(|idx| (idx, &title[..idx], &title[idx + separator.len()..]))
+            })
+            .max_by_key(|(idx, _, _)| *idx)
+            .map(|(_, head, tail)| (head.trim(), tail.trim()))
+    }
+
+    let mut title = title.trim();
+    let mut is_stripped = false;
+    while let Some((head, tail)) = split_last_part(title) {
+        if head.is_empty() || !is_site_name(tail) {
+            break;
+        }
+        title = head;
+        is_stripped = true;
+    }
+    if !is_stripped {
+        if let Some(idx) = title.rfind(" | ") {
+            let head = title[..idx].trim();
+            if !head.is_empty() {
+                title = head;
+            }
+        }
+    }
+    title.to_owned()
+}
+
+/// Replaces each run of whitespace in the text with a space. `ends_in_space` carries whether the
+/// text before it ended in a space so that runs spanning several text nodes are collapsed too.
+fn collapse_whitespace(text: &str, ends_in_space: &mut bool) -> String {
+    let mut collapsed = String::with_capacity(text.len());
+    for c in text.chars() {
+        if !c.is_whitespace() {
+            collapsed.push(c);
+            *ends_in_space = false;
+        } else if !*ends_in_space {
+            collapsed.push(' ');
+            *ends_in_space = true;
+        }
+    }
+    collapsed
+}
+
+/// Splits text at the break points of its long words. A `<wbr>` goes between each segment.
+fn word_break_segments(text: &str) -> Vec<String> {
+    let mut segments = vec![String::new()];
+    for token in text.split_inclusive(char::is_whitespace) {
+        if token.trim_end().chars().count() <= LONG_WORD_LEN {
+            segments.last_mut().unwrap().push_str(token);
+            continue;
+        }
+        let chars = token.chars().collect_vec();
+        let mut segment_start = 0;
+        for (idx, &c) in chars.iter().enumerate() {
+            segments.last_mut().unwrap().push(c);
+            let next = match chars.get(idx + 1) {
+                Some(next) if !next.is_whitespace() => *next,
+                _ => continue,
+            };
+            // Runs of punctuation such as the :// of URLs are kept together
+            let is_after_punctuation =
+                WORD_BREAK_CHARS.contains(&c) && !WORD_BREAK_CHARS.contains(&next);
+            if is_after_punctuation || idx + 1 - segment_start >= MAX_UNBROKEN_LEN {
+                segments.push(String::new());
+                segment_start = idx + 1;
+            }
+        }
+    }
+    segments
+}
+
+/// Returns the absolute URL without its cache busting query parameters or None when the URL
+/// has none
+fn strip_cache_busters(base_url: &Url, src: &str) -> Option<String> {
+    let mut url = base_url.join(src).ok()?;
+    let query_pairs = url
+        .query_pairs()
+        .map(|(key, value)| (key.into_owned(), value.into_owned()))
+        .collect_vec();
+    let kept_pairs = query_pairs
+        .iter()
+        .filter(|(key, _)| !CACHE_BUSTING_PARAMS.contains(&key.to_lowercase().as_str()))
+        .collect_vec();
+    if kept_pairs.len() == query_pairs.len() {
+        return None;
+    }
+    if kept_pairs.is_empty() {
+        url.set_query(None);
+    } else {
+        url.query_pairs_mut().clear().extend_pairs(kept_pairs);
+    }
+    Some(url.to_string())
+}
+
+/// Replaces `<picture>` elements with their `<img>` pointing at the best supported source.
+/// The first `<source>` in a supported format is used, falling back to the `<img>`'s own srcset,
+/// and the highest resolution candidate in its srcset is picked.
+fn resolve_picture_sources(content_ref: &NodeRef) {
+    let picture_refs = content_ref.select("picture").unwrap().collect::<Vec<_>>();
+    for picture_ref in picture_refs {
+        let picture_node = picture_ref.as_node();
+        let img_ref = match picture_node.select_first("img") {
+            Ok(img_ref) => img_ref,
+            Err(_) => continue,
+        };
+        let best_src = picture_node
+            .select("source[srcset]")
+            .unwrap()
+            .filter(|source_ref| {
+                source_ref
+                    .attributes
+                    .borrow()
+                    .get("type")
+                    .map_or(true, |mime| SUPPORTED_IMAGE_TYPES.contains(&mime.trim()))
+            })
+            .chain(std::iter::once(img_ref.clone()))
+            .find_map(|source_ref| {
+                source_ref
+                    .attributes
+                    .borrow()
+                    .get("srcset")
+                    .and_then(best_srcset_candidate)
+            });
+        {
+            let mut img_attrs = img_ref.attributes.borrow_mut();
+            if let Some(src) = best_src {
+                img_attrs.insert("src", src);
+            }
+            img_attrs.remove("srcset");
+        }
+        picture_node.insert_before(img_ref.as_node().clone());
+        picture_node.detach();
+    }
+}
+
+/// Inserts a box sized like the given image before `node` so that removing the image does not
+/// reflow the content around it. The size is read from the width and height attributes of the
+/// image, with the width falling back to the widest candidate of its srcset. Nothing is inserted
+/// when the size is not declared.
+pub fn insert_img_placeholder(img_ref: &NodeDataRef<ElementData>, node: &NodeRef) {
+    let (width, height) = match declared_img_size(&img_ref.attributes.borrow()) {
+        Some(size) => size,
+        None => return,
+    };
+    // The padding of the inner box keeps the aspect ratio when max-width narrows the placeholder
+    let placeholder_html = format!(
+        r#"<span class="paperoni-img-placeholder" style="display:block;width:{}px;max-width:100%;background:#eee"><span style="display:block;padding-top:{:.2}%"></span></span>"#,
+        width,
+        height as f32 / width as f32 * 100.0
+    );
+    let doc = kuchiki::parse_html().one(placeholder_html);
+    if let Ok(placeholder) = doc.select_first("span.paperoni-img-placeholder") {
+        node.insert_before(placeholder.as_node().clone());
+    }
+}
+
+/// Returns the width and height declared by the attributes of an `<img>` element
+fn declared_img_size(attrs: &kuchiki::Attributes) -> Option<(u32, u32)> {
+    let parse_size = |size: &str| {
+        size.trim()
+            .trim_end_matches("px")
+            .parse::<f32>()
+            .ok()
+            .filter(|size| *size >= 1.0)
+            .map(|size| size.round() as u32)
+    };
+    let height = attrs.get("height").and_then(parse_size)?;
+    let width = attrs.get("width").and_then(parse_size).or_else(|| {
+        attrs
+            .get("srcset")
+            .filter(|srcset| !srcset.contains("data:"))?
+            .split(',')
+            .filter_map(|candidate| {
+                candidate
+                    .split_whitespace()
+                    .nth(1)
+                    .filter(|descriptor| descriptor.ends_with('w'))
+                    .and_then(|descriptor| parse_size(descriptor.trim_end_matches('w')))
+            })
+            .max()
+    })?;
+    Some((width, height))
+}
+
+/// Picks the URL of the highest resolution candidate in a srcset that is in a supported format
+fn best_srcset_candidate(srcset: &str) -> Option<String> {
+    // Data URLs contain commas which cannot be told apart from the candidate separators
+    if srcset.contains("data:") {
+        return None;
+    }
+    srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = parts.next()?;
+            let descriptor = parts.next().unwrap_or("1x");
+            let size = descriptor
+                .get(..descriptor.len() - 1)
+                .filter(|_| descriptor.ends_with('w') || descriptor.ends_with('x'))?
+                .parse::<f32>()
+                .ok()?;
+            let ext = url
+                .split(|c| c == '?' || c == '#')
+                .next()
+                .and_then(|path| path.rsplit('.').next())
+                .unwrap_or_default()
+                .to_lowercase();
+            if UNSUPPORTED_IMAGE_EXTS.contains(&ext.as_str()) {
+                None
+            } else {
+                Some((url, size))
+            }
+        })
+        .max_by(|(_, size_a), (_, size_b)| {
+            size_a
+                .partial_cmp(size_b)
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })
+        .map(|(url, _)| url.to_owned())
+}
+
+/// Puts the articles in the given order. Shuffling always gives the same order for a seed so
+/// that a shuffled collection can be generated again.
+pub fn arrange_articles<T>(articles: &mut [T], order: &ArticleOrder) {
+    match order {
+        ArticleOrder::Original => (),
+        ArticleOrder::Reverse => articles.reverse(),
+        ArticleOrder::Shuffle(seed) => {
+            // SplitMix64 is used as the random number generator as no statistical quality
+            // beyond a varied order is needed
+            let mut state = *seed;
+            let mut next_random = || {
+                state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+                let mut z = state;
+                z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+                z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+                z ^ (z >> 31)
+            };
+            // Fisher-Yates shuffle
+            for idx in (1..articles.len()).rev() {
+                let swap_idx = (next_random() % (idx as u64 + 1)) as usize;
+                articles.swap(idx, swap_idx);
+            }
+        }
+    }
+}
+
+/// Serializes a NodeRef to a string that is XHTML compatible
+pub fn serialize_to_xhtml_string(node_ref: &NodeRef) -> Result<String, PaperoniError> {
+    let mut xhtml_buf = Vec::new();
+    serialize_to_xhtml(node_ref, &mut xhtml_buf)?;
+    String::from_utf8(xhtml_buf).map_err(|err| err.utf8_error().into())
+}
+
+/// Serializes the children of the `<body>` of a NodeRef to a string that is XHTML compatible.
+/// This is the markup placed in chapter templates.
+pub fn serialize_body_to_xhtml_string(node_ref: &NodeRef) -> Result<String, PaperoniError> {
+    let mut xhtml_buf = Vec::new();
+    if let Ok(body) = node_ref.select_first("body") {
+        for child in body.as_node().children() {
+            serialize_to_xhtml(&child, &mut xhtml_buf)?;
+        }
+    }
+    String::from_utf8(xhtml_buf).map_err(|err| err.utf8_error().into())
+}
+
+/// Serializes a NodeRef to XHTML compatible markup
+/// The only DOM nodes serialized are Text and Element nodes
+fn serialize_to_xhtml<W: std::io::Write>(
+    node_ref: &NodeRef,
+    mut w: &mut W,
+) -> Result<(), PaperoniError> {
+    // Add XHTML attributes. Fragments such as the children of <body> have no <html> element
+    if let Ok(html_elem) = node_ref.select_first("html") {
+        let mut html_attrs = html_elem.attributes.borrow_mut();
+        html_attrs.insert("xmlns", "http://www.w3.org/1999/xhtml".into());
+        html_attrs.insert("xmlns:epub", "http://www.idpf.org/2007/ops".into());
+    }
+    let mut escape_map = HashMap::new();
+    escape_map.insert("<", "&lt;");
+    escape_map.insert(">", "&gt;");
+    escape_map.insert("&", "&amp;");
+    escape_map.insert("\"", "&quot;");
+    escape_map.insert("'", "&apos;");
+    for edge in node_ref.traverse_inclusive() {
+        match edge {
+            kuchiki::iter::NodeEdge::Start(n) => match n.data() {
+                kuchiki::NodeData::Text(rc_text) => {
+                    let text = rc_text.borrow();
+                    let esc_text = ESC_SEQ_REGEX
+                        .replace_all(&text, |captures: &regex::Captures| escape_map[&captures[1]]);
+                    write!(&mut w, "{}", esc_text)?;
+                }
+                kuchiki::NodeData::Element(elem_data) => {
+                    let attrs = elem_data.attributes.borrow();
+                    let mut attrs_str = attrs
+                        .map
+                        .iter()
+                        .filter(|(k, _)| {
+                            let attr_key: &str = &k.local;
+                            attr_key.is_ascii() && VALID_ATTR_CHARS_REGEX.is_match(attr_key)
+                        })
+                        .map(|(k, v)| {
+                            format!(
+                                "{}=\"{}\"",
+                                k.local,
+                                ESC_SEQ_REGEX
+                                    .replace_all(&v.value, |captures: &regex::Captures| {
+                                        escape_map[&captures[1]]
+                                    })
+                            )
+                        })
+                        .fold("".to_string(), |acc, val| acc + " " + &val);
+                    // MathML is only rendered by readers when its namespace is declared
+                    if &elem_data.name.local == "math"
+                        && !attrs.map.keys().any(|k| &k.local == "xmlns")
+                    {
+                        attrs_str.push_str(&format!(" xmlns=\"{}\"", MATHML_NS));
+                    }
+                    write!(&mut w, "<{}{}>", &elem_data.name.local, attrs_str)?;
+                }
+                _ => (),
+            },
+            kuchiki::iter::NodeEdge::End(n) => match n.data() {
+                kuchiki::NodeData::Element(elem_data) => {
+                    write!(&mut w, "</{}>", &elem_data.name.local)?;
+                }
+                _ => (),
+            },
+        }
     }
+    Ok(())
 }
 
 #[cfg(test)]
@@ -121,8 +1586,1095 @@ mod test {
 
         assert!(article.img_urls.len() > 0);
         assert_eq!(
-            vec![("http://example.com/img.jpg".to_string(), None)],
+            vec![
+                ("http://example.com/img.jpg".to_string(), None),
+                (
+                    "data:image/png;base64,lJGWEIUQOIQWIDYVIVEDYFOUYQFWD".to_string(),
+                    None
+                )
+            ],
+            article.img_urls
+        );
+    }
+
+    #[test]
+    fn test_resolve_picture_sources() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <picture>
+                    <source type="image/avif" srcset="photo.avif 1x, photo@2x.avif 2x">
+                    <source srcset="photo.webp">
+                    <source type="image/jpeg" srcset="photo-400.jpg 400w, photo-1200.jpg 1200w, photo-800.jpg 800w">
+                    <img src="placeholder.gif" alt="Photo">
+                </picture>
+                <picture>
+                    <source type="image/webp" srcset="logo.webp">
+                    <img src="logo.png" srcset="logo.png 1x, logo@2x.png 2x" alt="Logo">
+                </picture>
+                <picture>
+                    <source type="image/webp" srcset="chart.webp">
+                    <img src="chart.png">
+                </picture>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+        resolve_picture_sources(&doc);
+
+        assert_eq!(0, doc.select("picture, source").unwrap().count());
+        let img_attrs = doc
+            .select("img")
+            .unwrap()
+            .map(|img_ref| {
+                let attrs = img_ref.attributes.borrow();
+                (
+                    attrs.get("src").map(ToString::to_string),
+                    attrs.get("srcset").map(ToString::to_string),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (Some("photo-1200.jpg".to_string()), None),
+                (Some("logo@2x.png".to_string()), None),
+                (Some("chart.png".to_string()), None),
+            ],
+            img_attrs
+        );
+    }
+
+    #[test]
+    fn test_best_srcset_candidate() {
+        assert_eq!(
+            Some("a-2x.png".to_string()),
+            best_srcset_candidate("a.png, a-2x.png 2x")
+        );
+        assert_eq!(
+            Some("b.jpg?w=900".to_string()),
+            best_srcset_candidate("b.jpg?w=300 300w,b.jpg?w=900 900w")
+        );
+        assert_eq!(None, best_srcset_candidate("c.webp 1x, c@2x.webp 2x"));
+        assert_eq!(None, best_srcset_candidate("data:image/png;base64,AAAA 1x"));
+    }
+
+    #[test]
+    fn test_extracted_article_to_article() {
+        let extracted = ExtractedArticle::extract(
+            TEST_HTML,
+            "http://example.com/",
+            ReadabilityOptions::default(),
+        )
+        .expect("Article extraction failed unexpectedly");
+        let mut article = Article::from(extracted);
+        article.extract_img_urls();
+
+        assert_eq!("Testing Paperoni", article.metadata().title());
+        assert!(article.metadata().content_score().unwrap() > 0.0);
+        assert_eq!(
+            1,
+            article
+                .node_ref()
+                .select("div#readability-page-1")
+                .unwrap()
+                .count()
+        );
+        assert_eq!(
+            vec![
+                ("http://example.com/img.jpg".to_string(), None),
+                (
+                    "data:image/png;base64,lJGWEIUQOIQWIDYVIVEDYFOUYQFWD".to_string(),
+                    None
+                )
+            ],
             article.img_urls
         );
     }
+
+    #[test]
+    fn test_quality_warnings() {
+        let mut article = Article::from_html(TEST_HTML, "http://example.com/");
+        article
+            .extract_content()
+            .expect("Article extraction failed unexpectedly");
+        assert_eq!(
+            vec![QualityWarning::FewParagraphs(2)],
+            article.quality_warnings()
+        );
+
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Subscribe to keep reading</p>
+                <p><a href="/sign-in">Sign in to your account</a> <a href="/plans">See our plans</a></p>
+            </body>
+        </html>
+        "#;
+        let article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "http://example.com/".into(),
+            response: None,
+        };
+        assert_eq!(
+            vec![
+                QualityWarning::HighLinkDensity,
+                QualityWarning::SubscriptionBoilerplate,
+                QualityWarning::FewParagraphs(2)
+            ],
+            article.quality_warnings()
+        );
+    }
+
+    #[test]
+    fn test_remove_boilerplate() {
+        let html_str = r##"
+        <!doctype html>
+        <html>
+            <body>
+                <nav><a href="/">Home</a><a href="/about">About</a></nav>
+                <div id="readability-page-1" class="page">
+                    <p>Sourdough relies on wild yeast and lactic acid bacteria to rise. The starter
+                    is fed with flour and water every day until it doubles in size reliably.</p>
+                    <div class="share-buttons"><a href="#">Share</a></div>
+                    <p>Once the starter is active, mix it with flour, water and salt, then let the
+                    dough rest before shaping and baking it in a very hot oven.</p>
+                    <aside>Read more about baking</aside>
+                    <div role="complementary">Popular posts</div>
+                </div>
+                <footer>Copyright Example</footer>
+            </body>
+        </html>
+        "##;
+        let mut article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "http://example.com/".into(),
+            response: None,
+        };
+        article.remove_boilerplate();
+
+        let body = article.node_ref().select_first("body").unwrap();
+        let body = body.as_node();
+        assert_eq!(2, body.select("p").unwrap().count());
+        assert!(body.select_first("#readability-page-1").is_ok());
+        assert!(body.select_first("nav").is_err());
+        assert!(body.select_first(".share-buttons").is_err());
+        assert!(body.select_first("aside").is_err());
+        assert!(body.select_first("[role=complementary]").is_err());
+        assert!(body.select_first("footer").is_err());
+    }
+
+    #[test]
+    fn test_remove_interstitials() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Browsers store cookies to remember the sites you have visited. This article
+                explains how they are used and what you can do to limit tracking across sites.</p>
+                <div class="newsletter">
+                    <h3>Stay in the loop</h3>
+                    <p>Subscribe to our weekly newsletter for more stories like this.</p>
+                </div>
+                <form action="/signup"><input type="email" name="email"><button>Go</button></form>
+                <p>Most browsers let you clear cookies from their privacy settings. Some browsers
+                also block third party cookies by default, which stops most cross site tracking.</p>
+                <p>Support independent journalism today.</p>
+                <ul class="related"><li>More on privacy</li></ul>
+                <div id="cookie-notice">We use cookies to improve your experience.</div>
+            </body>
+        </html>
+        "#;
+        let mut article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "http://example.com/".into(),
+            response: None,
+        };
+        article.remove_interstitials(&["Independent Journalism".into()], Some(".related"));
+
+        let body = article.node_ref().select_first("body").unwrap();
+        let body = body.as_node();
+        assert_eq!(2, body.select("p").unwrap().count());
+        assert!(body
+            .select_first("p")
+            .unwrap()
+            .text_contents()
+            .starts_with("Browsers"));
+        assert!(body.select_first(".newsletter").is_err());
+        assert!(body.select_first("form").is_err());
+        assert!(body.select_first(".related").is_err());
+        assert!(body.select_first("#cookie-notice").is_err());
+    }
+
+    #[test]
+    fn test_select_images() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <img src="/icons/share.png" width="24" height="24">
+                <p>Intro</p>
+                <figure><img src="/photos/hero.jpg"><figcaption>The hero</figcaption></figure>
+                <p>Body</p>
+                <figure><img src="https://example.com/photos/chart.png"><figcaption>A chart</figcaption></figure>
+            </body>
+        </html>
+        "#;
+        let article_with = |mode: ImagesMode, lead_image: Option<&str>| {
+            let mut html_str = html_str.to_owned();
+            if let Some(lead_image) = lead_image {
+                html_str = html_str.replace(
+                    "<html>",
+                    &format!(
+                        r#"<html><head><meta property="og:image" content="{}"></head>"#,
+                        lead_image
+                    ),
+                );
+            }
+            let mut article = Article::from_html(&html_str, "https://example.com/posts/1");
+            article.extract_content().unwrap();
+            article.node_ref_opt = Some(kuchiki::parse_html().one(html_str.as_str()));
+            article.select_images(&mode, false);
+            article.extract_img_urls();
+            (
+                article
+                    .img_urls
+                    .iter()
+                    .map(|(url, _)| url.clone())
+                    .collect_vec(),
+                article.node_ref().select("figcaption").unwrap().count(),
+            )
+        };
+
+        assert_eq!(3, article_with(ImagesMode::All, None).0.len());
+        assert_eq!(
+            (Vec::<String>::new(), 0),
+            article_with(ImagesMode::None, None)
+        );
+        assert_eq!(
+            (vec!["/photos/hero.jpg".to_owned()], 1),
+            article_with(ImagesMode::Lead, None)
+        );
+        assert_eq!(
+            (vec!["https://example.com/photos/chart.png".to_owned()], 1),
+            article_with(ImagesMode::Lead, Some("/photos/chart.png"))
+        );
+        assert_eq!(
+            (vec!["https://example.com/og.jpg".to_owned()], 0),
+            article_with(ImagesMode::Lead, Some("https://example.com/og.jpg"))
+        );
+    }
+
+    #[test]
+    fn test_link_internal_anchors() {
+        let html_str = r##"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Salami is cured for weeks<sup><a href="#cite_note-1">[1]</a></sup> and
+                smoked<sup><a href="https://example.com/posts/salami#cite_note-2">[2]</a></sup>.
+                See the <a href="#comments">comments</a>.</p>
+                <ol class="references">
+                    <li id="cite_note-1">Curing meats, 2019</li>
+                    <li><a name="cite_note-2"></a>Smoking meats, 2020</li>
+                </ol>
+            </body>
+        </html>
+        "##;
+        let mut article = Article::from_html(html_str, "https://example.com/posts/salami");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+        article.link_internal_anchors();
+
+        let hrefs = article
+            .node_ref()
+            .select("p a")
+            .unwrap()
+            .map(|link| link.attributes.borrow().get("href").unwrap().to_owned())
+            .collect_vec();
+        let first_ref = article.node_ref().select_first("li").unwrap();
+        let first_id = first_ref.attributes.borrow().get("id").unwrap().to_owned();
+        let second_ref = article.node_ref().select_first("li a").unwrap();
+        let second_id = second_ref.attributes.borrow().get("id").unwrap().to_owned();
+        assert!(first_id.starts_with('_'));
+        assert_ne!(first_id, second_id);
+        assert_eq!(
+            vec![
+                format!("#{}", first_id),
+                format!("#{}", second_id),
+                "https://example.com/posts/salami#comments".to_owned()
+            ],
+            hrefs
+        );
+    }
+
+    #[test]
+    fn test_insert_img_placeholder() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p><img src="a.png" width="600" height="300px"></p>
+                <p><img src="b.png" height="200" srcset="b-small.png 400w, b-large.png 800w"></p>
+                <p><img src="c.png" width="600"></p>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+        for img in doc.select("img").unwrap().collect_vec() {
+            insert_img_placeholder(&img, img.as_node());
+            img.as_node().detach();
+        }
+        let styles = doc
+            .select(".paperoni-img-placeholder")
+            .unwrap()
+            .map(|placeholder| {
+                let outer_style = placeholder
+                    .attributes
+                    .borrow()
+                    .get("style")
+                    .unwrap()
+                    .to_owned();
+                let inner = placeholder.as_node().first_child().unwrap();
+                let inner_style = inner
+                    .as_element()
+                    .unwrap()
+                    .attributes
+                    .borrow()
+                    .get("style")
+                    .unwrap()
+                    .to_owned();
+                (outer_style, inner_style)
+            })
+            .collect_vec();
+        assert_eq!(2, styles.len());
+        assert!(styles[0].0.contains("width:600px"));
+        assert_eq!("display:block;padding-top:50.00%", styles[0].1);
+        assert!(styles[1].0.contains("width:800px"));
+        assert_eq!("display:block;padding-top:25.00%", styles[1].1);
+    }
+
+    #[test]
+    fn test_strip_img_cache_busters() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <img src="/logo.png?v=1">
+                <img src="https://cdn.example.com/logo.png?v=2">
+                <img src="photo.jpg?w=300&amp;cb=8f2a">
+                <img src="chart.png?w=300">
+                <img src="data:image/gif;base64,R0lGODlh">
+            </body>
+        </html>
+        "#;
+        let mut article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "https://cdn.example.com/posts/".into(),
+            response: None,
+        };
+        article.strip_img_cache_busters();
+        article.extract_img_urls();
+
+        assert_eq!(
+            vec![
+                ("https://cdn.example.com/logo.png".to_string(), None),
+                (
+                    "https://cdn.example.com/posts/photo.jpg?w=300".to_string(),
+                    None
+                ),
+                ("chart.png?w=300".to_string(), None),
+                ("data:image/gif;base64,R0lGODlh".to_string(), None),
+            ],
+            article.img_urls
+        );
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Pepperoni is a variety of salami.</p>
+                <img src="pepperoni.jpg"><img src="pizza.jpg">
+            </body>
+        </html>
+        "#;
+        let mut metadata = MetaData::new();
+        metadata.set_title("Pepperoni".into());
+        let mut article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata,
+            url: "https://example.com/pepperoni".into(),
+            response: None,
+        };
+        assert_eq!(
+            ArticleSidecar {
+                title: "Pepperoni",
+                author: None,
+                site_name: None,
+                excerpt: None,
+                source_url: "https://example.com/pepperoni",
+                word_count: 6,
+                image_count: 2,
+                requested_url: None,
+                http_status: None,
+            },
+            article.sidecar()
+        );
+
+        article.response = Some(ResponseInfo {
+            requested_url: "https://example.com/p/1".into(),
+            status: Some(200),
+            timings: ArticleTimings::default(),
+        });
+        let sidecar = article.sidecar();
+        assert_eq!(Some("https://example.com/p/1"), sidecar.requested_url);
+        assert_eq!(Some(200), sidecar.http_status);
+    }
+
+    #[test]
+    fn test_linked_pdf_urls() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Read the <a href="https://example.com/paper.PDF?download=1">paper</a></p>
+                <p>Or the <a href="https://example.com/paper.PDF?download=1">same paper</a></p>
+                <p>And the <a href="https://example.com/slides.html">slides</a></p>
+                <p>See the <a href="ftp://example.com/appendix.pdf">appendix</a></p>
+            </body>
+        </html>
+        "#;
+        let article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "https://example.com/".into(),
+            response: None,
+        };
+        assert_eq!(
+            vec!["https://example.com/paper.PDF?download=1"],
+            article.linked_pdf_urls()
+        );
+    }
+
+    #[test]
+    fn test_file_stem() {
+        let mut article = Article::from_html("", "https://www.example.com/");
+        article.metadata.set_title("Home/Away".into());
+        assert_eq!("Home Away", article.file_stem(false));
+        assert_eq!(
+            format!(
+                "Home Away (example.com {})",
+                &format!("{:x}", md5::compute("https://www.example.com/"))[..8]
+            ),
+            article.file_stem(true)
+        );
+        assert_eq!("example.com", article.domain());
+        assert_eq!("", Article::from_html("", "data:text/html,Home").domain());
+    }
+
+    #[test]
+    fn test_audio_urls() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <audio controls><source src="/episodes/42.mp3" type="audio/mpeg"></audio>
+                <audio src="https://cdn.example.com/narration.m4a"></audio>
+                <p>Download the <a href="https://example.com/episodes/42.mp3">episode</a></p>
+                <p>Read the <a href="https://example.com/transcript.html">transcript</a></p>
+            </body>
+        </html>
+        "#;
+        let article = Article {
+            node_ref_opt: Some(kuchiki::parse_html().one(html_str)),
+            img_urls: Vec::new(),
+            attachments: Vec::new(),
+            cover: None,
+            readability: None,
+            metadata: MetaData::new(),
+            url: "https://example.com/posts/42".into(),
+            response: None,
+        };
+        assert_eq!(
+            vec![
+                "https://example.com/episodes/42.mp3",
+                "https://cdn.example.com/narration.m4a"
+            ],
+            article.audio_urls()
+        );
+    }
+
+    #[test]
+    fn test_to_title_case() {
+        assert_eq!(
+            "The Rise and Fall of the Roman Empire",
+            to_title_case("THE RISE AND FALL OF THE ROMAN EMPIRE")
+        );
+        assert_eq!(
+            "Why You Shouldn't Trust Well-Known Benchmarks",
+            to_title_case("WHY YOU SHOULDN'T TRUST WELL-KNOWN BENCHMARKS")
+        );
+        assert_eq!("What It Is For", to_title_case("WHAT  IT IS FOR"));
+        assert_eq!("", to_title_case(""));
+    }
+
+    #[test]
+    fn test_normalize_title() {
+        let mut article = Article::from_html(TEST_HTML, "http://example.com/");
+        article.metadata.set_title("RUST 1.52 RELEASED".into());
+        article.normalize_title();
+        assert_eq!("Rust 1.52 Released", article.metadata().title());
+
+        article
+            .metadata
+            .set_title("Announcing NASA's new rover".into());
+        article.normalize_title();
+        assert_eq!("Announcing NASA's new rover", article.metadata().title());
+    }
+
+    #[test]
+    fn test_strip_emoji() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Launch day 🚀 is here! 👨‍👩‍👧 Thumbs up 👍🏽 and a ☕️ — café ✓ 1 + 1 = 2</p>
+                <p>♩ C♯ ♠ ♥ ★ ✔ ✗ ☐ ❶ ❤️ 🏳️‍🌈</p>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+        article
+            .metadata
+            .set_title("🎉 Rust 1.52 Released 🎉".into());
+        article.strip_emoji();
+        assert_eq!("Rust 1.52 Released", article.metadata().title());
+        assert_eq!(
+            "Launch day  is here!  Thumbs up  and a  — café ✓ 1 + 1 = 2",
+            article
+                .node_ref()
+                .select_first("p")
+                .unwrap()
+                .text_contents()
+        );
+        // Symbols shown as text by default are kept
+        assert_eq!(
+            "♩ C♯ ♠ ♥ ★ ✔ ✗ ☐ ❶  ",
+            article
+                .node_ref()
+                .select("p")
+                .unwrap()
+                .nth(1)
+                .unwrap()
+                .text_contents()
+        );
+
+        article.metadata.set_title("🎉🎉".into());
+        article.strip_emoji();
+        assert_eq!("🎉🎉", article.metadata().title());
+    }
+
+    #[test]
+    fn test_insert_header_footer() {
+        let mut article = Article::from_html(TEST_HTML, "http://example.com/?a=1&b=2");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(TEST_HTML));
+        article.metadata.set_title("Salt & Pepper".into());
+        article.insert_header_footer(
+            Some("<p>Archived by MyOrg — {date}</p>"),
+            Some(r#"<p><a href="{url}">{title}</a></p>"#),
+            "2021-06-01",
+        );
+        let body = article.node_ref().select_first("body").unwrap();
+        let header = body.as_node().first_child().unwrap();
+        assert_eq!(
+            "paperoni-header",
+            header
+                .as_element()
+                .unwrap()
+                .attributes
+                .borrow()
+                .get("class")
+                .unwrap()
+        );
+        assert_eq!("Archived by MyOrg — 2021-06-01", header.text_contents());
+        let footer = body.as_node().last_child().unwrap();
+        let link = footer.select_first("div.paperoni-footer a").unwrap();
+        assert_eq!(
+            Some("http://example.com/?a=1&b=2"),
+            link.attributes.borrow().get("href")
+        );
+        assert_eq!("Salt & Pepper", link.text_contents());
+
+        article.insert_header_footer(None, None, "2021-06-01");
+        assert_eq!(
+            1,
+            article
+                .node_ref()
+                .select("div.paperoni-header")
+                .unwrap()
+                .count()
+        );
+    }
+
+    #[test]
+    fn test_strip_links() {
+        let html_str = r##"
+        <!doctype html>
+        <html>
+            <body>
+                <p>See <a href="https://example.com/study"><em>the study</em></a> for details<a href="#fn1" id="ref1">1</a>.</p>
+                <p><a id="fn1" href="#ref1">1</a> Published in 2021.</p>
+            </body>
+        </html>
+        "##;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+        article.strip_links();
+        let paragraphs = article
+            .node_ref()
+            .select("p")
+            .unwrap()
+            .map(|p| p.text_contents())
+            .collect_vec();
+        assert_eq!(
+            vec!["See the study for details1.", "1 Published in 2021."],
+            paragraphs
+        );
+        assert!(article.node_ref().select_first("a[href]").is_err());
+        assert!(article.node_ref().select_first("p > em").is_ok());
+        assert_eq!(2, article.node_ref().select("a[id]").unwrap().count());
+    }
+
+    #[test]
+    fn test_insert_user_css() {
+        let mut article = Article::from_html("", "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(
+            r#"<html><head><link rel="stylesheet" href="stylesheet.css"></head><body></body></html>"#,
+        ));
+        article.insert_user_css("body > p { font-size: 1.2em } </style>");
+        let head = article.node_ref().select_first("head").unwrap();
+        let style = head.as_node().last_child().unwrap();
+        assert_eq!("style", &*style.as_element().unwrap().name.local);
+        assert_eq!(
+            "body > p { font-size: 1.2em } <\\/style>",
+            style.text_contents()
+        );
+        let xhtml = serialize_to_xhtml_string(article.node_ref()).unwrap();
+        assert!(xhtml
+            .contains("<style>body &gt; p { font-size: 1.2em } &lt;\\/style&gt;</style></head>"));
+    }
+
+    #[test]
+    fn test_strip_comments() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <!--[if IE]><p>Upgrade your browser</p><![endif]-->
+                <p>Some text<!-- ad slot --> that is kept.</p>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+        article.strip_comments();
+        assert!(article
+            .node_ref()
+            .descendants()
+            .all(|node| node.as_comment().is_none()));
+        assert_eq!(
+            "Some text that is kept.",
+            article
+                .node_ref()
+                .select_first("p")
+                .unwrap()
+                .text_contents()
+        );
+        assert_eq!(
+            r#"<p>Some text that is kept.</p>"#,
+            article
+                .node_ref()
+                .select_first("p")
+                .unwrap()
+                .as_node()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn test_word_break_segments() {
+        assert_eq!(
+            vec!["Short words stay whole"],
+            word_break_segments("Short words stay whole")
+        );
+        assert_eq!(
+            vec![
+                "See https://",
+                "example.",
+                "com/",
+                "articles/",
+                "2021/",
+                "a-",
+                "very-",
+                "long-",
+                "slug for details"
+            ],
+            word_break_segments(
+                "See https://example.com/articles/2021/a-very-long-slug for details"
+            )
+        );
+        assert_eq!(
+            vec!["Pneumonoultramicrosc", "opicsilicovolcanocon", "iosis"],
+            word_break_segments("Pneumonoultramicroscopicsilicovolcanoconiosis")
+        );
+    }
+
+    #[test]
+    fn test_duplicate_filter() {
+        const STORY: &str = "Paperoni downloads web articles and exports them as EPUB files. It \
+            extracts the content of each article with a port of the readability library used by \
+            Firefox, downloads its images and bundles everything into a book that can be read on \
+            an e-reader. Articles from several sites can be merged into one book with a table of \
+            contents, and the styling of the exported files can be changed. Syndicated stories are \
+            often published on several sites with small changes such as a different byline, a new \
+            headline or an extra sentence at the end. When these articles are downloaded together \
+            they show up as separate chapters with almost the same text, which makes digests \
+            longer and harder to read.";
+        let article = |url: &str, text: &str| {
+            let mut article = Article::from_html("", url);
+            article.node_ref_opt = Some(
+                kuchiki::parse_html().one(format!("<html><body><p>{}</p></body></html>", text)),
+            );
+            article
+        };
+        let story = article("https://example.com/story", STORY);
+        let syndicated = article(
+            "https://example.org/story",
+            &format!("{} Originally published by the Example Times.", STORY),
+        );
+        let other = article(
+            "https://example.net/sourdough",
+            "Sourdough relies on wild yeast and lactic acid bacteria to rise. The starter is fed \
+            with flour and water every day until it doubles in size reliably. Once the starter is \
+            active, mix it with flour, water and salt, then let the dough rest before shaping and \
+            baking it in a very hot oven. The crust should be dark and the crumb open, and the loaf \
+            needs to cool before it is cut or the inside will be gummy.",
+        );
+        let fingerprint = |article: &Article| article.content_fingerprint().unwrap();
+        assert_eq!(
+            5,
+            (fingerprint(&story) ^ fingerprint(&syndicated)).count_ones()
+        );
+        assert_eq!(30, (fingerprint(&story) ^ fingerprint(&other)).count_ones());
+        assert_eq!(
+            None,
+            article("https://example.com/", " ").content_fingerprint()
+        );
+
+        let mut filter = DuplicateFilter::new(8);
+        assert!(filter.check(&story).is_none());
+        assert!(filter.check(&other).is_none());
+        let duplicate = filter.check(&syndicated).unwrap();
+        assert_eq!("https://example.org/story", duplicate.link);
+        assert_eq!("https://example.com/story", duplicate.duplicate_of);
+
+        let mut filter = DuplicateFilter::new(4);
+        assert!(filter.check(&story).is_none());
+        assert!(filter.check(&syndicated).is_none());
+    }
+
+    #[test]
+    fn test_strip_site_suffix() {
+        assert_eq!(
+            "Rust 1.52 Released",
+            strip_site_suffix(
+                "Rust 1.52 Released | The Example Times",
+                Some("The Example Times"),
+                "example.com"
+            )
+        );
+        assert_eq!(
+            "Rust 1.52 Released",
+            strip_site_suffix(
+                "Rust 1.52 Released - Example Times",
+                None,
+                "www.exampletimes.co.uk"
+            )
+        );
+        assert_eq!(
+            "Rust 1.52 Released – Tech",
+            strip_site_suffix(
+                "Rust 1.52 Released – Tech – Example",
+                Some("Example"),
+                "example.com"
+            )
+        );
+        assert_eq!(
+            "Rust 1.52 Released",
+            strip_site_suffix("Rust 1.52 Released | Daily Post", None, "dp.example.org")
+        );
+        assert_eq!(
+            "Write-ahead logging - a primer",
+            strip_site_suffix("Write-ahead logging - a primer", None, "example.com")
+        );
+        assert_eq!(
+            "Example",
+            strip_site_suffix("Example", Some("Example"), "example.com")
+        );
+    }
+
+    #[test]
+    fn test_use_og_title() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <head>
+                <title>Rust 1.52 Released | The Example Times</title>
+                <meta property="og:site_name" content="The Example Times">
+            </head>
+            <body><h1>Rust 1.52 Released</h1></body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "https://www.example.com/rust-1-52");
+        article.metadata = article.readability.as_ref().unwrap().parse_metadata();
+        article.metadata.set_title("Readability title".into());
+        article.use_og_title();
+        assert_eq!("Rust 1.52 Released", article.metadata().title());
+    }
+
+    #[test]
+    fn test_collapse_whitespace() {
+        let mut ends_in_space = true;
+        assert_eq!(
+            "let x = 1; ",
+            collapse_whitespace("\n    let  x =\t1;\n", &mut ends_in_space)
+        );
+        assert!(ends_in_space);
+        assert_eq!("y", collapse_whitespace("  y", &mut ends_in_space));
+        assert!(!ends_in_space);
+    }
+
+    #[test]
+    fn test_collapse_whitespace_in_pre() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Some   text</p>
+                <pre>
+    <code>fn main()   {
+        <span class="macro">println!</span>(  "Hello,   world"  );
+    }
+    </code>
+</pre>
+                <pre>  <pre>nested
+    pre</pre>  </pre>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+
+        // The whitespace in pre blocks is kept by default
+        let xhtml = serialize_body_to_xhtml_string(article.node_ref()).unwrap();
+        assert!(xhtml.contains("<code>fn main()   {\n        <span class=\"macro\">"));
+
+        article.collapse_whitespace_in_pre();
+        let pres = article.node_ref().select("pre").unwrap().collect_vec();
+        assert_eq!(
+            r#"fn main() { println!( "Hello, world" ); }"#,
+            pres[0].text_contents()
+        );
+        let code = pres[0].as_node().select_first("code").unwrap();
+        assert_eq!(
+            r#"<code>fn main() { <span class="macro">println!</span>( &quot;Hello, world&quot; ); }</code>"#,
+            serialize_to_xhtml_string(code.as_node()).unwrap()
+        );
+        assert_eq!("nested pre", pres[1].text_contents());
+        let p = article.node_ref().select_first("p").unwrap();
+        assert_eq!("Some   text", p.text_contents());
+    }
+
+    #[test]
+    fn test_break_long_words() {
+        let html_str = r#"
+        <!doctype html>
+        <html>
+            <body>
+                <p>Read https://example.com/articles/2021/a-very-long-slug today</p>
+                <pre>https://example.com/articles/2021/a-very-long-slug</pre>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(html_str));
+        article.break_long_words();
+        let p = article.node_ref().select_first("p").unwrap();
+        assert_eq!(8, p.as_node().select("wbr").unwrap().count());
+        assert_eq!(
+            "Read https://example.com/articles/2021/a-very-long-slug today",
+            p.text_contents()
+        );
+        assert!(article.node_ref().select_first("pre wbr").is_err());
+    }
+
+    #[test]
+    fn test_insert_qr_code() {
+        let mut article = Article::from_html(TEST_HTML, "https://example.com/posts/1?a=1&b=2");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(TEST_HTML));
+        article.insert_qr_code("qr_abc123.svg");
+        let body = article.node_ref().select_first("body").unwrap();
+        let qr_code = body.as_node().last_child().unwrap();
+        let img = qr_code.select_first("figure.paperoni-qr > img").unwrap();
+        assert_eq!(Some("qr_abc123.svg"), img.attributes.borrow().get("src"));
+        assert_eq!(
+            "Read on the web: https://example.com/posts/1?a=1&b=2",
+            qr_code.text_contents()
+        );
+    }
+
+    #[test]
+    fn test_insert_source_link() {
+        let mut article = Article::from_html(TEST_HTML, "https://example.com/posts/1?a=1&b=2");
+        article.node_ref_opt =
+            Some(kuchiki::parse_html().one(
+                r#"<html><body><div id="readability-page-1"><p>Content</p></div></body></html>"#,
+            ));
+        article.insert_source_link();
+        let link = article
+            .node_ref()
+            .select_first("#readability-page-1 > p.paperoni-source-link:last-child > a")
+            .unwrap();
+        assert_eq!(
+            Some("https://example.com/posts/1?a=1&b=2"),
+            link.attributes.borrow().get("href")
+        );
+        assert_eq!("https://example.com/posts/1?a=1&b=2", link.text_contents());
+    }
+
+    #[test]
+    fn test_insert_favicon() {
+        let mut article = Article::from_html(TEST_HTML, "https://www.example.com/posts/1");
+        article.node_ref_opt = Some(kuchiki::parse_html().one(TEST_HTML));
+        article.insert_favicon("abc123.png");
+        let source = article
+            .node_ref()
+            .select_first("body > p.paperoni-source")
+            .unwrap();
+        let favicon = source.as_node().select_first("img").unwrap();
+        assert_eq!(Some("abc123.png"), favicon.attributes.borrow().get("src"));
+        assert_eq!(" www.example.com", source.text_contents());
+    }
+
+    #[test]
+    fn test_serialize_to_xhtml_preserves_mathml() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <p>Where <math><mi>x</mi><mo>=</mo><msup><mi>y</mi><mn>2</mn></msup></math> holds</p>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+        let xhtml_str = serialize_to_xhtml_string(&doc).unwrap();
+
+        assert!(xhtml_str.contains(
+            r#"<math xmlns="http://www.w3.org/1998/Math/MathML"><mi>x</mi><mo>=</mo><msup><mi>y</mi><mn>2</mn></msup></math>"#
+        ));
+    }
+
+    #[test]
+    fn test_definition_lists_are_preserved() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>A glossary of curing</title></head>
+            <body>
+                <article>
+                    <div class="content">
+                        <p>Curing is one of the oldest ways of preserving meat, and it relies on salt, time and careful control of the temperature and humidity of the room the meat is hung in.</p>
+                        <p>Salami, pepperoni and other dry sausages are cured for weeks, during which they lose a large share of their weight as water evaporates and their flavour deepens.</p>
+                        <p>The terms below come up often in recipes, and knowing them makes it much easier to follow the instructions and to understand why each of the steps matters.</p>
+                    </div>
+                    <dl><dt>Nitrite</dt><dd>A curing salt that keeps cured meats pink and stops the growth of bacteria while they dry.</dd><dt>Casing</dt><dd>The natural or synthetic tube that sausages are stuffed into before they are hung up to dry.</dd></dl>
+                </article>
+            </body>
+        </html>
+        "#;
+        let mut article = Article::from_html(html_str, "http://example.com/");
+        article.extract_content().unwrap();
+        let dl = article.node_ref().select_first("dl").unwrap();
+        let terms = dl
+            .as_node()
+            .children()
+            .elements()
+            .map(|elem| (elem.name.local.to_string(), elem.text_contents()))
+            .collect_vec();
+        assert_eq!(
+            vec![
+                ("dt".to_owned(), "Nitrite".to_owned()),
+                ("dd".to_owned(), "A curing salt that keeps cured meats pink and stops the growth of bacteria while they dry.".to_owned()),
+                ("dt".to_owned(), "Casing".to_owned()),
+                ("dd".to_owned(), "The natural or synthetic tube that sausages are stuffed into before they are hung up to dry.".to_owned()),
+            ],
+            terms
+        );
+
+        let xhtml_str = serialize_to_xhtml_string(article.node_ref()).unwrap();
+        assert!(xhtml_str.contains("<dt>Nitrite</dt><dd>A curing salt"));
+    }
+
+    #[test]
+    fn test_arrange_articles() {
+        let mut articles = vec![1, 2, 3, 4, 5];
+        arrange_articles(&mut articles, &ArticleOrder::Original);
+        assert_eq!(vec![1, 2, 3, 4, 5], articles);
+        arrange_articles(&mut articles, &ArticleOrder::Reverse);
+        assert_eq!(vec![5, 4, 3, 2, 1], articles);
+
+        let shuffle = |seed| {
+            let mut articles = (0..20).collect_vec();
+            arrange_articles(&mut articles, &ArticleOrder::Shuffle(seed));
+            articles
+        };
+        assert_eq!(shuffle(7), shuffle(7));
+        assert_ne!(shuffle(7), shuffle(8));
+        let mut shuffled = shuffle(7);
+        assert_ne!((0..20).collect_vec(), shuffled);
+        shuffled.sort_unstable();
+        assert_eq!((0..20).collect_vec(), shuffled);
+    }
 }
@@ -1,7 +1,7 @@
 use std::{
     collections::{BTreeMap, HashSet},
     fs::{self, File},
-    path::Path,
+    path::{Path, PathBuf},
 };
 
 use base64::encode;
@@ -12,7 +12,8 @@ use kuchiki::{traits::*, NodeRef};
 use log::{debug, error, info};
 
 use crate::{
-    cli::{self, AppConfig, CSSConfig},
+    catalog::{add_metadata_row, MetadataRow},
+    cli::{self, AppConfig, CSSConfig, HighlightTheme},
     errors::PaperoniError,
     extractor::Article,
     moz_readability::MetaData,
@@ -32,6 +33,8 @@ pub fn generate_html_exports(
     articles: Vec<Article>,
     app_config: &AppConfig,
     successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
 ) -> Result<(), Vec<PaperoniError>> {
     if articles.is_empty() {
         return Ok(());
@@ -66,7 +69,12 @@ pub fn generate_html_exports(
             let body_elem = base_html_elem.select_first("body").unwrap();
             let base_path = Path::new(app_config.output_directory.as_deref().unwrap_or("."));
             let img_dirs_path_name = name.trim_end_matches(".html");
-            let imgs_dir_path = base_path.join(img_dirs_path_name);
+            let (imgs_dir_path, imgs_src_path) = if app_config.is_flattening_images {
+                flattened_imgs_dir(Path::new(name))
+            } else {
+                let imgs_dir_path = base_path.join(img_dirs_path_name);
+                (imgs_dir_path.clone(), imgs_dir_path)
+            };
 
             if !(app_config.is_inlining_images || imgs_dir_path.exists()) {
                 info!("Creating imgs dir in {:?} for {}", imgs_dir_path, name);
@@ -105,11 +113,12 @@ pub fn generate_html_exports(
                     info!("Completed inlining images for {}", title);
                 } else {
                     info!("Copying images to imgs dir for {}", title);
-                    let result = update_img_urls(article, &imgs_dir_path).map_err(|e| {
-                        let mut err: PaperoniError = e.into();
-                        err.set_article_source(title);
-                        err
-                    });
+                    let result =
+                        update_img_urls(article, &imgs_dir_path, &imgs_src_path).map_err(|e| {
+                            let mut err: PaperoniError = e.into();
+                            err.set_article_source(title);
+                            err
+                        });
                     if let Err(e) = result {
                         error!("Unable to copy images to imgs dir for {}", title);
                         errors.push(e);
@@ -125,14 +134,21 @@ pub fn generate_html_exports(
             }
 
             insert_title_elem(&base_html_elem, name);
-            insert_appendix(
+            if !app_config.is_inlining_sources {
+                insert_appendix(
+                    &base_html_elem,
+                    articles
+                        .iter()
+                        .map(|article| (article.metadata(), article.url.as_str()))
+                        .collect(),
+                );
+            }
+            inline_css(
                 &base_html_elem,
-                articles
-                    .iter()
-                    .map(|article| (article.metadata(), article.url.as_str()))
-                    .collect(),
+                &app_config.css_config,
+                app_config.body_width.as_deref(),
+                app_config.highlight_theme.as_ref(),
             );
-            inline_css(&base_html_elem, &app_config.css_config);
             remove_existing_stylesheet_link(&base_html_elem);
 
             info!("Added title, footer and inlined styles for {}", name);
@@ -151,7 +167,12 @@ pub fn generate_html_exports(
 
             bar.finish_with_message("Generated html file\n");
             debug!("Created {:?}", name);
-            println!("Created {:?}", name);
+            for article in &articles {
+                add_metadata_row(metadata_rows, app_config, article, name);
+            }
+            if app_config.can_print_to_stdout() {
+                println!("Created {:?}", name);
+            }
         }
         None => {
             successful_articles_table
@@ -163,15 +184,15 @@ pub fn generate_html_exports(
 
             let mut file_names: HashSet<String> = HashSet::new();
 
-            for article in &articles {
+            for article in articles.iter() {
+                let file_stem = app_config.numbered_file_stem(
+                    article.file_stem(app_config.is_including_url_in_title),
+                    article.url_index + 1,
+                );
                 let mut file_name = format!(
                     "{}/{}.html",
                     app_config.output_directory.as_deref().unwrap_or("."),
-                    article
-                        .metadata()
-                        .title()
-                        .replace("/", " ")
-                        .replace("\\", " ")
+                    file_stem
                 );
 
                 if file_names.contains(&file_name) {
@@ -179,11 +200,7 @@ pub fn generate_html_exports(
                     file_name = format!(
                         "{}/{}_{}.html",
                         app_config.output_directory.as_deref().unwrap_or("."),
-                        article
-                            .metadata()
-                            .title()
-                            .replace("/", " ")
-                            .replace("\\", " "),
+                        file_stem,
                         file_names.len()
                     );
                     info!("Renamed to {:?}", file_name);
@@ -191,22 +208,28 @@ pub fn generate_html_exports(
                 file_names.insert(file_name.clone());
 
                 debug!("Creating {:?}", file_name);
-                let export_article = || -> Result<(), PaperoniError> {
+                let export_article = || -> Result<Option<String>, PaperoniError> {
                     let mut out_file = File::create(&file_name)?;
+                    let mut imgs_dir = None;
 
                     if app_config.is_inlining_images {
                         update_imgs_base64(article)?;
                     } else {
                         let base_path =
                             Path::new(app_config.output_directory.as_deref().unwrap_or("."));
-                        let imgs_dir_name = article.metadata().title();
-
-                        if !base_path.join(imgs_dir_name).exists() {
-                            std::fs::create_dir(base_path.join(imgs_dir_name))?;
+                        let (imgs_dir_path, imgs_src_path) = if app_config.is_flattening_images {
+                            flattened_imgs_dir(Path::new(&file_name))
+                        } else {
+                            let imgs_dir_path = base_path.join(article.metadata().title());
+                            (imgs_dir_path.clone(), imgs_dir_path)
+                        };
+
+                        if !imgs_dir_path.exists() {
+                            std::fs::create_dir(&imgs_dir_path)?;
                         }
 
-                        let imgs_dir_path = base_path.join(imgs_dir_name);
-                        update_img_urls(article, &imgs_dir_path)?;
+                        update_img_urls(article, &imgs_dir_path, &imgs_src_path)?;
+                        imgs_dir = Some(imgs_dir_path.to_string_lossy().into_owned());
                     }
 
                     let utf8_encoding =
@@ -223,16 +246,28 @@ pub fn generate_html_exports(
 
                     insert_title_elem(article.node_ref(), article.metadata().title());
                     insert_appendix(article.node_ref(), vec![(article.metadata(), &article.url)]);
-                    inline_css(article.node_ref(), &app_config.css_config);
+                    inline_css(
+                        article.node_ref(),
+                        &app_config.css_config,
+                        app_config.body_width.as_deref(),
+                        app_config.highlight_theme.as_ref(),
+                    );
                     remove_existing_stylesheet_link(article.node_ref());
 
                     article.node_ref().serialize(&mut out_file)?;
-                    Ok(())
+                    Ok(imgs_dir)
                 };
 
-                if let Err(mut err) = export_article() {
-                    err.set_article_source(&article.url);
-                    errors.push(err);
+                match export_article() {
+                    Ok(imgs_dir) => {
+                        exported_files.push(file_name.clone());
+                        exported_files.extend(imgs_dir);
+                        add_metadata_row(metadata_rows, app_config, article, &file_name);
+                    }
+                    Err(mut err) => {
+                        err.set_article_source(&article.url);
+                        errors.push(err);
+                    }
                 }
                 debug!("Created {:?}", file_name);
 
@@ -284,8 +319,31 @@ fn update_imgs_base64(article: &Article) -> Result<(), std::io::Error> {
     Ok(())
 }
 
-/// Updates the src attribute of `<img>` elements to the new `imgs_dir_path` and copies the image to the new file location
-fn update_img_urls(article: &Article, imgs_dir_path: &Path) -> Result<(), std::io::Error> {
+/// Returns the `<name>_files` directory next to an HTML export that its images are copied to
+/// with `--flatten-images`, along with the path of the directory relative to the export. The
+/// `<img>` elements point at the relative path so that the export still displays its images
+/// when it is moved along with the directory.
+fn flattened_imgs_dir(html_path: &Path) -> (PathBuf, PathBuf) {
+    let dir_name = format!(
+        "{}_files",
+        html_path
+            .file_stem()
+            .map_or_else(|| "images".into(), |stem| stem.to_string_lossy())
+    );
+    let imgs_dir_path = html_path
+        .parent()
+        .unwrap_or_else(|| Path::new("."))
+        .join(&dir_name);
+    (imgs_dir_path, PathBuf::from(dir_name))
+}
+
+/// Updates the src attribute of `<img>` elements to `imgs_src_path` and copies the images to
+/// `imgs_dir_path`
+fn update_img_urls(
+    article: &Article,
+    imgs_dir_path: &Path,
+    imgs_src_path: &Path,
+) -> Result<(), std::io::Error> {
     let temp_dir = std::env::temp_dir();
     for (img_url, _) in &article.img_urls {
         let (from, to) = (temp_dir.join(img_url), imgs_dir_path.join(img_url));
@@ -298,7 +356,7 @@ fn update_img_urls(article: &Article, imgs_dir_path: &Path) -> Result<(), std::i
         for img_elem in img_elems {
             let mut img_attr = img_elem.attributes.borrow_mut();
             if let Some(src_attr) = img_attr.get_mut("src") {
-                *src_attr = imgs_dir_path.join(img_url).to_str().unwrap().into();
+                *src_attr = imgs_src_path.join(img_url).to_str().unwrap().into();
             }
         }
     }
@@ -348,8 +406,14 @@ fn insert_appendix(root_node: &NodeRef, article_links: Vec<(&MetaData, &str)>) {
     root_node.append(footer_elem.as_node().clone());
 }
 
-/// Inlines the CSS stylesheets into the HTML article node
-fn inline_css(root_node: &NodeRef, css_config: &CSSConfig) {
+/// Inlines the CSS stylesheets into the HTML article node. The body width only applies along
+/// with the default stylesheet.
+fn inline_css(
+    root_node: &NodeRef,
+    css_config: &CSSConfig,
+    body_width: Option<&str>,
+    highlight_theme: Option<&HighlightTheme>,
+) {
     let body_stylesheet = include_str!("./assets/body.min.css");
     let header_stylesheet = include_str!("./assets/headers.min.css");
     let mut css_str = String::new();
@@ -361,9 +425,16 @@ fn inline_css(root_node: &NodeRef, css_config: &CSSConfig) {
             css_str.push_str(body_stylesheet);
             css_str.push_str(header_stylesheet);
         }
-        cli::CSSConfig::None => {
-            return;
-        }
+        cli::CSSConfig::None => (),
+    }
+    if let (Some(body_width), false) = (body_width, css_str.is_empty()) {
+        css_str.push_str(&cli::body_width_stylesheet(body_width));
+    }
+    if let Some(theme) = highlight_theme {
+        css_str.push_str(theme.stylesheet());
+    }
+    if css_str.is_empty() {
+        return;
     }
     let css_html_str = format!("<style>{}</style>", css_str);
     let style_container =
@@ -408,6 +479,21 @@ mod test {
         );
     }
 
+    #[test]
+    fn test_flattened_imgs_dir() {
+        assert_eq!(
+            (
+                PathBuf::from("out/Pepperoni_files"),
+                PathBuf::from("Pepperoni_files")
+            ),
+            flattened_imgs_dir(Path::new("out/Pepperoni.html"))
+        );
+        assert_eq!(
+            (PathBuf::from("reads_files"), PathBuf::from("reads_files")),
+            flattened_imgs_dir(Path::new("reads.html"))
+        );
+    }
+
     #[test]
     fn test_inline_css() {
         let html_str = r#"<html>
@@ -421,22 +507,47 @@ mod test {
         let header_stylesheet = include_str!("./assets/headers.min.css");
         assert_eq!(0, doc.select("style").unwrap().count());
 
-        inline_css(&doc, &CSSConfig::None);
+        inline_css(&doc, &CSSConfig::None, None, None);
         assert_eq!(0, doc.select("style").unwrap().count());
 
-        inline_css(&doc, &CSSConfig::NoHeaders);
+        inline_css(&doc, &CSSConfig::NoHeaders, None, None);
         assert_eq!(1, doc.select("style").unwrap().count());
         let style_elem = doc.select_first("style").unwrap();
         assert_eq!(body_stylesheet, style_elem.text_contents());
 
         let doc = kuchiki::parse_html().one(html_str);
-        inline_css(&doc, &CSSConfig::All);
+        inline_css(&doc, &CSSConfig::All, None, None);
         assert_eq!(1, doc.select("style").unwrap().count());
         let style_elem = doc.select_first("style").unwrap();
         assert_eq!(
             format!("{}{}", body_stylesheet, header_stylesheet),
             style_elem.text_contents()
         );
+
+        let doc = kuchiki::parse_html().one(html_str);
+        inline_css(&doc, &CSSConfig::None, None, Some(&HighlightTheme::Dark));
+        assert_eq!(1, doc.select("style").unwrap().count());
+        let style_elem = doc.select_first("style").unwrap();
+        assert_eq!(
+            HighlightTheme::Dark.stylesheet(),
+            style_elem.text_contents()
+        );
+
+        let doc = kuchiki::parse_html().one(html_str);
+        inline_css(&doc, &CSSConfig::NoHeaders, Some("40em"), None);
+        let style_elem = doc.select_first("style").unwrap();
+        assert_eq!(
+            format!(
+                "{}body{{max-width:40em;margin-left:auto;margin-right:auto}}",
+                body_stylesheet
+            ),
+            style_elem.text_contents()
+        );
+
+        // The body width is left out without the default stylesheet
+        let doc = kuchiki::parse_html().one(html_str);
+        inline_css(&doc, &CSSConfig::None, Some("40em"), None);
+        assert_eq!(0, doc.select("style").unwrap().count());
     }
 
     #[test]
@@ -1,85 +1,881 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_std::channel::{self, Receiver, Sender};
 use async_std::io::prelude::*;
 use async_std::task;
 use async_std::{fs::File, stream};
+use chrono::{DateTime, NaiveDate, Utc};
 use futures::StreamExt;
 use indicatif::ProgressBar;
+use itertools::Itertools;
+use kuchiki::{traits::*, NodeRef};
 use log::warn;
 use log::{debug, info};
 use url::Url;
 
-use crate::cli::AppConfig;
+use crate::catalog::CatalogEntry;
+use crate::cli::{AppConfig, CleanMode, ExportType, ImageFormatTarget, ImagesMode, InsecureImages};
 use crate::errors::{ErrorKind, ImgError, PaperoniError};
-use crate::extractor::Article;
-type HTMLResource = (String, String);
+use crate::extractor::{
+    insert_img_placeholder, Article, ArticleTimings, Attachment, ExtractedArticle, ResourceInfo,
+    ResponseInfo,
+};
+use crate::moz_readability::ReadabilityOptions;
+use crate::netrc::Netrc;
+use crate::progress::{emit_failed, emit_progress, ProgressStage};
+use crate::social::MastodonStatus;
+
+/// A downloaded HTML document
+pub struct HTMLResource {
+    /// The URL the document was served from once redirects were followed
+    pub final_url: String,
+    /// The HTTP status of the response. It is missing for documents rendered in a browser
+    pub status: Option<u16>,
+    pub html: String,
+}
+
+/// Used to find article links on listing pages when no link selector is provided
+const DEFAULT_LINK_SELECTOR: &str =
+    r#"a[rel~="bookmark"], article h1 a, article h2 a, article h3 a"#;
+/// The number of times an article is requested again after the server responds that it is
+/// rate limited or unavailable with a Retry-After header
+const MAX_RATE_LIMIT_RETRIES: u8 = 3;
+/// The longest total time spent waiting on the Retry-After headers of a single article
+const MAX_RETRY_AFTER_WAIT: Duration = Duration::from_secs(120);
+/// The maximum size in bytes of an audio file downloaded with `--fetch-audio`
+const MAX_AUDIO_SIZE: u64 = 100 * 1024 * 1024;
+/// The maximum size in bytes of a favicon downloaded with `--favicons`
+const MAX_FAVICON_SIZE: u64 = 100 * 1024;
+/// The maximum number of sitemaps fetched when following sitemap index files
+const MAX_SITEMAPS: usize = 100;
+/// The browsers looked up in the PATH when rendering articles without --browser
+const BROWSER_NAMES: [&str; 5] = [
+    "chromium",
+    "chromium-browser",
+    "google-chrome",
+    "google-chrome-stable",
+    "chrome",
+];
+/// The milliseconds of virtual time a page's scripts are given to render before its DOM is read
+const RENDER_TIME_BUDGET_MS: u32 = 10_000;
 
+lazy_static! {
+    static ref SITEMAP_LOC_REGEX: regex::Regex =
+        regex::Regex::new(r"(?s)<loc>\s*(?:<!\[CDATA\[)?(.*?)(?:\]\]>)?\s*</loc>").unwrap();
+    static ref SITEMAP_ENTRY_REGEX: regex::Regex =
+        regex::Regex::new(r"(?s)<(?:url|sitemap)\b.*?</(?:url|sitemap)>").unwrap();
+    static ref SITEMAP_LASTMOD_REGEX: regex::Regex =
+        regex::Regex::new(r"(?s)<lastmod>\s*(.*?)\s*</lastmod>").unwrap();
+}
+
+/// Downloads and extracts the articles, passing each one to `on_article` as soon as it is ready.
+/// The pages are fetched and extracted in spawned tasks so that up to `max_conn` of them keep
+/// downloading while `on_article` runs, but the images and other resources of the next article
+/// are only downloaded once it returns. Slow work such as writing EPUBs should be handed off,
+/// as the `ArticleEpubWriter` does. Articles are passed in the order of the URLs.
 pub fn download(
     app_config: &AppConfig,
     bar: &ProgressBar,
     partial_downloads: &mut Vec<PartialDownload>,
     errors: &mut Vec<PaperoniError>,
-) -> Vec<Article> {
+    mut on_article: impl FnMut(Article),
+) {
     task::block_on(async {
-        let urls_iter = app_config.urls.iter().map(|url| fetch_html(url));
+        // A single client is shared by all requests so that connections to the same host
+        // are pooled and reused instead of being established for every request
+        let client = surf::Client::new();
+        // The spawned tasks outlive this function's borrows so they share their own copy
+        let shared_config = Arc::new(app_config.clone());
+        let readability_options = ReadabilityOptions {
+            keep_latex: app_config.keep_latex,
+            keep_classes: app_config.keep_classes,
+            classes_to_preserve: app_config.classes_to_preserve.clone(),
+            char_threshold: app_config.char_threshold,
+            n_top_candidates: app_config.n_top_candidates,
+            min_paragraph_length: app_config.min_paragraph_length,
+        };
+        let browser = if app_config.is_rendering {
+            let browser = find_browser(app_config.browser.as_deref());
+            if browser.is_none() {
+                warn!("No browser was found to render articles. Articles are downloaded without rendering");
+            }
+            browser
+        } else {
+            None
+        };
+        let host_permits = app_config
+            .concurrency_per_host
+            .map(|limit| Arc::new(HostPermits::new(&app_config.urls, limit)));
+        let urls_iter = app_config.urls.iter().enumerate().map(|(url_index, url)| {
+            let (url, client, host_permits, browser, shared_config) = (
+                url.clone(),
+                client.clone(),
+                host_permits.clone(),
+                browser.clone(),
+                shared_config.clone(),
+            );
+            let readability_options = readability_options.clone();
+            task::spawn(async move {
+                let _permit = match host_permits {
+                    Some(ref host_permits) => host_permits.acquire(&url).await,
+                    None => None,
+                };
+                emit_progress(&shared_config, ProgressStage::Started, &url);
+                let extract_result = fetch_and_extract(
+                    &url,
+                    &client,
+                    browser.as_deref(),
+                    readability_options,
+                    &shared_config,
+                )
+                .await;
+                (url_index, url, extract_result)
+            })
+        });
+        // At most max_conn articles are in flight. The next one is only spawned once the
+        // oldest has been handed to on_article, which bounds the memory used by large batches
         let mut responses = stream::from_iter(urls_iter).buffered(app_config.max_conn);
-        let mut articles = Vec::new();
-        while let Some(fetch_result) = responses.next().await {
-            match fetch_result {
-                Ok((url, html)) => {
-                    debug!("Extracting {}", &url);
-                    let mut extractor = Article::from_html(&html, &url);
-                    bar.set_message("Extracting...");
-                    match extractor.extract_content() {
-                        Ok(_) => {
-                            extractor.extract_img_urls();
-                            if let Err(img_errors) =
-                                download_images(&mut extractor, &Url::parse(&url).unwrap(), &bar)
-                                    .await
-                            {
-                                partial_downloads
-                                    .push(PartialDownload::new(&url, extractor.metadata().title()));
-                                warn!(
-                                    "{} image{} failed to download for {}",
-                                    img_errors.len(),
-                                    if img_errors.len() > 1 { "s" } else { "" },
-                                    url
-                                );
-                                for img_error in img_errors {
-                                    warn!(
-                                        "{}\n\t\tReason {}",
-                                        img_error.url().as_ref().unwrap(),
-                                        img_error
-                                    );
-                                }
-                            }
-                            articles.push(extractor);
+        // The favicons downloaded for each host. Hosts whose favicon failed to download are
+        // kept as None so that the download is not retried for every article
+        let mut favicons = HashMap::new();
+        while let Some((url_index, requested_url, extract_result)) = responses.next().await {
+            let requested_url = requested_url.as_str();
+            match extract_result {
+                Ok((extracted, resource, timings)) => {
+                    emit_progress(app_config, ProgressStage::Extracted, requested_url);
+                    let mut extractor = Article::from(extracted);
+                    extractor.url_index = url_index;
+                    extractor.response = Some(ResponseInfo {
+                        requested_url: requested_url.to_owned(),
+                        status: resource.status,
+                        timings,
+                    });
+                    if app_config.is_using_og_title {
+                        extractor.use_og_title();
+                    }
+                    if app_config.is_normalizing_titles {
+                        extractor.normalize_title();
+                    }
+                    if app_config.is_stripping_emoji {
+                        extractor.strip_emoji();
+                    }
+                    if matches!(app_config.clean_mode, CleanMode::Aggressive) {
+                        extractor.remove_boilerplate();
+                    }
+                    if app_config.is_removing_interstitials {
+                        extractor.remove_interstitials(
+                            &app_config.interstitial_phrases,
+                            app_config.interstitial_selector.as_deref(),
+                        );
+                    }
+                    extractor.link_internal_anchors();
+                    if app_config.is_stripping_links {
+                        extractor.strip_links();
+                    }
+                    if app_config.is_stripping_comments {
+                        extractor.strip_comments();
+                    }
+                    if let Some(css) = &app_config.user_css {
+                        extractor.insert_user_css(css);
+                    }
+                    if app_config.is_collapsing_pre_whitespace {
+                        extractor.collapse_whitespace_in_pre();
+                    }
+                    if app_config.is_breaking_long_words {
+                        extractor.break_long_words();
+                    }
+                    if app_config.header.is_some() || app_config.footer.is_some() {
+                        extractor.insert_header_footer(
+                            app_config.header.as_deref(),
+                            app_config.footer.as_deref(),
+                            &app_config.start_time.format("%Y-%m-%d").to_string(),
+                        );
+                    }
+                    if app_config.is_saving_raw_html {
+                        save_raw_html(&extractor, &resource.html, app_config);
+                    }
+                    let content_score = extractor.metadata().content_score().unwrap_or(0.0);
+                    debug!(
+                        "Extracted {} with a content score of {:.1}",
+                        extractor.url, content_score
+                    );
+                    if let Some(min_score) = app_config.min_score {
+                        if content_score < min_score {
+                            let mut err: PaperoniError = ErrorKind::ReadabilityError(format!(
+                                "The content score {:.1} is below the minimum score of {}",
+                                content_score, min_score
+                            ))
+                            .into();
+                            err.set_article_source(&extractor.url);
+                            emit_failed(app_config, requested_url, &err);
+                            errors.push(err);
+                            bar.inc(1);
+                            continue;
+                        }
+                    }
+                    if let Some(min_paragraphs) = app_config.min_paragraphs {
+                        let paragraph_count = extractor.paragraph_count();
+                        if paragraph_count < min_paragraphs {
+                            let mut err: PaperoniError = ErrorKind::ReadabilityError(format!(
+                                "The content has {} paragraph{}, fewer than the minimum of {}",
+                                paragraph_count,
+                                if paragraph_count == 1 { "" } else { "s" },
+                                min_paragraphs
+                            ))
+                            .into();
+                            err.set_article_source(&extractor.url);
+                            emit_failed(app_config, requested_url, &err);
+                            errors.push(err);
+                            bar.inc(1);
+                            continue;
+                        }
+                    }
+                    let url = extractor.url.clone();
+                    // Images are left out of text exports so they are not downloaded
+                    if !matches!(app_config.export_type, ExportType::TXT) {
+                        extractor.select_images(
+                            &app_config.images_mode,
+                            app_config.is_keeping_aspect_placeholders,
+                        );
+                        if app_config.is_stripping_query_images {
+                            extractor.strip_img_cache_busters();
+                        }
+                        extractor.extract_img_urls();
+                    }
+                    let mut resource_errors = Vec::new();
+                    let images_start = Instant::now();
+                    let images_result = download_images(
+                        &mut extractor,
+                        &Url::parse(&url).unwrap(),
+                        &bar,
+                        &client,
+                        app_config,
+                    )
+                    .await;
+                    if let Some(response) = &mut extractor.response {
+                        response.timings.download += images_start.elapsed();
+                    }
+                    if let Err(img_errors) = images_result {
+                        warn!(
+                            "{} image{} failed to download for {}",
+                            img_errors.len(),
+                            if img_errors.len() > 1 { "s" } else { "" },
+                            url
+                        );
+                        resource_errors.extend(img_errors);
+                    }
+                    if app_config.is_embedding_favicons {
+                        add_favicon(&mut extractor, &client, app_config, &mut favicons).await;
+                    }
+                    if matches!(app_config.export_type, ExportType::EPUB)
+                        && !matches!(app_config.images_mode, ImagesMode::None)
+                    {
+                        add_cover(&mut extractor, &client, app_config).await;
+                    }
+                    if app_config.is_inlining_sources {
+                        extractor.insert_source_link();
+                    }
+                    if app_config.is_adding_qr_codes {
+                        if let Err(qr_error) = add_qr_code(&mut extractor) {
+                            warn!("Unable to create a QR code for {}", url);
+                            resource_errors.push(qr_error);
+                        }
+                    }
+                    if app_config.is_fetching_linked_pdfs {
+                        if let Err(pdf_errors) =
+                            download_linked_pdfs(&mut extractor, &bar, &client).await
+                        {
+                            warn!(
+                                "{} linked PDF{} failed to download for {}",
+                                pdf_errors.len(),
+                                if pdf_errors.len() > 1 { "s" } else { "" },
+                                url
+                            );
+                            resource_errors.extend(pdf_errors);
+                        }
+                    }
+                    if app_config.is_fetching_audio {
+                        if let Err(audio_errors) =
+                            download_audio(&mut extractor, &bar, &client).await
+                        {
+                            warn!(
+                                "{} audio file{} failed to download for {}",
+                                audio_errors.len(),
+                                if audio_errors.len() > 1 { "s" } else { "" },
+                                url
+                            );
+                            resource_errors.extend(audio_errors);
+                        }
+                    }
+                    if !resource_errors.is_empty() {
+                        for resource_error in &resource_errors {
+                            warn!(
+                                "{}\n\t\tReason {}",
+                                resource_error.url().as_ref().unwrap(),
+                                resource_error
+                            );
                         }
-                        Err(mut e) => {
-                            e.set_article_source(&url);
-                            errors.push(e);
+                        // Partial downloads are left out of the exports when only complete
+                        // captures are wanted
+                        if app_config.is_failing_on_partial {
+                            let mut err: PaperoniError =
+                                ErrorKind::PartialDownloadError(format!(
+                                    "{} of the article's resources failed to download, the first being {}",
+                                    resource_errors.len(),
+                                    resource_errors[0].url().as_deref().unwrap_or("unknown")
+                                ))
+                                .into();
+                            err.set_article_source(&url);
+                            emit_failed(app_config, requested_url, &err);
+                            errors.push(err);
+                            bar.inc(1);
+                            continue;
                         }
+                        partial_downloads
+                            .push(PartialDownload::new(&url, extractor.metadata().title()));
                     }
+                    if app_config.is_writing_sidecars {
+                        save_sidecar(&extractor, app_config);
+                    }
+                    on_article(extractor);
+                }
+                Err(e) => {
+                    emit_failed(app_config, requested_url, &e);
+                    errors.push(e);
                 }
-                Err(e) => errors.push(e),
             }
             bar.inc(1);
         }
-        articles
     })
 }
 
-pub async fn fetch_html(url: &str) -> Result<HTMLResource, PaperoniError> {
-    let client = surf::Client::new();
+/// Downloads the pages and reads their metadata for the catalog without extracting the articles
+/// or downloading their images. The entries are returned in the order of the URLs.
+pub fn download_metadata(
+    app_config: &AppConfig,
+    bar: &ProgressBar,
+    errors: &mut Vec<PaperoniError>,
+) -> Vec<CatalogEntry> {
+    task::block_on(async {
+        let client = surf::Client::new();
+        let shared_config = Arc::new(app_config.clone());
+        let host_permits = app_config
+            .concurrency_per_host
+            .map(|limit| Arc::new(HostPermits::new(&app_config.urls, limit)));
+        let urls_iter = app_config.urls.iter().map(|url| {
+            let (url, client, host_permits, shared_config) = (
+                url.clone(),
+                client.clone(),
+                host_permits.clone(),
+                shared_config.clone(),
+            );
+            task::spawn(async move {
+                let _permit = match host_permits {
+                    Some(ref host_permits) => host_permits.acquire(&url).await,
+                    None => None,
+                };
+                emit_progress(&shared_config, ProgressStage::Started, &url);
+                let entry_result =
+                    match fetch_html(&url, &client, shared_config.netrc.as_ref()).await {
+                        Ok(resource) => Ok(task::spawn_blocking(move || {
+                            CatalogEntry::from_html(&resource.html, &resource.final_url)
+                        })
+                        .await),
+                        Err(e) => Err(e),
+                    };
+                (url, entry_result)
+            })
+        });
+        let mut responses = stream::from_iter(urls_iter).buffered(app_config.max_conn);
+        let mut entries = Vec::new();
+        while let Some((url, entry_result)) = responses.next().await {
+            match entry_result {
+                Ok(entry) => {
+                    emit_progress(app_config, ProgressStage::Extracted, &url);
+                    entries.push(entry);
+                }
+                Err(e) => {
+                    emit_failed(app_config, &url, &e);
+                    errors.push(e);
+                }
+            }
+            bar.inc(1);
+        }
+        entries
+    })
+}
+
+/// Fetches listing pages, such as a blog's homepage, and collects the article links in them.
+/// The links found at each level are treated as listing pages until `crawl_depth` levels
+/// have been followed.
+pub fn collect_listing_urls(
+    app_config: &AppConfig,
+    errors: &mut Vec<PaperoniError>,
+) -> Vec<String> {
+    let selector = app_config
+        .link_selector
+        .as_deref()
+        .unwrap_or(DEFAULT_LINK_SELECTOR);
+    task::block_on(async {
+        let client = surf::Client::new();
+        let mut urls = app_config.urls.clone();
+        for _ in 0..app_config.crawl_depth {
+            let urls_iter = urls
+                .iter()
+                .map(|url| fetch_html(url, &client, app_config.netrc.as_ref()));
+            let mut responses = stream::from_iter(urls_iter).buffered(app_config.max_conn);
+            let mut article_urls = Vec::new();
+            while let Some(fetch_result) = responses.next().await {
+                match fetch_result {
+                    Ok(resource) => {
+                        let links =
+                            extract_listing_links(&resource.html, &resource.final_url, selector);
+                        info!(
+                            "Found {} article links in {}",
+                            links.len(),
+                            resource.final_url
+                        );
+                        article_urls.extend(links);
+                    }
+                    Err(e) => errors.push(e),
+                }
+            }
+            // The responses borrow the URLs of this level so they are dropped before the
+            // URLs are replaced with the links found on them
+            drop(responses);
+            urls = article_urls.into_iter().unique().collect();
+        }
+        urls
+    })
+}
+
+/// Returns the absolute URLs of the links in a listing page matched by `selector`
+fn extract_listing_links(html: &str, page_url: &str, selector: &str) -> Vec<String> {
+    let page_url = match Url::parse(page_url) {
+        Ok(url) => url,
+        Err(_) => return Vec::new(),
+    };
+    let doc = kuchiki::parse_html().one(html);
+    let links = match doc.select(selector) {
+        Ok(links) => links,
+        Err(_) => return Vec::new(),
+    };
+    links
+        .filter_map(|link_ref| {
+            let attrs = link_ref.attributes.borrow();
+            attrs.get("href").and_then(|href| page_url.join(href).ok())
+        })
+        .filter(|url| url.scheme() == "http" || url.scheme() == "https")
+        .map(|mut url| {
+            url.set_fragment(None);
+            url
+        })
+        .filter(|url| url != &page_url)
+        .map(String::from)
+        .unique()
+        .collect()
+}
+
+/// Fetches a sitemap and collects the URLs of the pages it lists. Sitemap index files are followed
+/// to their child sitemaps. Only URLs matching the sitemap filter are kept and no more than the
+/// sitemap limit are collected. With `--last`, pages and child sitemaps last modified before the
+/// time window are skipped.
+pub fn collect_sitemap_urls(
+    sitemap_url: &str,
+    app_config: &AppConfig,
+    errors: &mut Vec<PaperoniError>,
+) -> Vec<String> {
+    let url_filter = app_config
+        .sitemap_filter
+        .as_deref()
+        .map(|filter| regex::Regex::new(filter).expect("Sitemap filter was already validated"));
+    let limit = app_config.sitemap_limit.unwrap_or(usize::MAX);
+    let since = app_config
+        .sitemap_since
+        .map(|since| since.with_timezone(&Utc));
+    task::block_on(async {
+        let client = surf::Client::new();
+        let mut sitemap_urls = VecDeque::new();
+        sitemap_urls.push_back(sitemap_url.to_owned());
+        let mut fetched_sitemaps = HashSet::new();
+        let mut urls = Vec::new();
+        while let Some(sitemap_url) = sitemap_urls.pop_front() {
+            if urls.len() >= limit || fetched_sitemaps.len() >= MAX_SITEMAPS {
+                break;
+            }
+            if !fetched_sitemaps.insert(sitemap_url.clone()) {
+                continue;
+            }
+            let sitemap = match fetch_sitemap(&sitemap_url, &client).await {
+                Ok(sitemap) => sitemap,
+                Err(mut e) => {
+                    e.set_article_source(&sitemap_url);
+                    errors.push(e);
+                    continue;
+                }
+            };
+            let (is_index, locs) = parse_sitemap(&sitemap, since);
+            info!("Found {} urls in {}", locs.len(), sitemap_url);
+            if is_index {
+                sitemap_urls.extend(locs);
+                continue;
+            }
+            for url in locs {
+                if urls.len() >= limit {
+                    break;
+                }
+                let is_match = url_filter
+                    .as_ref()
+                    .map_or(true, |filter| filter.is_match(&url));
+                if is_match && !urls.contains(&url) {
+                    urls.push(url);
+                }
+            }
+        }
+        urls
+    })
+}
+
+async fn fetch_sitemap(url: &str, client: &surf::Client) -> Result<String, PaperoniError> {
+    debug!("Fetching sitemap {}", url);
+    let mut res = client
+        .get(url)
+        .middleware(surf::middleware::Redirect::default())
+        .await?;
+    if !res.status().is_success() {
+        return Err(ErrorKind::HTTPError(format!(
+            "Non-success HTTP status code ({})",
+            res.status()
+        ))
+        .into());
+    }
+    Ok(res.body_string().await?)
+}
+
+/// Returns whether the sitemap is a sitemap index and the URLs in its `<loc>` elements. Entries
+/// whose `<lastmod>` is before `since` are left out. Entries without a `<lastmod>` are kept
+/// since there is no telling when they changed.
+fn parse_sitemap(sitemap: &str, since: Option<DateTime<Utc>>) -> (bool, Vec<String>) {
+    let is_index = sitemap.contains("<sitemapindex");
+    let is_recent = |entry: &str| match since {
+        Some(since) => SITEMAP_LASTMOD_REGEX
+            .captures(entry)
+            .and_then(|captures| parse_lastmod(&captures[1]))
+            .map_or(true, |lastmod| lastmod >= since),
+        None => true,
+    };
+    let locs = SITEMAP_ENTRY_REGEX
+        .find_iter(sitemap)
+        .map(|entry| entry.as_str())
+        .filter(|entry| is_recent(entry))
+        .filter_map(|entry| SITEMAP_LOC_REGEX.captures(entry))
+        .map(|captures| {
+            captures[1]
+                .trim()
+                .replace("&lt;", "<")
+                .replace("&gt;", ">")
+                .replace("&quot;", "\"")
+                .replace("&apos;", "'")
+                .replace("&amp;", "&")
+        })
+        .filter(|loc| !loc.is_empty())
+        .collect();
+    (is_index, locs)
+}
+
+/// Parses the W3C datetime of a `<lastmod>` element. Dates without a time are taken as
+/// midnight UTC.
+fn parse_lastmod(lastmod: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(lastmod)
+        .or_else(|_| DateTime::parse_from_str(lastmod, "%Y-%m-%dT%H:%M%:z"))
+        .map(|lastmod| lastmod.with_timezone(&Utc))
+        .ok()
+        .or_else(|| {
+            NaiveDate::parse_from_str(lastmod, "%Y-%m-%d")
+                .ok()
+                .map(|date| DateTime::from_utc(date.and_hms(0, 0, 0), Utc))
+        })
+}
+
+/// Limits the number of concurrent requests made to each host. The permits of a host are held
+/// in a bounded channel so that acquiring one waits until another request to the host is done.
+struct HostPermits {
+    permits: HashMap<String, (Sender<()>, Receiver<()>)>,
+}
+
+impl HostPermits {
+    fn new(urls: &[String], limit: usize) -> Self {
+        let mut permits = HashMap::new();
+        for host in urls.iter().filter_map(|url| get_host(url)) {
+            permits.entry(host).or_insert_with(|| {
+                let (sender, receiver) = channel::bounded(limit);
+                for _ in 0..limit {
+                    sender
+                        .try_send(())
+                        .expect("Unable to add permit to an empty channel");
+                }
+                (sender, receiver)
+            });
+        }
+        Self { permits }
+    }
+
+    /// Waits for a permit to make a request to the host of the URL. The permit is returned
+    /// when it is dropped.
+    async fn acquire(&self, url: &str) -> Option<HostPermit<'_>> {
+        let (sender, receiver) = self.permits.get(&get_host(url)?)?;
+        receiver.recv().await.ok()?;
+        Some(HostPermit { sender })
+    }
+}
+
+struct HostPermit<'a> {
+    sender: &'a Sender<()>,
+}
+
+impl Drop for HostPermit<'_> {
+    fn drop(&mut self) {
+        let _ = self.sender.try_send(());
+    }
+}
+
+fn get_host(url: &str) -> Option<String> {
+    Url::parse(url)
+        .ok()?
+        .host_str()
+        .map(|host| host.to_lowercase())
+}
+
+/// Fetches an article and extracts its content on a blocking thread. This allows the extraction of
+/// large articles to be spread across multiple cores while other articles are still downloading.
+/// The fetched HTML and the time each step took are returned alongside the extracted article.
+async fn fetch_and_extract(
+    url: &str,
+    client: &surf::Client,
+    browser: Option<&str>,
+    readability_options: ReadabilityOptions,
+    app_config: &AppConfig,
+) -> Result<(ExtractedArticle, HTMLResource, ArticleTimings), PaperoniError> {
+    let download_start = Instant::now();
+    // Social posts are rendered with JavaScript so their threads are downloaded with the
+    // site's API instead of extracting the page
+    let thread_html = match MastodonStatus::from_url(url) {
+        Some(status) => match status.fetch_thread(url, client).await {
+            Ok(resource) => Some(resource),
+            Err(e) => {
+                warn!(
+                    "Unable to download {} as a Mastodon thread. Downloading it as a page: {}",
+                    url, e
+                );
+                None
+            }
+        },
+        None => None,
+    };
+    let rendered_html = match browser {
+        Some(browser) if thread_html.is_none() => {
+            match render_html(url, browser, app_config.proxy.as_deref()).await {
+                Ok(html) => Some(HTMLResource {
+                    final_url: url.to_owned(),
+                    status: None,
+                    html,
+                }),
+                Err(e) => {
+                    warn!(
+                        "Unable to render {}. Downloading it without rendering: {}",
+                        url, e
+                    );
+                    None
+                }
+            }
+        }
+        _ => None,
+    };
+    let resource = match thread_html.or(rendered_html) {
+        Some(resource) => resource,
+        None => fetch_html(url, client, app_config.netrc.as_ref()).await?,
+    };
+    emit_progress(app_config, ProgressStage::Downloaded, url);
+    let download = download_start.elapsed();
+    task::spawn_blocking(move || {
+        debug!("Extracting {}", &resource.final_url);
+        let extraction_start = Instant::now();
+        match ExtractedArticle::extract(&resource.html, &resource.final_url, readability_options) {
+            Ok(extracted) => {
+                let timings = ArticleTimings {
+                    download,
+                    extraction: extraction_start.elapsed(),
+                };
+                Ok((extracted, resource, timings))
+            }
+            Err(mut error) => {
+                error.set_article_source(&resource.final_url);
+                Err(error)
+            }
+        }
+    })
+    .await
+}
+
+/// Returns the browser used to render articles. The given browser is used if it exists,
+/// otherwise the PATH is searched for a Chromium based browser.
+fn find_browser(browser: Option<&str>) -> Option<String> {
+    if let Some(browser) = browser {
+        return if std::path::Path::new(browser).is_file() {
+            Some(browser.to_owned())
+        } else {
+            find_in_path(&[browser])
+        };
+    }
+    find_in_path(&BROWSER_NAMES)
+}
+
+fn find_in_path(names: &[&str]) -> Option<String> {
+    let paths = std::env::var_os("PATH")?;
+    std::env::split_paths(&paths)
+        .flat_map(|dir| names.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+        .map(|path| path.to_string_lossy().into_owned())
+}
+
+/// Loads an article in a headless browser and returns its DOM once its scripts have run
+async fn render_html(
+    url: &str,
+    browser: &str,
+    proxy: Option<&str>,
+) -> Result<String, PaperoniError> {
+    debug!("Rendering {} with {}", url, browser);
+    let mut args = vec![
+        "--headless".to_owned(),
+        "--disable-gpu".to_owned(),
+        "--no-first-run".to_owned(),
+        format!("--virtual-time-budget={}", RENDER_TIME_BUDGET_MS),
+        "--dump-dom".to_owned(),
+    ];
+    if let Some(proxy) = proxy {
+        args.extend(browser_proxy_args(proxy));
+    }
+    args.push(url.to_owned());
+    let browser = browser.to_owned();
+    let output =
+        task::spawn_blocking(move || std::process::Command::new(browser).args(args).output())
+            .await?;
+    if !output.status.success() {
+        return Err(ErrorKind::IOError(format!(
+            "The browser exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))
+        .into());
+    }
+    let html = String::from_utf8(output.stdout).map_err(|err| err.utf8_error())?;
+    if html.trim().is_empty() {
+        return Err(ErrorKind::IOError("The browser returned an empty page".to_owned()).into());
+    }
+    Ok(html)
+}
+
+/// Sends the requests of the HTTP client through the proxy. The client is built on curl which
+/// reads the proxy from these environment variables, so this has to be called before any request
+/// is made. The variables take precedence over the proxy settings of the user's environment.
+pub fn use_proxy(proxy: &str) {
+    if proxy.starts_with("socks5://") || proxy.starts_with("socks4://") {
+        warn!(
+            "Hostnames are resolved locally when using {}. Use socks5h:// to resolve them through the proxy",
+            proxy
+        );
+    }
+    for var in &["http_proxy", "https_proxy", "HTTPS_PROXY", "ALL_PROXY"] {
+        std::env::set_var(var, proxy);
+    }
+    std::env::remove_var("no_proxy");
+    std::env::remove_var("NO_PROXY");
+}
+
+/// Returns the arguments sending the browser's requests through the proxy. Chromium does not know
+/// the socks5h scheme but resolves hostnames through SOCKS5 proxies, and its own lookups such as
+/// DNS prefetching are disabled so that they cannot leak outside the proxy.
+fn browser_proxy_args(proxy: &str) -> Vec<String> {
+    if let Some(address) = proxy.strip_prefix("socks5h://") {
+        let host = address
+            .rsplit('@')
+            .next()
+            .and_then(|host_port| host_port.rsplitn(2, ':').last())
+            .unwrap_or(address);
+        vec![
+            format!("--proxy-server=socks5://{}", address),
+            format!("--host-resolver-rules=MAP * ~NOTFOUND , EXCLUDE {}", host),
+        ]
+    } else {
+        vec![format!("--proxy-server={}", proxy)]
+    }
+}
+
+/// Returns the path of a file in the output directory named after the article's title like
+/// the exported files
+fn article_file_name(article: &Article, extension: &str, app_config: &AppConfig) -> String {
+    format!(
+        "{}/{}.{}",
+        app_config.output_directory.as_deref().unwrap_or("."),
+        article.file_stem(app_config.is_including_url_in_title),
+        extension
+    )
+}
+
+/// Writes the fetched HTML of an article to the output directory
+fn save_raw_html(article: &Article, raw_html: &str, app_config: &AppConfig) {
+    let file_name = article_file_name(article, "raw.html", app_config);
+    match std::fs::write(&file_name, raw_html) {
+        Ok(_) => debug!("Saved the HTML of {} to {:?}", article.url, file_name),
+        Err(e) => warn!(
+            "Unable to save the HTML of {} to {:?}: {}",
+            article.url, file_name, e
+        ),
+    }
+}
+
+/// Writes the metadata of an article as JSON to the output directory
+fn save_sidecar(article: &Article, app_config: &AppConfig) {
+    let file_name = article_file_name(article, "json", app_config);
+    let save_result = serde_json::to_string_pretty(&article.sidecar())
+        .map_err(|e| e.to_string())
+        .and_then(|json| std::fs::write(&file_name, json).map_err(|e| e.to_string()));
+    match save_result {
+        Ok(_) => debug!("Saved the metadata of {} to {:?}", article.url, file_name),
+        Err(e) => warn!(
+            "Unable to save the metadata of {} to {:?}: {}",
+            article.url, file_name, e
+        ),
+    }
+}
+
+/// Fetches the HTML of a page, following redirects. Credentials from the netrc file are only
+/// sent to the hosts they are listed for, including the hosts redirected to.
+pub async fn fetch_html(
+    url: &str,
+    client: &surf::Client,
+    netrc: Option<&Netrc>,
+) -> Result<HTMLResource, PaperoniError> {
     debug!("Fetching {}", url);
 
     let process_request = async {
         let mut redirect_count: u8 = 0;
+        let mut rate_limit_retries: u8 = 0;
+        let mut retry_after_wait = Duration::from_secs(0);
         let base_url = Url::parse(&url)?;
         let mut url = base_url.clone();
         while redirect_count < 5 {
-            redirect_count += 1;
-            let req = surf::get(&url);
+            let mut req = surf::get(&url);
+            if let Some(authorization) = netrc.and_then(|netrc| netrc.authorization(&url)) {
+                req = req.header(surf::http::headers::AUTHORIZATION, authorization);
+            }
             let mut res = client.send(req).await?;
             if res.status().is_redirection() {
+                redirect_count += 1;
                 if let Some(location) = res.header(surf::http::headers::LOCATION) {
                     match Url::parse(location.last().as_str()) {
                         Ok(valid_url) => {
@@ -104,7 +900,11 @@ pub async fn fetch_html(url: &str) -> Result<HTMLResource, PaperoniError> {
                 if let Some(mime) = res.content_type() {
                     if mime.essence() == "text/html" {
                         debug!("Successfully fetched {}", url);
-                        return Ok((url.to_string(), res.body_string().await?));
+                        return Ok(HTMLResource {
+                            final_url: url.to_string(),
+                            status: Some(res.status() as u16),
+                            html: res.body_string().await?,
+                        });
                     } else {
                         let msg = format!(
                             "Invalid HTTP response. Received {} instead of text/html",
@@ -117,8 +917,33 @@ pub async fn fetch_html(url: &str) -> Result<HTMLResource, PaperoniError> {
                     return Err(ErrorKind::HTTPError("Unknown HTTP response".to_owned()).into());
                 }
             } else {
-                let msg = format!("Request failed: HTTP {}", res.status());
-                return Err(ErrorKind::HTTPError(msg).into());
+                let retry_after = match res.status() {
+                    surf::StatusCode::TooManyRequests | surf::StatusCode::ServiceUnavailable
+                        if rate_limit_retries < MAX_RATE_LIMIT_RETRIES =>
+                    {
+                        res.header(surf::http::headers::RETRY_AFTER)
+                            .and_then(|value| parse_retry_after(value.last().as_str(), Utc::now()))
+                            .filter(|delay| retry_after_wait + *delay <= MAX_RETRY_AFTER_WAIT)
+                    }
+                    _ => None,
+                };
+                match retry_after {
+                    Some(delay) => {
+                        rate_limit_retries += 1;
+                        retry_after_wait += delay;
+                        info!(
+                            "{} responded with HTTP {}. Retrying after {} seconds",
+                            url,
+                            res.status(),
+                            delay.as_secs()
+                        );
+                        task::sleep(delay).await;
+                    }
+                    None => {
+                        let msg = format!("Request failed: HTTP {}", res.status());
+                        return Err(ErrorKind::HTTPError(msg).into());
+                    }
+                }
             }
         }
         Err(ErrorKind::HTTPError("Unable to fetch HTML".to_owned()).into())
@@ -130,6 +955,19 @@ pub async fn fetch_html(url: &str) -> Result<HTMLResource, PaperoniError> {
     })
 }
 
+/// Parses the value of a Retry-After header which is either a number of seconds or an HTTP date
+fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    match value.parse::<u64>() {
+        Ok(secs) => Some(Duration::from_secs(secs)),
+        Err(_) => DateTime::parse_from_rfc2822(value).ok().map(|retry_date| {
+            (retry_date.with_timezone(&Utc) - now)
+                .to_std()
+                .unwrap_or_else(|_| Duration::from_secs(0))
+        }),
+    }
+}
+
 type ImgItem<'a> = (&'a str, String, Option<String>);
 
 async fn process_img_response<'a>(
@@ -147,25 +985,24 @@ async fn process_img_response<'a>(
         Ok(bytes) => bytes,
         Err(e) => return Err(e.into()),
     };
-    let img_mime = img_response
+    // SVG images are often served with a generic XML or binary Content-Type so the content
+    // is checked before rejecting the response
+    let img_mime = match img_response
         .content_type()
-        .map(|mime| mime.essence().to_string());
-    if let Some(mime_str) = &img_mime {
-        if !mime_str.starts_with("image/") {
+        .map(|mime| mime.essence().to_string())
+    {
+        Some(mime_str) if mime_str.starts_with("image/") => mime_str,
+        _ if is_svg(&img_content) => "image/svg+xml".to_owned(),
+        Some(mime_str) => {
             return Err(ErrorKind::HTTPError(format!(
                 "Invalid image MIME type: {} for {}",
                 mime_str, url
             ))
-            .into());
+            .into())
         }
-    }
-    let img_ext = match img_response
-        .content_type()
-        .map(|mime| map_mime_subtype_to_ext(mime.subtype()).to_string())
-    {
-        Some(mime_str) => mime_str,
         None => return Err(ErrorKind::HTTPError("Image has no Content-Type".to_owned()).into()),
     };
+    let img_ext = map_mime_subtype_to_ext(img_mime.trim_start_matches("image/")).to_string();
 
     let mut img_path = std::env::temp_dir();
     img_path.push(format!("{}.{}", hash_url(url), &img_ext));
@@ -189,14 +1026,116 @@ async fn process_img_response<'a>(
                     .to_string()
             })
             .unwrap(),
-        img_mime,
+        Some(img_mime),
     ))
 }
 
+/// Checks whether the content is an SVG document by skipping the XML declaration, comments
+/// and doctype that may come before the root element
+fn is_svg(content: &[u8]) -> bool {
+    let head = String::from_utf8_lossy(&content[..content.len().min(1024)]);
+    let mut head = head.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        let prolog_end = if head.starts_with("<?") {
+            head.find("?>").map(|idx| idx + 2)
+        } else if head.starts_with("<!--") {
+            head.find("-->").map(|idx| idx + 3)
+        } else if head.starts_with("<!") {
+            head.find('>').map(|idx| idx + 1)
+        } else {
+            break;
+        };
+        match prolog_end {
+            Some(idx) => head = head[idx..].trim_start(),
+            None => return false,
+        }
+    }
+    head.starts_with("<svg")
+}
+
+/// Decodes an image embedded in a data URL and stores it like a downloaded image so that it
+/// is added to the export as a resource without making any request
+async fn process_data_url(url: &str) -> Result<ImgItem<'_>, ImgError> {
+    let (img_mime, img_content) = match decode_data_url(url) {
+        Some(decoded) => decoded,
+        None => return Err(ErrorKind::HTTPError("Invalid data URL".to_owned()).into()),
+    };
+    if !img_mime.starts_with("image/") {
+        return Err(ErrorKind::HTTPError(format!("Invalid image MIME type: {}", img_mime)).into());
+    }
+    let img_ext = map_mime_subtype_to_ext(img_mime.trim_start_matches("image/")).to_string();
+
+    let mut img_path = std::env::temp_dir();
+    img_path.push(format!("{}.{}", hash_url(url), &img_ext));
+    let mut img_file = File::create(&img_path).await?;
+    img_file.write_all(&img_content).await?;
+
+    Ok((
+        url,
+        format!("{}.{}", hash_url(url), img_ext),
+        Some(img_mime),
+    ))
+}
+
+/// Splits a data URL into its MIME type and decoded content.
+/// The MIME type defaults to text/plain when the URL does not specify one.
+fn decode_data_url(url: &str) -> Option<(String, Vec<u8>)> {
+    let mut parts = url.strip_prefix("data:")?.splitn(2, ',');
+    let (media_type, data) = (parts.next()?, parts.next()?);
+    let mime = media_type
+        .split(';')
+        .next()
+        .map(str::trim)
+        .filter(|mime| !mime.is_empty())
+        .unwrap_or("text/plain")
+        .to_lowercase();
+    let content = percent_decode(data);
+    if media_type
+        .split(';')
+        .skip(1)
+        .any(|param| param.trim() == "base64")
+    {
+        let content: Vec<u8> = content
+            .into_iter()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+        base64::decode(content).ok().map(|content| (mime, content))
+    } else {
+        Some((mime, content))
+    }
+}
+
+fn percent_decode(data: &str) -> Vec<u8> {
+    let bytes = data.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let escaped_byte = if bytes[idx] == b'%' {
+            data.get(idx + 1..idx + 3)
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        } else {
+            None
+        };
+        match escaped_byte {
+            Some(byte) => {
+                decoded.push(byte);
+                idx += 3;
+            }
+            None => {
+                decoded.push(bytes[idx]);
+                idx += 1;
+            }
+        }
+    }
+    decoded
+}
+
 pub async fn download_images(
     extractor: &mut Article,
     article_origin: &Url,
     bar: &ProgressBar,
+    client: &surf::Client,
+    app_config: &AppConfig,
 ) -> Result<(), Vec<ImgError>> {
     if extractor.img_urls.len() > 0 {
         debug!(
@@ -206,70 +1145,473 @@ pub async fn download_images(
         );
     }
     let img_count = extractor.img_urls.len();
+    let image_timeout = app_config.image_timeout;
 
-    let imgs_req_iter = extractor
-        .img_urls
-        .iter()
-        .map(|(url, _)| {
-            (
-                url,
-                surf::Client::new()
-                    .with(surf::middleware::Redirect::default())
-                    .get(get_absolute_url(&url, article_origin)),
-            )
-        })
-        .enumerate()
-        .map(|(img_idx, (url, req))| async move {
-            bar.set_message(format!(
-                "Downloading images [{}/{}]",
-                img_idx + 1,
-                img_count
-            ));
-            match req.await {
-                Ok(mut img_response) => {
-                    let process_response =
-                        process_img_response(&mut img_response, url.as_ref()).await;
-                    process_response.map_err(|mut e: ImgError| {
-                        e.set_url(url);
-                        e
-                    })
-                }
-                Err(e) => {
-                    let mut img_err: ImgError = e.into();
-                    img_err.set_url(url);
-                    Err(img_err)
+    let imgs_req_iter =
+        extractor
+            .img_urls
+            .iter()
+            .enumerate()
+            .map(|(img_idx, (url, _))| async move {
+                bar.set_message(format!(
+                    "Downloading images [{}/{}]",
+                    img_idx + 1,
+                    img_count
+                ));
+                let download_img = async {
+                    if url.starts_with("data:") {
+                        return process_data_url(url).await.map_err(|mut e: ImgError| {
+                            e.set_url(url);
+                            e
+                        });
+                    }
+                    let img_url = get_absolute_url(&url, article_origin);
+                    if is_mixed_content(&img_url, article_origin) {
+                        match app_config.insecure_images {
+                            Some(InsecureImages::Skip) => {
+                                let mut img_err =
+                                    ImgError::with_kind(ErrorKind::InsecureImageError(
+                                        "Skipped insecure image in a HTTPS article".to_owned(),
+                                    ));
+                                img_err.set_url(url);
+                                return Err(img_err);
+                            }
+                            Some(InsecureImages::Upgrade) => {
+                                let secure_url = img_url.replacen("http://", "https://", 1);
+                                match build_img_request(client, &secure_url, app_config).await {
+                                    Ok(mut img_response) if img_response.status().is_success() => {
+                                        return process_img_response(
+                                            &mut img_response,
+                                            url.as_ref(),
+                                        )
+                                        .await
+                                        .map_err(
+                                            |mut e: ImgError| {
+                                                e.set_url(url);
+                                                e
+                                            },
+                                        );
+                                    }
+                                    _ => warn!(
+                                        "Unable to upgrade {} to HTTPS. Falling back to HTTP",
+                                        img_url
+                                    ),
+                                }
+                            }
+                            None => (),
+                        }
+                    }
+                    match build_img_request(client, &img_url, app_config).await {
+                        Ok(mut img_response) => {
+                            let process_response =
+                                process_img_response(&mut img_response, url.as_ref()).await;
+                            process_response.map_err(|mut e: ImgError| {
+                                e.set_url(url);
+                                e
+                            })
+                        }
+                        Err(e) => {
+                            let mut img_err: ImgError = e.into();
+                            img_err.set_url(url);
+                            Err(img_err)
+                        }
+                    }
+                };
+                match image_timeout {
+                    Some(duration) => async_std::future::timeout(duration, download_img)
+                        .await
+                        .unwrap_or_else(|_| {
+                            let mut img_err =
+                                ImgError::with_kind(ErrorKind::TimeoutError(format!(
+                                    "Image download took longer than {} seconds",
+                                    duration.as_secs()
+                                )));
+                            img_err.set_url(url);
+                            Err(img_err)
+                        }),
+                    None => download_img.await,
                 }
-            }
-        });
+            });
 
     // A utility closure used when update the value of an image source after downloading is successful
     let replace_existing_img_src = |img_item: ImgItem| -> (String, Option<String>) {
         let (img_url, img_path, img_mime) = img_item;
-        let img_ref = extractor
-            .node_ref()
-            .select_first(&format!("img[src='{}']", img_url))
-            .expect("Image node does not exist");
-        let mut img_node = img_ref.attributes.borrow_mut();
-        *img_node.get_mut("src").unwrap() = img_path.clone();
-        // srcset is removed because readers such as Foliate then fail to display
-        // the image already downloaded and stored in src
-        img_node.remove("srcset");
+        // The nodes are matched by comparing attributes instead of using a selector because
+        // data URLs can contain quotes that would break the selector
+        let img_refs = select_imgs_with_src(extractor.node_ref(), img_url);
+        assert!(!img_refs.is_empty(), "Image node does not exist");
+        for img_ref in img_refs {
+            let mut img_node = img_ref.attributes.borrow_mut();
+            *img_node.get_mut("src").unwrap() = img_path.clone();
+            // srcset is removed because readers such as Foliate then fail to display
+            // the image already downloaded and stored in src
+            img_node.remove("srcset");
+        }
         (img_path, img_mime)
     };
 
     let imgs_req_iter = stream::from_iter(imgs_req_iter)
-        .buffered(10)
+        .buffered(app_config.image_concurrency)
         .collect::<Vec<Result<_, ImgError>>>()
         .await;
     let mut errors = Vec::new();
     let mut replaced_imgs = Vec::new();
     for img_req_result in imgs_req_iter {
         match img_req_result {
-            Ok(img_req) => replaced_imgs.push(replace_existing_img_src(img_req)),
+            Ok(img_req) if is_below_min_size(&img_req, app_config.min_image_size) => {
+                debug!(
+                    "Removing image {} as it is below the minimum size",
+                    img_req.0
+                );
+                for img_ref in select_imgs_with_src(extractor.node_ref(), img_req.0) {
+                    img_ref.as_node().detach();
+                }
+            }
+            Ok(mut img_req) => {
+                if let Some(target) = app_config.image_format_target.clone() {
+                    let file_name = img_req.1.clone();
+                    // Decoding and encoding images is CPU bound
+                    match task::spawn_blocking(move || convert_image(&file_name, &target)).await {
+                        Ok(Some((file_name, mime))) => {
+                            img_req.1 = file_name;
+                            img_req.2 = Some(mime.to_owned());
+                        }
+                        Ok(None) => (),
+                        Err(e) => warn!("Unable to convert image {}: {}", img_req.0, e),
+                    }
+                }
+                replaced_imgs.push(replace_existing_img_src(img_req))
+            }
             Err(e) => errors.push(e),
         }
     }
     extractor.img_urls = replaced_imgs;
+    for error in &errors {
+        match (error.kind(), error.url()) {
+            (ErrorKind::TimeoutError(_), Some(img_url))
+            | (ErrorKind::InsecureImageError(_), Some(img_url)) => replace_img_with_alt_text(
+                extractor.node_ref(),
+                img_url,
+                app_config.is_keeping_aspect_placeholders,
+            ),
+            _ => (),
+        }
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Downloads the favicon of the article's site and adds it as a marker at the start of the
+/// content. The favicon declared by the page is used, falling back to /favicon.ico. Favicons
+/// that fail to download are left out without reporting the article as partially downloaded.
+async fn add_favicon(
+    article: &mut Article,
+    client: &surf::Client,
+    app_config: &AppConfig,
+    favicons: &mut HashMap<String, Option<ResourceInfo>>,
+) {
+    let article_url = match Url::parse(&article.url) {
+        Ok(article_url) => article_url,
+        Err(_) => return,
+    };
+    let host = match get_host(&article.url) {
+        Some(host) => host,
+        None => return,
+    };
+    if !favicons.contains_key(&host) {
+        let favicon_url = article
+            .metadata()
+            .favicon()
+            .and_then(|href| article_url.join(href).ok())
+            .or_else(|| article_url.join("/favicon.ico").ok());
+        let favicon = match favicon_url {
+            Some(favicon_url) => {
+                match download_favicon(favicon_url.as_str(), client, app_config).await {
+                    Ok(favicon) => Some(favicon),
+                    Err(e) => {
+                        debug!("Unable to download the favicon of {}: {}", host, e);
+                        None
+                    }
+                }
+            }
+            None => None,
+        };
+        favicons.insert(host.clone(), favicon);
+    }
+    if let Some(favicon) = &favicons[&host] {
+        article.insert_favicon(&favicon.0);
+        if !article.img_urls.contains(favicon) {
+            article.img_urls.push(favicon.clone());
+        }
+    }
+}
+
+/// Downloads the image of the article's metadata, such as its og:image, to be used as the cover
+/// of its EPUB. Like favicons, covers that fail to download are left out without reporting the
+/// article as partially downloaded.
+async fn add_cover(article: &mut Article, client: &surf::Client, app_config: &AppConfig) {
+    let cover_url = match article.metadata().lead_image().and_then(|lead_image| {
+        Url::parse(&article.url)
+            .and_then(|article_url| article_url.join(lead_image))
+            .ok()
+    }) {
+        Some(cover_url) => cover_url,
+        None => return,
+    };
+    let download_cover = async {
+        let mut cover_response = build_img_request(client, cover_url.as_str(), app_config).await?;
+        let (_, file_name, mime) =
+            process_img_response(&mut cover_response, cover_url.as_str()).await?;
+        Ok::<_, ImgError>((file_name, mime))
+    };
+    match download_cover.await {
+        Ok(cover) => article.cover = Some(cover),
+        Err(e) => debug!("Unable to download the cover of {}: {}", article.url, e),
+    }
+}
+
+/// Renders a QR code of the article's URL to an SVG image in the temp directory and adds it to
+/// the end of the content so that printed copies can be traced back to the source
+fn add_qr_code(article: &mut Article) -> Result<(), ImgError> {
+    let to_img_error = |message: String| {
+        let mut img_error = ImgError::with_kind(ErrorKind::IOError(message));
+        img_error.set_url(&article.url);
+        img_error
+    };
+    let qr_svg = qrcode::QrCode::new(article.url.as_bytes())
+        .map_err(|err| to_img_error(format!("Unable to encode the URL: {}", err)))?
+        .render::<qrcode::render::svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    let file_name = format!("qr_{}.svg", hash_url(&article.url));
+    std::fs::write(std::env::temp_dir().join(&file_name), qr_svg)
+        .map_err(|err| to_img_error(err.to_string()))?;
+    article.insert_qr_code(&file_name);
+    article
+        .img_urls
+        .push((file_name, Some("image/svg+xml".to_owned())));
+    Ok(())
+}
+
+async fn download_favicon(
+    url: &str,
+    client: &surf::Client,
+    app_config: &AppConfig,
+) -> Result<ResourceInfo, ImgError> {
+    let too_large_error = || {
+        ImgError::from(ErrorKind::HTTPError(format!(
+            "{} is larger than the maximum size of {} bytes",
+            url, MAX_FAVICON_SIZE
+        )))
+    };
+    let mut favicon_response = build_img_request(client, url, app_config).await?;
+    if favicon_response.len().unwrap_or(0) as u64 > MAX_FAVICON_SIZE {
+        return Err(too_large_error());
+    }
+    let (_, file_name, mime) = process_img_response(&mut favicon_response, url).await?;
+    // The Content-Length header is missing from chunked responses so the size of the saved
+    // file is checked as well
+    let file_path = std::env::temp_dir().join(&file_name);
+    if std::fs::metadata(&file_path)?.len() > MAX_FAVICON_SIZE {
+        let _ = std::fs::remove_file(&file_path);
+        return Err(too_large_error());
+    }
+    Ok((file_name, mime))
+}
+
+fn build_img_request(
+    client: &surf::Client,
+    img_url: &str,
+    app_config: &AppConfig,
+) -> surf::RequestBuilder {
+    let mut req = client
+        .get(img_url)
+        .middleware(surf::middleware::Redirect::default());
+    if let Some(user_agent) = app_config.image_user_agent.as_deref() {
+        req = req.header(surf::http::headers::USER_AGENT, user_agent);
+    }
+    req
+}
+
+/// Checks whether an image is served over HTTP in an article served over HTTPS.
+fn is_mixed_content(img_url: &str, article_origin: &Url) -> bool {
+    article_origin.scheme() == "https"
+        && Url::parse(img_url).map_or(false, |img_url| img_url.scheme() == "http")
+}
+
+/// Downloads the PDFs linked from an article so that they can be attached to it.
+/// Failures are reported as [ImgError]s since, like images, they are recoverable.
+pub async fn download_linked_pdfs(
+    extractor: &mut Article,
+    bar: &ProgressBar,
+    client: &surf::Client,
+) -> Result<(), Vec<ImgError>> {
+    let pdf_urls = extractor.linked_pdf_urls();
+    download_attachments(
+        extractor,
+        pdf_urls,
+        "linked PDFs",
+        bar,
+        client,
+        None,
+        |_, _| ("pdf".to_owned(), "application/pdf".to_owned()),
+    )
+    .await
+}
+
+/// Downloads the audio played or linked in an article and attaches it to the article. Audio
+/// files larger than [MAX_AUDIO_SIZE] are not downloaded.
+pub async fn download_audio(
+    extractor: &mut Article,
+    bar: &ProgressBar,
+    client: &surf::Client,
+) -> Result<(), Vec<ImgError>> {
+    let audio_urls = extractor.audio_urls();
+    download_attachments(
+        extractor,
+        audio_urls,
+        "audio",
+        bar,
+        client,
+        Some(MAX_AUDIO_SIZE),
+        audio_file_info,
+    )
+    .await
+}
+
+/// Returns the file extension and media type of an audio file from its URL and the media type
+/// it was served with
+fn audio_file_info(url: &str, mime: Option<&str>) -> (String, String) {
+    let url_ext = Url::parse(url).ok().and_then(|url| {
+        url.path()
+            .rsplit('/')
+            .next()
+            .and_then(|file_name| file_name.rsplit_once('.'))
+            .map(|(_, ext)| ext.to_lowercase())
+    });
+    match mime.filter(|mime| mime.starts_with("audio/")) {
+        Some(mime) => {
+            let ext = match mime {
+                "audio/mpeg" => "mp3",
+                "audio/mp4" | "audio/x-m4a" => "m4a",
+                "audio/ogg" => "ogg",
+                "audio/opus" => "opus",
+                "audio/wav" | "audio/x-wav" => "wav",
+                "audio/aac" => "aac",
+                _ => url_ext.as_deref().unwrap_or("audio"),
+            };
+            (ext.to_owned(), mime.to_owned())
+        }
+        None => {
+            let ext = url_ext.unwrap_or_else(|| "mp3".to_owned());
+            let mime = match ext.as_str() {
+                "m4a" => "audio/mp4",
+                "ogg" | "oga" => "audio/ogg",
+                "opus" => "audio/opus",
+                "wav" => "audio/wav",
+                "aac" => "audio/aac",
+                _ => "audio/mpeg",
+            };
+            (ext, mime.to_owned())
+        }
+    }
+}
+
+/// Downloads files linked from an article to the temp directory and adds them to the article's
+/// attachments. `file_info` returns the extension and media type of a file from its URL and the
+/// media type it was served with.
+async fn download_attachments(
+    extractor: &mut Article,
+    urls: Vec<String>,
+    label: &str,
+    bar: &ProgressBar,
+    client: &surf::Client,
+    max_size: Option<u64>,
+    file_info: fn(&str, Option<&str>) -> (String, String),
+) -> Result<(), Vec<ImgError>> {
+    if urls.is_empty() {
+        return Ok(());
+    }
+    debug!("Downloading {} {} for {}", urls.len(), label, extractor.url);
+    let attachment_count = urls.len();
+    let attachment_reqs = urls
+        .into_iter()
+        .enumerate()
+        .map(|(attachment_idx, url)| async move {
+            bar.set_message(format!(
+                "Downloading {} [{}/{}]",
+                label,
+                attachment_idx + 1,
+                attachment_count
+            ));
+            let download_attachment = async {
+                let mut res = client
+                    .get(&url)
+                    .middleware(surf::middleware::Redirect::default())
+                    .await?;
+                if !res.status().is_success() {
+                    let kind = ErrorKind::HTTPError(format!(
+                        "Non-success HTTP status code ({})",
+                        res.status()
+                    ));
+                    return Err(ImgError::with_kind(kind));
+                }
+                let mime = res.content_type().map(|mime| mime.essence().to_owned());
+                if mime.as_deref() == Some("text/html") {
+                    return Err(ErrorKind::HTTPError(format!(
+                        "Invalid MIME type: text/html for {}",
+                        url
+                    ))
+                    .into());
+                }
+                let is_too_large = |size: u64| max_size.map_or(false, |max_size| size > max_size);
+                if is_too_large(res.len().unwrap_or(0) as u64) {
+                    return Err(ErrorKind::HTTPError(format!(
+                        "{} is larger than the maximum size of {} bytes",
+                        url,
+                        max_size.unwrap_or(0)
+                    ))
+                    .into());
+                }
+                let content = res.body_bytes().await?;
+                if is_too_large(content.len() as u64) {
+                    return Err(ErrorKind::HTTPError(format!(
+                        "{} is larger than the maximum size of {} bytes",
+                        url,
+                        max_size.unwrap_or(0)
+                    ))
+                    .into());
+                }
+                let (ext, mime_type) = file_info(&url, mime.as_deref());
+                let file_name = format!("{}.{}", hash_url(&url), ext);
+                let mut file = File::create(std::env::temp_dir().join(&file_name)).await?;
+                file.write_all(&content).await?;
+                Ok(Attachment {
+                    url: url.clone(),
+                    file_name,
+                    mime_type,
+                })
+            };
+            download_attachment.await.map_err(|mut e: ImgError| {
+                e.set_url(&url);
+                e
+            })
+        });
+
+    let mut errors = Vec::new();
+    for attachment_result in stream::from_iter(attachment_reqs)
+        .buffered(10)
+        .collect::<Vec<_>>()
+        .await
+    {
+        match attachment_result {
+            Ok(attachment) => extractor.attachments.push(attachment),
+            Err(e) => errors.push(e),
+        }
+    }
     if errors.is_empty() {
         Ok(())
     } else {
@@ -291,6 +1633,150 @@ impl PartialDownload {
     }
 }
 
+/// Replaces the `<img>` elements with the given source with their alt text. This is used for
+/// images that were not downloaded, such as those that timed out or were skipped for being
+/// insecure, so that the article still reads correctly. A placeholder of the image's size is
+/// left after the alt text when `is_keeping_placeholders` is set.
+fn replace_img_with_alt_text(root_node: &NodeRef, img_url: &str, is_keeping_placeholders: bool) {
+    for img_ref in select_imgs_with_src(root_node, img_url) {
+        let alt_text = img_ref
+            .attributes
+            .borrow()
+            .get("alt")
+            .map(str::trim)
+            .unwrap_or("")
+            .to_string();
+        let img_node = img_ref.as_node();
+        if !alt_text.is_empty() {
+            img_node.insert_before(NodeRef::new_text(format!("[{}]", alt_text)));
+        }
+        if is_keeping_placeholders {
+            insert_img_placeholder(&img_ref, img_node);
+        }
+        img_node.detach();
+    }
+}
+
+fn select_imgs_with_src(
+    root_node: &NodeRef,
+    img_url: &str,
+) -> Vec<kuchiki::NodeDataRef<kuchiki::ElementData>> {
+    root_node
+        .select("img[src]")
+        .unwrap()
+        .filter(|img_ref| img_ref.attributes.borrow().get("src") == Some(img_url))
+        .collect()
+}
+
+/// Checks whether a downloaded image is narrower or shorter than the minimum size.
+/// Images whose dimensions cannot be read, such as SVGs, are never considered too small.
+fn is_below_min_size(img_item: &ImgItem, min_image_size: Option<u32>) -> bool {
+    let min_image_size = match min_image_size {
+        Some(min_image_size) => min_image_size,
+        None => return false,
+    };
+    std::fs::read(std::env::temp_dir().join(&img_item.1))
+        .ok()
+        .and_then(|img_content| image_dimensions(&img_content))
+        .map_or(false, |(width, height)| {
+            width < min_image_size || height < min_image_size
+        })
+}
+
+/// Converts the downloaded image stored in the temp directory to the target format and returns
+/// the file name and MIME type of the converted copy. Returns `None` for images that are not
+/// JPEG or PNG, which are the only formats decoded, and images already in the target format so
+/// that they are not compressed again.
+fn convert_image(
+    file_name: &str,
+    target: &ImageFormatTarget,
+) -> Result<Option<(String, &'static str)>, image::ImageError> {
+    let img_content = std::fs::read(std::env::temp_dir().join(file_name))?;
+    if !matches!(
+        image::guess_format(&img_content),
+        Ok(image::ImageFormat::Jpeg) | Ok(image::ImageFormat::Png)
+    ) {
+        return Ok(None);
+    }
+    let img = image::load_from_memory(&img_content)?;
+    let has_transparency =
+        img.color().has_alpha() && img.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX);
+    let (jpeg_quality, ext, mime) = match target {
+        ImageFormatTarget::Jpeg(quality) if !has_transparency => {
+            (Some(*quality), "jpeg", "image/jpeg")
+        }
+        _ => (None, "png", "image/png"),
+    };
+    let file_stem = file_name.rsplitn(2, '.').last().unwrap_or(file_name);
+    let converted_file_name = format!("{}.{}", file_stem, ext);
+    if converted_file_name == file_name {
+        return Ok(None);
+    }
+    let mut converted_content = Vec::new();
+    match jpeg_quality {
+        Some(quality) => {
+            let rgb_img = img.to_rgb8();
+            image::codecs::jpeg::JpegEncoder::new_with_quality(&mut converted_content, quality)
+                .encode(
+                    &rgb_img,
+                    rgb_img.width(),
+                    rgb_img.height(),
+                    image::ColorType::Rgb8,
+                )?;
+        }
+        None => img.write_to(&mut converted_content, image::ImageOutputFormat::Png)?,
+    }
+    std::fs::write(
+        std::env::temp_dir().join(&converted_file_name),
+        converted_content,
+    )?;
+    Ok(Some((converted_file_name, mime)))
+}
+
+/// Reads the width and height of an image from its header without decoding it.
+/// PNG, GIF, JPEG and WebP images are supported.
+fn image_dimensions(img_content: &[u8]) -> Option<(u32, u32)> {
+    let read_bytes = |idx: usize, len: usize| img_content.get(idx..idx + len);
+    let be_u16 = |idx| read_bytes(idx, 2).map(|b| u16::from_be_bytes([b[0], b[1]]) as u32);
+    let le_u16 = |idx| read_bytes(idx, 2).map(|b| u16::from_le_bytes([b[0], b[1]]) as u32);
+    let be_u32 = |idx| read_bytes(idx, 4).map(|b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]));
+    let le_u24 = |idx| read_bytes(idx, 3).map(|b| u32::from_le_bytes([b[0], b[1], b[2], 0]));
+
+    if img_content.starts_with(b"\x89PNG\r\n\x1a\n") {
+        Some((be_u32(16)?, be_u32(20)?))
+    } else if img_content.starts_with(b"GIF8") {
+        Some((le_u16(6)?, le_u16(8)?))
+    } else if img_content.starts_with(&[0xFF, 0xD8]) {
+        // The dimensions are in the start of frame segment which may follow other segments
+        let mut idx = 2;
+        loop {
+            if *img_content.get(idx)? != 0xFF {
+                return None;
+            }
+            match *img_content.get(idx + 1)? {
+                0xFF => idx += 1,
+                0x01 | 0xD0..=0xD9 => idx += 2,
+                0xC0..=0xCF if ![0xC4, 0xC8, 0xCC].contains(img_content.get(idx + 1)?) => {
+                    return Some((be_u16(idx + 7)?, be_u16(idx + 5)?));
+                }
+                _ => idx += 2 + be_u16(idx + 2)? as usize,
+            }
+        }
+    } else if img_content.starts_with(b"RIFF") && read_bytes(8, 4) == Some(&b"WEBP"[..]) {
+        match read_bytes(12, 4)? {
+            b"VP8 " => Some((le_u16(26)? & 0x3FFF, le_u16(28)? & 0x3FFF)),
+            b"VP8L" => {
+                let bits = le_u16(21)? | le_u16(23)? << 16;
+                Some(((bits & 0x3FFF) + 1, ((bits >> 14) & 0x3FFF) + 1))
+            }
+            b"VP8X" => Some((le_u24(24)? + 1, le_u24(27)? + 1)),
+            _ => None,
+        }
+    } else {
+        None
+    }
+}
+
 /// Handles getting the extension from a given MIME subtype.
 fn map_mime_subtype_to_ext(subtype: &str) -> &str {
     if subtype == ("svg+xml") {
@@ -328,6 +1814,346 @@ fn get_absolute_url(url: &str, request_url: &Url) -> String {
 #[cfg(test)]
 mod test {
     use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_find_browser() {
+        assert_eq!(None, find_browser(Some("paperoni-missing-browser")));
+        let current_exe = std::env::current_exe().unwrap();
+        let current_exe = current_exe.to_str().unwrap();
+        assert_eq!(
+            Some(current_exe.to_owned()),
+            find_browser(Some(current_exe))
+        );
+    }
+
+    #[test]
+    fn test_browser_proxy_args() {
+        assert_eq!(
+            vec![
+                "--proxy-server=socks5://127.0.0.1:9050".to_owned(),
+                "--host-resolver-rules=MAP * ~NOTFOUND , EXCLUDE 127.0.0.1".to_owned()
+            ],
+            browser_proxy_args("socks5h://127.0.0.1:9050")
+        );
+        assert_eq!(
+            vec!["--proxy-server=http://proxy.example.org:3128".to_owned()],
+            browser_proxy_args("http://proxy.example.org:3128")
+        );
+    }
+
+    #[test]
+    fn test_parse_sitemap() {
+        let sitemap_index = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <sitemapindex xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+            <sitemap><loc>https://example.com/sitemap-posts.xml</loc></sitemap>
+            <sitemap>
+                <loc>
+                    https://example.com/sitemap-pages.xml
+                </loc>
+            </sitemap>
+        </sitemapindex>"#;
+        assert_eq!(
+            (
+                true,
+                vec![
+                    "https://example.com/sitemap-posts.xml".to_owned(),
+                    "https://example.com/sitemap-pages.xml".to_owned()
+                ]
+            ),
+            parse_sitemap(sitemap_index, None)
+        );
+
+        let sitemap = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+            <url><loc>https://example.com/posts/hello?a=1&amp;b=2</loc></url>
+            <url><loc><![CDATA[https://example.com/posts/world]]></loc></url>
+        </urlset>"#;
+        assert_eq!(
+            (
+                false,
+                vec![
+                    "https://example.com/posts/hello?a=1&b=2".to_owned(),
+                    "https://example.com/posts/world".to_owned()
+                ]
+            ),
+            parse_sitemap(sitemap, None)
+        );
+
+        let sitemap = r#"<?xml version="1.0" encoding="UTF-8"?>
+        <urlset xmlns="http://www.sitemaps.org/schemas/sitemap/0.9">
+            <url><loc>https://example.com/old</loc><lastmod>2021-05-01</lastmod></url>
+            <url>
+                <loc>https://example.com/new</loc>
+                <lastmod>2021-06-01T09:30:00+02:00</lastmod>
+            </url>
+            <url><loc>https://example.com/undated</loc></url>
+            <url><loc>https://example.com/minutes</loc><lastmod>2021-05-30T10:00+00:00</lastmod></url>
+        </urlset>"#;
+        let since = Utc.ymd(2021, 5, 25).and_hms(0, 0, 0);
+        assert_eq!(
+            (
+                false,
+                vec![
+                    "https://example.com/new".to_owned(),
+                    "https://example.com/undated".to_owned(),
+                    "https://example.com/minutes".to_owned()
+                ]
+            ),
+            parse_sitemap(sitemap, Some(since))
+        );
+    }
+
+    #[test]
+    fn test_host_permits() {
+        let urls = vec![
+            "https://example.com/a".to_owned(),
+            "https://EXAMPLE.com/b".to_owned(),
+            "https://example.org/c".to_owned(),
+        ];
+        let host_permits = HostPermits::new(&urls, 2);
+        assert_eq!(2, host_permits.permits.len());
+
+        task::block_on(async {
+            let first = host_permits.acquire("https://example.com/a").await;
+            let second = host_permits.acquire("https://example.com/b").await;
+            assert!(first.is_some() && second.is_some());
+            let (_, receiver) = &host_permits.permits["example.com"];
+            assert!(receiver.is_empty());
+            assert!(host_permits
+                .acquire("https://example.org/c")
+                .await
+                .is_some());
+
+            drop(first);
+            assert_eq!(1, receiver.len());
+            assert!(host_permits.acquire("not a url").await.is_none());
+        });
+    }
+
+    #[test]
+    fn test_is_svg() {
+        assert!(is_svg(b"<svg xmlns=\"http://www.w3.org/2000/svg\"></svg>"));
+        assert!(is_svg(
+            b"<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<!-- Generator: Inkscape -->\n\
+            <!DOCTYPE svg PUBLIC \"-//W3C//DTD SVG 1.1//EN\" \"svg11.dtd\">\n<svg></svg>"
+        ));
+        assert!(!is_svg(
+            b"<!DOCTYPE html><html><body><svg></svg></body></html>"
+        ));
+        assert!(!is_svg(b"\x89PNG\r\n\x1a\n"));
+        assert!(!is_svg(b"<?xml version=\"1.0\""));
+    }
+
+    #[test]
+    fn test_decode_data_url() {
+        assert_eq!(
+            Some(("image/gif".to_owned(), b"GIF89a".to_vec())),
+            decode_data_url("data:image/gif;base64,R0lGODlh")
+        );
+        assert_eq!(
+            Some((
+                "image/svg+xml".to_owned(),
+                b"<svg width='1'></svg>".to_vec()
+            )),
+            decode_data_url("data:image/svg+xml;charset=utf-8,%3Csvg width='1'%3E%3C/svg%3E")
+        );
+        assert_eq!(
+            Some(("text/plain".to_owned(), b"Hello".to_vec())),
+            decode_data_url("data:,Hello")
+        );
+        assert_eq!(None, decode_data_url("data:image/png;base64,###"));
+        assert_eq!(None, decode_data_url("https://example.com/img.png"));
+    }
+
+    #[test]
+    fn test_process_data_url() {
+        let url = "data:image/gif;base64,R0lGODlhAQABAAAAACw=";
+        let (img_url, img_path, img_mime) =
+            task::block_on(process_data_url(url)).expect("Unable to process data URL");
+        assert_eq!(url, img_url);
+        assert_eq!(format!("{}.gif", hash_url(url)), img_path);
+        assert_eq!(Some("image/gif".to_owned()), img_mime);
+
+        let img_content = std::fs::read(std::env::temp_dir().join(&img_path)).unwrap();
+        assert!(img_content.starts_with(b"GIF89a"));
+
+        assert!(task::block_on(process_data_url("data:text/plain,Hello")).is_err());
+    }
+
+    #[test]
+    fn test_replace_img_with_alt_text() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <p>A <img src="slow.png" alt=" Chart of results "> here</p>
+                <p><img src="slow.png"><img src="fast.png" alt="Logo"></p>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+        replace_img_with_alt_text(&doc, "slow.png", false);
+
+        assert_eq!(1, doc.select("img").unwrap().count());
+        let paragraphs = doc
+            .select("p")
+            .unwrap()
+            .map(|p| p.text_contents())
+            .collect::<Vec<_>>();
+        assert_eq!(vec!["A [Chart of results] here", ""], paragraphs);
+    }
+
+    #[test]
+    fn test_convert_image() {
+        let write_png = |file_name: &str, img: image::DynamicImage| {
+            img.save_with_format(
+                std::env::temp_dir().join(file_name),
+                image::ImageFormat::Png,
+            )
+            .unwrap();
+        };
+        let opaque = image::RgbaImage::from_pixel(4, 4, image::Rgba([200, 10, 10, 255]));
+        write_png(
+            "paperoni-test-opaque.png",
+            image::DynamicImage::ImageRgba8(opaque),
+        );
+        let mut transparent = image::RgbaImage::from_pixel(4, 4, image::Rgba([200, 10, 10, 255]));
+        transparent.put_pixel(0, 0, image::Rgba([0, 0, 0, 0]));
+        write_png(
+            "paperoni-test-transparent.png",
+            image::DynamicImage::ImageRgba8(transparent),
+        );
+
+        let jpeg = ImageFormatTarget::Jpeg(80);
+        let (file_name, mime) = convert_image("paperoni-test-opaque.png", &jpeg)
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            ("paperoni-test-opaque.jpeg", "image/jpeg"),
+            (file_name.as_str(), mime)
+        );
+        let converted = std::fs::read(std::env::temp_dir().join(&file_name)).unwrap();
+        assert_eq!(Some((4, 4)), image_dimensions(&converted));
+        assert_eq!(
+            image::ImageFormat::Jpeg,
+            image::guess_format(&converted).unwrap()
+        );
+
+        assert_eq!(
+            None,
+            convert_image("paperoni-test-transparent.png", &jpeg).unwrap()
+        );
+        assert_eq!(
+            None,
+            convert_image("paperoni-test-opaque.png", &ImageFormatTarget::Png).unwrap()
+        );
+
+        // Formats without a decoder are left as is
+        std::fs::write(
+            std::env::temp_dir().join("paperoni-test.gif"),
+            b"GIF89a\x01\x00\x01\x00\x00\x00\x00;",
+        )
+        .unwrap();
+        assert_eq!(None, convert_image("paperoni-test.gif", &jpeg).unwrap());
+    }
+
+    #[test]
+    fn test_image_dimensions() {
+        let mut png = b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR".to_vec();
+        png.extend_from_slice(&[0, 0, 2, 0, 0, 0, 0, 1]);
+        assert_eq!(Some((512, 1)), image_dimensions(&png));
+
+        let gif = b"GIF89a\x01\0\x01\0";
+        assert_eq!(Some((1, 1)), image_dimensions(gif));
+
+        // An APP0 segment followed by a baseline start of frame segment
+        let mut jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+        jpeg.extend_from_slice(&[0; 14]);
+        jpeg.extend_from_slice(&[0xFF, 0xC0, 0x00, 0x11, 0x08, 0x01, 0xE0, 0x02, 0x80]);
+        assert_eq!(Some((640, 480)), image_dimensions(&jpeg));
+
+        let mut webp = b"RIFF\0\0\0\0WEBPVP8X\0\0\0\0\0\0\0\0".to_vec();
+        webp.extend_from_slice(&[0x1F, 0x00, 0x00, 0x0F, 0x00, 0x00]);
+        assert_eq!(Some((32, 16)), image_dimensions(&webp));
+
+        assert_eq!(None, image_dimensions(b"<svg></svg>"));
+        assert_eq!(None, image_dimensions(&[0xFF, 0xD8, 0xFF]));
+    }
+
+    #[test]
+    fn test_parse_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:28:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+
+        assert_eq!(
+            Some(Duration::from_secs(120)),
+            parse_retry_after("120", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(90)),
+            parse_retry_after("Wed, 21 Oct 2015 07:29:30 GMT", now)
+        );
+        assert_eq!(
+            Some(Duration::from_secs(0)),
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now)
+        );
+        assert_eq!(None, parse_retry_after("soon", now));
+    }
+
+    #[test]
+    fn test_is_mixed_content() {
+        let https_origin = Url::parse("https://example.com/article").unwrap();
+        let http_origin = Url::parse("http://example.com/article").unwrap();
+
+        assert!(is_mixed_content(
+            "http://cdn.example.com/a.png",
+            &https_origin
+        ));
+        assert!(!is_mixed_content(
+            "https://cdn.example.com/a.png",
+            &https_origin
+        ));
+        assert!(!is_mixed_content(
+            "http://cdn.example.com/a.png",
+            &http_origin
+        ));
+        assert!(!is_mixed_content(
+            "data:image/png;base64,AAAA",
+            &https_origin
+        ));
+    }
+
+    #[test]
+    fn test_extract_listing_links() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <nav><a href="/about">About</a></nav>
+                <article><h2><a href="/posts/first">First post</a></h2></article>
+                <article><h2><a href="https://example.org/posts/second#comments">Second post</a></h2></article>
+                <article><h2><a href="/posts/first">First post again</a></h2></article>
+                <a rel="bookmark" href="posts/third">Third post</a>
+                <a rel="bookmark" href="mailto:author@example.org">Email</a>
+            </body>
+        </html>
+        "#;
+        let links = extract_listing_links(html_str, "https://example.org/", DEFAULT_LINK_SELECTOR);
+        assert_eq!(
+            vec![
+                "https://example.org/posts/first",
+                "https://example.org/posts/second",
+                "https://example.org/posts/third"
+            ],
+            links
+        );
+
+        let links = extract_listing_links(html_str, "https://example.org/", "nav a");
+        assert_eq!(vec!["https://example.org/about"], links);
+    }
+
     #[test]
     fn test_map_mime_type_to_ext() {
         let mime_subtypes = vec![
@@ -342,4 +2168,23 @@ mod test {
             exts
         );
     }
+
+    #[test]
+    fn test_audio_file_info() {
+        assert_eq!(
+            ("mp3".to_owned(), "audio/mpeg".to_owned()),
+            audio_file_info("https://example.com/play?id=42", Some("audio/mpeg"))
+        );
+        assert_eq!(
+            ("m4a".to_owned(), "audio/mp4".to_owned()),
+            audio_file_info("https://example.com/episodes/42.M4A", None)
+        );
+        assert_eq!(
+            ("ogg".to_owned(), "audio/ogg".to_owned()),
+            audio_file_info(
+                "https://example.com/episodes/42.ogg",
+                Some("application/octet-stream")
+            )
+        );
+    }
 }
@@ -1,41 +1,73 @@
 use std::fs;
+use std::io::{self, Write};
+use std::time::Duration;
 
 use chrono::{DateTime, Local};
 use colored::*;
 use comfy_table::presets::UTF8_HORIZONTAL_BORDERS_ONLY;
 use comfy_table::{Cell, CellAlignment, ContentArrangement, Table};
-use flexi_logger::{FileSpec, LevelFilter};
-use log::error;
+use flexi_logger::{DeferredNow, FileSpec, LevelFilter};
+use itertools::Itertools;
+use log::{error, warn, Record};
 
+use crate::cli::LogFormat;
 use crate::errors::PaperoniError;
+use crate::extractor::{ArticleTimings, DuplicateArticle, LowQualityExtraction};
 
+/// How long the phases of a run took, shown in the summary with `--timings`
+#[derive(Default)]
+pub struct Timings {
+    pub articles: Vec<ArticleTiming>,
+    /// The time spent writing the exports
+    pub generation: Duration,
+    /// The time the whole run took
+    pub total: Duration,
+}
+
+pub struct ArticleTiming {
+    pub link: String,
+    pub timings: ArticleTimings,
+    /// The time spent writing the article's own EPUB. It is only known for articles exported
+    /// while the next ones download.
+    pub generation: Option<Duration>,
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn display_summary(
     initial_article_count: usize,
     successful_articles_table: Table,
     partial_downloads: Vec<PartialDownload>,
+    low_quality_extractions: Vec<LowQualityExtraction>,
+    duplicate_articles: Vec<DuplicateArticle>,
     errors: Vec<PaperoniError>,
-) {
+    file_errors: Vec<PaperoniError>,
+    timings: Option<Timings>,
+    out: &mut dyn Write,
+) -> io::Result<()> {
     let partial_downloads_count = partial_downloads.len();
+    let duplicate_count = duplicate_articles.len();
     let successfully_downloaded_count =
-        initial_article_count - partial_downloads_count - errors.len();
+        initial_article_count - partial_downloads_count - errors.len() - duplicate_count;
 
-    println!(
+    writeln!(
+        out,
         "{}",
         short_summary(DownloadCount::new(
             initial_article_count,
             successfully_downloaded_count,
             partial_downloads_count,
-            errors.len()
+            errors.len(),
+            duplicate_count
         ))
         .bold()
-    );
+    )?;
 
     if successfully_downloaded_count > 0 {
-        println!("{}", successful_articles_table);
+        writeln!(out, "{}", successful_articles_table)?;
     }
 
     if partial_downloads_count > 0 {
-        println!("\n{}", "Partially failed downloads".yellow().bold());
+        writeln!(out, "\n{}", "Partially failed downloads".yellow().bold())?;
         let mut table_partial = Table::new();
         table_partial
             .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
@@ -48,36 +80,187 @@ pub fn display_summary(
         for partial in partial_downloads {
             table_partial.add_row(vec![&partial.link, &partial.title]);
         }
-        println!("{}", table_partial);
+        writeln!(out, "{}", table_partial)?;
     }
 
-    if !errors.is_empty() {
-        println!("\n{}", "Failed article downloads".bright_red().bold());
-        let mut table_failed = Table::new();
-        table_failed
+    if !low_quality_extractions.is_empty() {
+        writeln!(
+            out,
+            "\n{}",
+            "Possibly low quality extractions".yellow().bold()
+        )?;
+        let mut table_low_quality = Table::new();
+        table_low_quality
+            .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
+            .set_header(vec![
+                Cell::new("Link").set_alignment(CellAlignment::Center),
+                Cell::new("Title").set_alignment(CellAlignment::Center),
+                Cell::new("Status").set_alignment(CellAlignment::Center),
+                Cell::new("Warnings").set_alignment(CellAlignment::Center),
+            ])
+            .set_content_arrangement(ContentArrangement::Dynamic);
+
+        for extraction in low_quality_extractions {
+            let warnings = extraction.warnings.iter().join("\n");
+            warn!(
+                "{} may have been extracted poorly: {}",
+                extraction.link,
+                extraction.warnings.iter().join(", ")
+            );
+            // Redirects are shown so that soft paywalls and login pages are easier to spot
+            let link = match &extraction.redirected_from {
+                Some(requested_link) => format!("{}\n→ {}", requested_link, extraction.link),
+                None => extraction.link.clone(),
+            };
+            let status = extraction
+                .status
+                .map_or_else(|| "-".to_owned(), |status| status.to_string());
+            table_low_quality.add_row(vec![&link, &extraction.title, &status, &warnings]);
+        }
+        writeln!(out, "{}", table_low_quality)?;
+    }
+
+    if !duplicate_articles.is_empty() {
+        writeln!(out, "\n{}", "Skipped duplicate articles".yellow().bold())?;
+        let mut table_duplicates = Table::new();
+        table_duplicates
             .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
             .set_header(vec![
                 Cell::new("Link").set_alignment(CellAlignment::Center),
-                Cell::new("Reason").set_alignment(CellAlignment::Center),
+                Cell::new("Title").set_alignment(CellAlignment::Center),
+                Cell::new("Duplicate of").set_alignment(CellAlignment::Center),
             ])
             .set_content_arrangement(ContentArrangement::Dynamic);
 
-        for error in errors {
-            let error_source = error
-                .article_source()
-                .clone()
-                .unwrap_or_else(|| "<unknown link>".to_string());
-            table_failed.add_row(vec![&error_source, &format!("{}", error.kind())]);
-            error!("{}\n - {}", error, error_source);
+        for duplicate in duplicate_articles {
+            warn!(
+                "Skipped {} as a duplicate of {}",
+                duplicate.link, duplicate.duplicate_of
+            );
+            table_duplicates.add_row(vec![
+                &duplicate.link,
+                &duplicate.title,
+                &duplicate.duplicate_of,
+            ]);
+        }
+        writeln!(out, "{}", table_duplicates)?;
+    }
+
+    if !errors.is_empty() {
+        writeln!(out, "\n{}", "Failed article downloads".bright_red().bold())?;
+        writeln!(out, "{}", failed_table("Link", errors))?;
+    }
+
+    // Files such as the metadata CSV are not articles so they are left out of the counts above
+    if !file_errors.is_empty() {
+        writeln!(out, "\n{}", "Failed files".bright_red().bold())?;
+        writeln!(out, "{}", failed_table("File", file_errors))?;
+    }
+
+    if let Some(timings) = timings {
+        display_timings(timings, out)?;
+    }
+    Ok(())
+}
+
+/// Returns a table of the sources, codes and reasons of the errors, and logs them
+fn failed_table(source_header: &str, mut errors: Vec<PaperoniError>) -> Table {
+    let mut table_failed = Table::new();
+    table_failed
+        .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
+        .set_header(vec![
+            Cell::new(source_header).set_alignment(CellAlignment::Center),
+            Cell::new("Code").set_alignment(CellAlignment::Center),
+            Cell::new("Reason").set_alignment(CellAlignment::Center),
+        ])
+        .set_content_arrangement(ContentArrangement::Dynamic);
+
+    // Failures of the same category are listed together
+    errors.sort_by_key(|error| error.code());
+    for error in errors {
+        let error_source = error
+            .article_source()
+            .clone()
+            .unwrap_or_else(|| "<unknown link>".to_string());
+        table_failed.add_row(vec![
+            error_source.clone(),
+            error.code().to_string(),
+            error.kind().to_string(),
+        ]);
+        error!("{} ({})\n - {}", error, error.code(), error_source);
+    }
+    table_failed
+}
+
+/// Writes the time each article took to download and extract followed by the totals of each
+/// phase. Articles are downloaded and extracted concurrently so the totals of these phases can
+/// add up to more than the time the whole run took.
+fn display_timings(timings: Timings, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "\n{}", "Timings".bold())?;
+    if !timings.articles.is_empty() {
+        let mut table_timings = Table::new();
+        table_timings
+            .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
+            .set_header(vec![
+                Cell::new("Link").set_alignment(CellAlignment::Center),
+                Cell::new("Download").set_alignment(CellAlignment::Center),
+                Cell::new("Extraction").set_alignment(CellAlignment::Center),
+                Cell::new("Generation").set_alignment(CellAlignment::Center),
+            ])
+            .set_content_arrangement(ContentArrangement::Dynamic);
+        for article in &timings.articles {
+            table_timings.add_row(vec![
+                article.link.clone(),
+                format_duration(article.timings.download),
+                format_duration(article.timings.extraction),
+                article
+                    .generation
+                    .map_or_else(|| "-".to_owned(), format_duration),
+            ]);
         }
-        println!("{}", table_failed);
+        writeln!(out, "{}", table_timings)?;
     }
+    let article_count = timings.articles.len() as u32;
+    let phase_summary = |total: Duration| {
+        if article_count == 0 {
+            format_duration(total)
+        } else {
+            format!(
+                "{} in total, {} per article",
+                format_duration(total),
+                format_duration(total / article_count)
+            )
+        }
+    };
+    let download = timings
+        .articles
+        .iter()
+        .map(|article| article.timings.download)
+        .sum();
+    let extraction = timings
+        .articles
+        .iter()
+        .map(|article| article.timings.extraction)
+        .sum();
+    writeln!(out, "Download: {}", phase_summary(download))?;
+    writeln!(out, "Extraction: {}", phase_summary(extraction))?;
+    writeln!(out, "Generation: {}", phase_summary(timings.generation))?;
+    writeln!(out, "Total: {}", format_duration(timings.total))
 }
 
-/// Returns a string summary of the total number of failed and successful article downloads
+/// Formats a duration in seconds with millisecond precision e.g 1.250s
+fn format_duration(duration: Duration) -> String {
+    format!("{:.3}s", duration.as_secs_f64())
+}
+
+/// Returns a string summary of the total number of failed, successful and duplicate article
+/// downloads
 fn short_summary(download_count: DownloadCount) -> String {
     if download_count.total
-        != download_count.successful + download_count.failed + download_count.partial
+        != download_count.successful
+            + download_count.failed
+            + download_count.partial
+            + download_count.duplicate
     {
         panic!("initial_count must be equal to the sum of failed and successful count")
     }
@@ -124,6 +307,18 @@ fn short_summary(download_count: DownloadCount) -> String {
     } else {
         summary = summary + &failed_summary;
     }
+
+    let duplicate_label = if download_count.duplicate == 1 {
+        " skipped as a duplicate"
+    } else {
+        " skipped as duplicates"
+    };
+    let duplicate_summary = get_summary(download_count.duplicate, duplicate_label, Color::Yellow);
+    if !summary.is_empty() && !duplicate_summary.is_empty() {
+        summary = summary + ", " + &duplicate_summary;
+    } else {
+        summary = summary + &duplicate_summary;
+    }
     summary
 }
 
@@ -132,14 +327,22 @@ struct DownloadCount {
     successful: usize,
     partial: usize,
     failed: usize,
+    duplicate: usize,
 }
 impl DownloadCount {
-    fn new(total: usize, successful: usize, partial: usize, failed: usize) -> Self {
+    fn new(
+        total: usize,
+        successful: usize,
+        partial: usize,
+        failed: usize,
+        duplicate: usize,
+    ) -> Self {
         Self {
             total,
             successful,
             partial,
             failed,
+            duplicate,
         }
     }
 }
@@ -147,10 +350,44 @@ impl DownloadCount {
 use crate::errors::LogError as Error;
 use crate::http::PartialDownload;
 
+lazy_static! {
+    static ref LOG_URL_REGEX: regex::Regex = regex::Regex::new(r#"https?://[^\s"'<>]+"#).unwrap();
+}
+
+/// Writes a log record as a JSON object with its timestamp, level, message and the first URL
+/// in the message, which is usually the article or resource the record is about
+fn json_log_format(w: &mut dyn Write, now: &mut DeferredNow, record: &Record) -> io::Result<()> {
+    write!(
+        w,
+        "{}",
+        json_log_line(
+            now.now(),
+            &record.level().to_string(),
+            &record.args().to_string()
+        )
+    )
+}
+
+fn json_log_line(timestamp: &DateTime<Local>, level: &str, message: &str) -> String {
+    let url = LOG_URL_REGEX.find(message).map(|url_match| {
+        url_match
+            .as_str()
+            .trim_end_matches(&['.', ',', ')', ';'][..])
+    });
+    serde_json::json!({
+        "timestamp": timestamp.to_rfc3339(),
+        "level": level,
+        "url": url,
+        "message": message,
+    })
+    .to_string()
+}
+
 pub fn init_logger(
     log_level: LevelFilter,
     start_time: &DateTime<Local>,
     is_logging_to_file: bool,
+    log_format: &LogFormat,
 ) -> Result<(), Error> {
     use directories::UserDirs;
     use flexi_logger::LogSpecBuilder;
@@ -175,6 +412,9 @@ pub fn init_logger(
                         .discriminant(formatted_timestamp.to_string())
                         .suppress_timestamp(),
                 );
+                if let LogFormat::Json = log_format {
+                    logger = logger.format_for_files(json_log_format);
+                }
             }
             logger.start()?;
             Ok(())
@@ -185,30 +425,142 @@ pub fn init_logger(
 
 #[cfg(test)]
 mod tests {
-    use super::{short_summary, DownloadCount};
+    use super::{
+        display_summary, display_timings, json_log_line, short_summary, ArticleTiming,
+        DownloadCount, Timings,
+    };
+    use crate::errors::{ErrorKind, PaperoniError};
+    use crate::extractor::{ArticleTimings, DuplicateArticle};
+    use chrono::{Local, TimeZone};
     use colored::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_display_summary_file_errors() {
+        let mut article_error: PaperoniError = ErrorKind::HTTPError("Not Found".to_owned()).into();
+        article_error.set_article_source("https://example.com/pepperoni");
+        let mut file_error: PaperoniError =
+            ErrorKind::IOError("Permission denied".to_owned()).into();
+        file_error.set_article_source("archive.csv");
+        let mut out = Vec::new();
+        // The file errors are not counted as failed articles
+        display_summary(
+            1,
+            comfy_table::Table::new(),
+            Vec::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![article_error],
+            vec![file_error],
+            None,
+            &mut out,
+        )
+        .unwrap();
+        let summary = String::from_utf8(out).unwrap();
+        assert!(summary.contains("Article failed to download"));
+        assert!(summary.contains("Failed files"));
+        assert!(summary.contains("archive.csv"));
+    }
+
+    #[test]
+    fn test_display_summary_duplicates() {
+        let duplicate = DuplicateArticle {
+            link: "https://example.com/pepperoni?ref=feed".to_owned(),
+            title: "Pepperoni".to_owned(),
+            duplicate_of: "https://example.com/pepperoni".to_owned(),
+        };
+        let mut out = Vec::new();
+        // The skipped duplicates are not counted as successful downloads
+        display_summary(
+            2,
+            comfy_table::Table::new(),
+            Vec::new(),
+            Vec::new(),
+            vec![duplicate],
+            Vec::new(),
+            Vec::new(),
+            None,
+            &mut out,
+        )
+        .unwrap();
+        let summary = String::from_utf8(out).unwrap();
+        assert!(summary.contains("1 article downloaded successfully"));
+        assert!(summary.contains("1 article skipped as a duplicate"));
+    }
+
+    #[test]
+    fn test_json_log_line() {
+        let timestamp = Local.ymd(2021, 6, 1).and_hms(8, 30, 0);
+        let line = json_log_line(
+            &timestamp,
+            "WARN",
+            "Unable to download https://example.com/pepperoni.jpg. Retrying",
+        );
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(timestamp.to_rfc3339(), entry["timestamp"]);
+        assert_eq!("WARN", entry["level"]);
+        assert_eq!("https://example.com/pepperoni.jpg", entry["url"]);
+        assert_eq!(
+            "Unable to download https://example.com/pepperoni.jpg. Retrying",
+            entry["message"]
+        );
+        assert!(!line.contains('\n'));
+
+        let line = json_log_line(&timestamp, "INFO", "Downloading articles");
+        let entry: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(entry["url"].is_null());
+    }
+
+    #[test]
+    fn test_display_timings() {
+        let article_timing = |link: &str, download_ms, extraction_ms| ArticleTiming {
+            link: link.to_owned(),
+            timings: ArticleTimings {
+                download: Duration::from_millis(download_ms),
+                extraction: Duration::from_millis(extraction_ms),
+            },
+            generation: None,
+        };
+        let timings = Timings {
+            articles: vec![
+                article_timing("https://example.com/1", 1500, 100),
+                article_timing("https://example.com/2", 500, 300),
+            ],
+            generation: Duration::from_millis(400),
+            total: Duration::from_millis(2250),
+        };
+        let mut out = Vec::new();
+        display_timings(timings, &mut out).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("Download: 2.000s in total, 1.000s per article\n"));
+        assert!(out.contains("Extraction: 0.400s in total, 0.200s per article\n"));
+        assert!(out.contains("Generation: 0.400s in total, 0.200s per article\n"));
+        assert!(out.contains("Total: 2.250s\n"));
+        assert!(out.contains("1.500s"));
+    }
+
     #[test]
     fn test_short_summary() {
         assert_eq!(
-            short_summary(DownloadCount::new(1, 1, 0, 0)),
+            short_summary(DownloadCount::new(1, 1, 0, 0, 0)),
             "Article downloaded successfully".bright_green().to_string()
         );
         assert_eq!(
-            short_summary(DownloadCount::new(1, 0, 0, 1)),
+            short_summary(DownloadCount::new(1, 0, 0, 1, 0)),
             "Article failed to download".red().to_string()
         );
         assert_eq!(
-            short_summary(DownloadCount::new(10, 10, 0, 0)),
+            short_summary(DownloadCount::new(10, 10, 0, 0, 0)),
             "All articles downloaded successfully"
                 .bright_green()
                 .to_string()
         );
         assert_eq!(
-            short_summary(DownloadCount::new(10, 0, 0, 10)),
+            short_summary(DownloadCount::new(10, 0, 0, 10, 0)),
             "All articles failed to download".red().to_string()
         );
         assert_eq!(
-            short_summary(DownloadCount::new(10, 8, 0, 2)),
+            short_summary(DownloadCount::new(10, 8, 0, 2, 0)),
             format!(
                 "{}, {}",
                 "8 articles downloaded successfully".bright_green(),
@@ -216,7 +568,7 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(10, 1, 0, 9)),
+            short_summary(DownloadCount::new(10, 1, 0, 9, 0)),
             format!(
                 "{}, {}",
                 "1 article downloaded successfully".bright_green(),
@@ -224,7 +576,7 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(7, 6, 0, 1)),
+            short_summary(DownloadCount::new(7, 6, 0, 1, 0)),
             format!(
                 "{}, {}",
                 "6 articles downloaded successfully".bright_green(),
@@ -232,7 +584,7 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(7, 4, 2, 1)),
+            short_summary(DownloadCount::new(7, 4, 2, 1, 0)),
             format!(
                 "{}, {}, {}",
                 "4 articles downloaded successfully".bright_green(),
@@ -241,7 +593,7 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(12, 6, 6, 0)),
+            short_summary(DownloadCount::new(12, 6, 6, 0, 0)),
             format!(
                 "{}, {}",
                 "6 articles downloaded successfully".bright_green(),
@@ -249,7 +601,7 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(5, 0, 4, 1)),
+            short_summary(DownloadCount::new(5, 0, 4, 1, 0)),
             format!(
                 "{}, {}",
                 "4 articles partially failed to download".yellow(),
@@ -257,11 +609,28 @@ mod tests {
             )
         );
         assert_eq!(
-            short_summary(DownloadCount::new(4, 0, 4, 0)),
+            short_summary(DownloadCount::new(4, 0, 4, 0, 0)),
             "All articles partially failed to download"
                 .yellow()
                 .to_string()
         );
+        assert_eq!(
+            short_summary(DownloadCount::new(6, 3, 0, 1, 2)),
+            format!(
+                "{}, {}, {}",
+                "3 articles downloaded successfully".bright_green(),
+                "1 article failed to download".red(),
+                "2 articles skipped as duplicates".yellow()
+            )
+        );
+        assert_eq!(
+            short_summary(DownloadCount::new(2, 1, 0, 0, 1)),
+            format!(
+                "{}, {}",
+                "1 article downloaded successfully".bright_green(),
+                "1 article skipped as a duplicate".yellow()
+            )
+        );
     }
 
     #[test]
@@ -269,6 +638,6 @@ mod tests {
         expected = "initial_count must be equal to the sum of failed and successful count"
     )]
     fn test_short_summary_panics_on_invalid_input() {
-        short_summary(DownloadCount::new(0, 12, 0, 43));
+        short_summary(DownloadCount::new(0, 12, 0, 43, 0));
     }
 }
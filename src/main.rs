@@ -1,14 +1,19 @@
 #[macro_use]
 extern crate lazy_static;
 
+use std::io::{self, Write};
 use std::process::exit;
+use std::time::Instant;
 
 use colored::Colorize;
 use comfy_table::presets::{UTF8_FULL, UTF8_HORIZONTAL_BORDERS_ONLY};
 use comfy_table::{ContentArrangement, Table};
-use http::download;
+use http::{collect_listing_urls, collect_sitemap_urls, download, download_metadata};
 use indicatif::{ProgressBar, ProgressStyle};
+use itertools::Itertools;
 
+mod bundle;
+mod catalog;
 mod cli;
 mod epub;
 mod errors;
@@ -19,11 +24,19 @@ mod html;
 mod http;
 mod logs;
 mod moz_readability;
+mod netrc;
+mod progress;
+mod social;
+mod txt;
 
+use bundle::create_zip_bundle;
+use catalog::{write_catalog, write_metadata_csv};
 use cli::AppConfig;
-use epub::generate_epubs;
+use epub::{generate_epubs, set_article_table_header, ArticleEpubWriter, WrittenEpub};
+use extractor::{arrange_articles, DuplicateFilter, LowQualityExtraction};
 use html::generate_html_exports;
-use logs::display_summary;
+use logs::{display_summary, ArticleTiming, Timings};
+use txt::generate_txt_exports;
 
 fn main() {
     let app_config = match cli::AppConfig::init_with_cli() {
@@ -34,22 +47,56 @@ fn main() {
         }
     };
 
-    if !app_config.urls.is_empty() {
+    if !app_config.urls.is_empty() || app_config.sitemap_url.is_some() {
         run(app_config);
     }
 }
 
-fn run(app_config: AppConfig) {
+fn run(mut app_config: AppConfig) {
+    let run_start = Instant::now();
+    if let Some(proxy) = &app_config.proxy {
+        http::use_proxy(proxy);
+    }
     let mut errors = Vec::new();
+    // Errors writing the files that are not articles, which are not counted as failed articles
+    let mut file_errors = Vec::new();
     let mut partial_downloads = Vec::new();
 
+    if app_config.crawl_depth > 0 {
+        app_config.urls = collect_listing_urls(&app_config, &mut errors);
+    }
+    if let Some(sitemap_url) = app_config.sitemap_url.clone() {
+        let sitemap_urls = collect_sitemap_urls(&sitemap_url, &app_config, &mut errors);
+        let urls = std::mem::take(&mut app_config.urls);
+        app_config.urls = urls.into_iter().chain(sitemap_urls).unique().collect();
+    }
+    let filtered_count = app_config.filter_urls();
+    if app_config.url_start > 0 {
+        let skipped_count = app_config.url_start.min(app_config.urls.len());
+        app_config.urls.drain(..skipped_count);
+    }
+    if let Some(limit) = app_config.url_limit {
+        app_config.urls.truncate(limit);
+    }
+    app_config.name_generated_merge();
+    // Listing pages that failed to download are counted alongside the articles
+    let article_count = app_config.urls.len() + errors.len();
+
     if let Some(dir_name) = &app_config.output_directory {
         let noun = if app_config.urls.len() > 1 {
             "articles"
         } else {
             "article"
         };
-        println!("Downloading {} to {}", noun, dir_name);
+        if app_config.can_print_to_stdout() {
+            println!("Downloading {} to {}", noun, dir_name);
+        }
+    }
+    if let Some(merged_name) = &app_config.merged {
+        // The name of a merge named after its first article is shown once it is downloaded
+        if app_config.can_print_to_stdout() && !app_config.is_naming_merge_from_first {
+            println!("Merging articles into {}", merged_name);
+        }
     }
 
     let bar = if app_config.can_disable_progress_bar {
@@ -64,50 +111,266 @@ fn run(app_config: AppConfig) {
         enabled_bar
     };
 
-    let articles = download(&app_config, &bar, &mut partial_downloads, &mut errors);
-    bar.finish_with_message("Downloaded articles");
-
     let mut successful_articles_table = Table::new();
     successful_articles_table
         .load_preset(UTF8_FULL)
         .load_preset(UTF8_HORIZONTAL_BORDERS_ONLY)
         .set_content_arrangement(ContentArrangement::Dynamic);
 
-    match app_config.export_type {
-        cli::ExportType::EPUB => {
-            match generate_epubs(articles, &app_config, &mut successful_articles_table) {
-                Ok(_) => (),
-                Err(gen_epub_errors) => {
-                    errors.extend(gen_epub_errors);
+    let mut low_quality_extractions = Vec::new();
+    let mut duplicate_articles = Vec::new();
+    let mut timings = Timings::default();
+    let mut exported_files = Vec::new();
+    let mut metadata_rows = Vec::new();
+    if let Some(metadata_path) = &app_config.metadata_path {
+        set_article_table_header(&mut successful_articles_table);
+        let entries = download_metadata(&app_config, &bar, &mut errors);
+        bar.finish_with_message("Downloaded metadata");
+        for entry in &entries {
+            successful_articles_table.add_row(vec![entry.title.as_str()]);
+        }
+        match write_catalog(metadata_path, &entries) {
+            Ok(_) => exported_files.push(metadata_path.clone()),
+            Err(mut err) => {
+                err.set_article_source(metadata_path);
+                file_errors.push(err);
+            }
+        }
+    } else {
+        // Articles exported to their own EPUBs are written while the next ones download so that
+        // they are not all kept in memory. The other exports need all the articles at once
+        let is_exporting_while_downloading =
+            matches!(app_config.export_type, cli::ExportType::EPUB)
+                && app_config.merged.is_none()
+                && app_config.append_to.is_none()
+                && !app_config.is_generating_index
+                && app_config.opds_path.is_none();
+        let mut epub_writer = if is_exporting_while_downloading {
+            set_article_table_header(&mut successful_articles_table);
+            Some(ArticleEpubWriter::new(&app_config, &exported_files))
+        } else {
+            None
+        };
+        let mut articles = Vec::new();
+        let mut export_errors = Vec::new();
+        let mut duplicate_filter = app_config.dedupe_max_distance.map(DuplicateFilter::new);
+        download(
+            &app_config,
+            &bar,
+            &mut partial_downloads,
+            &mut errors,
+            |article| {
+                // The first of the duplicates to download is kept
+                if let Some(duplicate) = duplicate_filter
+                    .as_mut()
+                    .and_then(|filter| filter.check(&article))
+                {
+                    duplicate_articles.push(duplicate);
+                    return;
+                }
+                low_quality_extractions.extend(LowQualityExtraction::from_article(&article));
+                timings
+                    .articles
+                    .extend(article.response.as_ref().map(|response| ArticleTiming {
+                        link: article.url.clone(),
+                        timings: response.timings,
+                        generation: None,
+                    }));
+                match &mut epub_writer {
+                    Some(epub_writer) => {
+                        let written_epubs = epub_writer.export(
+                            article,
+                            &app_config,
+                            &mut successful_articles_table,
+                            &mut exported_files,
+                            &mut metadata_rows,
+                        );
+                        record_written_epubs(written_epubs, &mut timings, &mut export_errors);
+                    }
+                    None => articles.push(article),
+                }
+            },
+        );
+        bar.finish_with_message("Downloaded articles");
+        if let Some(epub_writer) = epub_writer {
+            let written_epubs = epub_writer.finish(
+                &app_config,
+                &mut successful_articles_table,
+                &mut exported_files,
+                &mut metadata_rows,
+            );
+            record_written_epubs(written_epubs, &mut timings, &mut export_errors);
+        }
+        errors.extend(export_errors);
+        // Articles that failed to download are not in the list so the first article that was
+        // downloaded names the merge
+        if app_config.is_naming_merge_from_first {
+            if let Some(article) = articles.first() {
+                app_config.rename_merged(&article.file_stem(app_config.is_including_url_in_title));
+            }
+            if let Some(merged_name) = &app_config.merged {
+                if app_config.can_print_to_stdout() {
+                    println!("Merging articles into {}", merged_name);
+                }
+            }
+        }
+        // The seed is shown so that the same order can be generated again
+        if let cli::ArticleOrder::Shuffle(seed) = app_config.article_order {
+            if app_config.can_print_to_stdout() {
+                println!("Shuffled the articles with the seed {}", seed);
+            }
+        }
+        arrange_articles(&mut articles, &app_config.article_order);
+
+        let generation_start = Instant::now();
+        match app_config.export_type {
+            cli::ExportType::EPUB => {
+                match generate_epubs(
+                    articles,
+                    &app_config,
+                    &mut successful_articles_table,
+                    &mut exported_files,
+                    &mut metadata_rows,
+                    &mut file_errors,
+                ) {
+                    Ok(_) => (),
+                    Err(gen_epub_errors) => {
+                        errors.extend(gen_epub_errors);
+                    }
+                };
+            }
+            cli::ExportType::HTML => {
+                match generate_html_exports(
+                    articles,
+                    &app_config,
+                    &mut successful_articles_table,
+                    &mut exported_files,
+                    &mut metadata_rows,
+                ) {
+                    Ok(_) => (),
+                    Err(gen_html_errors) => errors.extend(gen_html_errors),
+                }
+            }
+            cli::ExportType::TXT => {
+                match generate_txt_exports(
+                    articles,
+                    &app_config,
+                    &mut successful_articles_table,
+                    &mut exported_files,
+                    &mut metadata_rows,
+                ) {
+                    Ok(_) => (),
+                    Err(gen_txt_errors) => errors.extend(gen_txt_errors),
                 }
-            };
+            }
+        }
+        timings.generation += generation_start.elapsed();
+    }
+
+    if let (Some(csv_path), false) = (&app_config.metadata_csv, metadata_rows.is_empty()) {
+        match write_metadata_csv(csv_path, &metadata_rows) {
+            Ok(_) => exported_files.push(csv_path.clone()),
+            Err(mut err) => {
+                err.set_article_source(csv_path);
+                file_errors.push(err);
+            }
         }
-        cli::ExportType::HTML => {
-            match generate_html_exports(articles, &app_config, &mut successful_articles_table) {
-                Ok(_) => (),
-                Err(gen_html_errors) => errors.extend(gen_html_errors),
+    }
+
+    let mut zip_bundle = None;
+    if let Some(bundle_path) = &app_config.zip_bundle {
+        if !exported_files.is_empty() {
+            let output_directory = app_config.output_directory.as_deref().unwrap_or(".");
+            match create_zip_bundle(bundle_path, &exported_files, output_directory) {
+                Ok(file_count) => zip_bundle = Some((bundle_path, file_count)),
+                Err(mut err) => {
+                    err.set_article_source(bundle_path);
+                    file_errors.push(err);
+                }
             }
         }
     }
 
-    let has_errors = !errors.is_empty() || !partial_downloads.is_empty();
+    let has_errors = !errors.is_empty() || !file_errors.is_empty() || !partial_downloads.is_empty();
+    // The summary goes to stderr when the EPUB or progress events are written to stdout so
+    // that it is not mixed with them
+    let mut summary_out: Box<dyn Write> = if app_config.can_print_to_stdout() {
+        Box::new(io::stdout())
+    } else {
+        Box::new(io::stderr())
+    };
+    timings.total = run_start.elapsed();
     display_summary(
-        app_config.urls.len(),
+        article_count,
         successful_articles_table,
         partial_downloads,
+        low_quality_extractions,
+        duplicate_articles,
         errors,
-    );
+        file_errors,
+        app_config.is_showing_timings.then(|| timings),
+        &mut summary_out,
+    )
+    .expect("Unable to display the summary");
+    if filtered_count > 0 {
+        writeln!(
+            summary_out,
+            "Left out {} URL{} with --include or --exclude",
+            filtered_count,
+            if filtered_count == 1 { "" } else { "s" }
+        )
+        .expect("Unable to display the summary");
+    }
+    if let Some((bundle_path, file_count)) = zip_bundle {
+        writeln!(
+            summary_out,
+            "Bundled {} file{} into {:?}",
+            file_count,
+            if file_count == 1 { "" } else { "s" },
+            bundle_path
+        )
+        .expect("Unable to display the summary");
+    }
 
     if app_config.is_logging_to_file {
-        println!(
+        writeln!(
+            summary_out,
             "Log written to paperoni_{}.log\n",
             app_config.start_time.format("%Y-%m-%d_%H-%M-%S")
-        );
+        )
+        .expect("Unable to display the summary");
     } else if has_errors && !app_config.is_logging_to_file {
-        println!("\nRun paperoni with the --log-to-file flag to create a log file");
+        writeln!(
+            summary_out,
+            "\nRun paperoni with the --log-to-file flag to create a log file"
+        )
+        .expect("Unable to display the summary");
     }
 
     if has_errors {
         std::process::exit(1);
     }
 }
+
+/// Adds the time spent on the EPUBs written while downloading to the timings and collects
+/// the EPUBs that failed
+fn record_written_epubs(
+    written_epubs: Vec<WrittenEpub>,
+    timings: &mut Timings,
+    export_errors: &mut Vec<errors::PaperoniError>,
+) {
+    for written_epub in written_epubs {
+        timings.generation += written_epub.generation;
+        if let Some(article_timing) = timings
+            .articles
+            .iter_mut()
+            .rev()
+            .find(|article_timing| article_timing.link == written_epub.url)
+        {
+            article_timing.generation = Some(written_epub.generation);
+        }
+        if let Err(error) = written_epub.result {
+            export_errors.push(error);
+        }
+    }
+}
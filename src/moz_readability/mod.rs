@@ -13,6 +13,8 @@ use url::Url;
 use crate::errors::{ErrorKind, PaperoniError};
 
 const DEFAULT_CHAR_THRESHOLD: usize = 500;
+const DEFAULT_N_TOP_CANDIDATES: usize = 5;
+const DEFAULT_MIN_PARAGRAPH_LENGTH: usize = 25;
 const FLAG_STRIP_UNLIKELYS: u32 = 0x1;
 const FLAG_WEIGHT_CLASSES: u32 = 0x2;
 const FLAG_CLEAN_CONDITIONALLY: u32 = 0x4;
@@ -29,7 +31,8 @@ const PHRASING_ELEMS: [&str; 39] = [
 const DEFAULT_TAGS_TO_SCORE: [&str; 9] =
     ["section", "h2", "h3", "h4", "h5", "h6", "p", "td", "pre"];
 // TODO: Change to HashSet
-const ALTER_TO_DIV_EXCEPTIONS: [&str; 4] = ["div", "article", "section", "p"];
+/// Definition lists are kept as is since their <dt> and <dd> children are only valid in a <dl>
+const ALTER_TO_DIV_EXCEPTIONS: [&str; 5] = ["div", "article", "section", "p", "dl"];
 const PRESENTATIONAL_ATTRIBUTES: [&str; 12] = [
     "align",
     "background",
@@ -59,6 +62,27 @@ pub struct Readability {
     article_dir: Option<String>,
     flags: u32,
     pub metadata: MetaData,
+    pub options: ReadabilityOptions,
+}
+
+/// Options that change how an article is extracted. These mirror the options object
+/// that can be passed to the original Readability.js
+#[derive(Clone, Debug, Default)]
+pub struct ReadabilityOptions {
+    /// Keeps the LaTeX source of MathJax `<script type="math/tex">` elements as delimited text
+    pub keep_latex: bool,
+    /// Skips removing the class attribute of elements in the article
+    pub keep_classes: bool,
+    /// Classes that are not removed from the elements in the article
+    pub classes_to_preserve: Vec<String>,
+    /// The number of characters an article must have for the extraction to be considered
+    /// successful. Defaults to 500
+    pub char_threshold: Option<usize>,
+    /// The number of top candidates considered when picking the element holding the content.
+    /// Defaults to 5
+    pub n_top_candidates: Option<usize>,
+    /// Paragraphs shorter than this number of characters are not scored. Defaults to 25
+    pub min_paragraph_length: Option<usize>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -77,10 +101,20 @@ impl Readability {
             article_dir: None,
             flags: FLAG_STRIP_UNLIKELYS | FLAG_WEIGHT_CLASSES | FLAG_CLEAN_CONDITIONALLY,
             metadata: MetaData::new(),
+            options: ReadabilityOptions::default(),
         }
     }
+
+    /// Reads the metadata of the document without extracting the article
+    pub fn parse_metadata(&self) -> MetaData {
+        self.get_article_metadata()
+    }
+
     pub fn parse(&mut self, url: &str) -> Result<(), PaperoniError> {
         self.unwrap_no_script_tags();
+        if self.options.keep_latex {
+            self.preserve_latex_scripts();
+        }
         self.remove_scripts();
         self.prep_document();
         self.metadata = self.get_article_metadata();
@@ -205,6 +239,35 @@ impl Readability {
         }
     }
 
+    /// Replaces MathJax `<script type="math/tex">` elements with their LaTeX source wrapped in
+    /// `\(...\)` delimiters, or `\[...\]` for display math, so that the math is not lost when
+    /// scripts are removed.
+    fn preserve_latex_scripts(&mut self) {
+        let latex_scripts = self
+            .root_node
+            .select("script[type^=\"math/tex\"]")
+            .unwrap()
+            .collect::<Vec<_>>();
+        for script_ref in latex_scripts {
+            let is_display_mode = {
+                let attrs = script_ref.attributes.borrow();
+                attrs
+                    .get("type")
+                    .map(|val| val.contains("mode=display"))
+                    .unwrap_or(false)
+            };
+            let latex = script_ref.text_contents();
+            let delimited_latex = if is_display_mode {
+                format!("\\[{}\\]", latex.trim())
+            } else {
+                format!("\\({}\\)", latex.trim())
+            };
+            let script_node = script_ref.as_node();
+            script_node.insert_before(NodeRef::new_text(delimited_latex));
+            script_node.detach();
+        }
+    }
+
     /// Removes script tags from the document.
     fn remove_scripts(&mut self) {
         match self.root_node.select("script") {
@@ -510,6 +573,69 @@ impl Readability {
         meta_data.site_name = values
             .get("og:site_name")
             .map(|site_name| site_name.to_owned());
+        meta_data.og_title = values
+            .get("og:title")
+            .filter(|og_title| !og_title.is_empty())
+            .map(|og_title| og_title.to_owned());
+        meta_data.page_title = self
+            .root_node
+            .select_first("title")
+            .map(|title| title.text_contents().trim().to_owned())
+            .ok()
+            .filter(|page_title| !page_title.is_empty());
+
+        let first_meta_content = |selectors: &[&str]| {
+            selectors.iter().find_map(|selector| {
+                self.root_node
+                    .select_first(selector)
+                    .ok()
+                    .and_then(|meta| {
+                        meta.attributes
+                            .borrow()
+                            .get("content")
+                            .map(|content| content.trim().to_owned())
+                    })
+                    .filter(|content| !content.is_empty())
+            })
+        };
+        // The image properties are matched exactly since og:image:width and similar properties
+        // describe the image rather than point to it
+        meta_data.lead_image = first_meta_content(&[
+            "meta[property='og:image']",
+            "meta[property='og:image:url']",
+            "meta[property='og:image:secure_url']",
+            "meta[name='twitter:image'], meta[property='twitter:image']",
+        ]);
+        meta_data.published_time = first_meta_content(&[
+            "meta[property='article:published_time']",
+            "meta[itemprop='datePublished']",
+            "meta[name='parsely-pub-date']",
+            "meta[name='date'], meta[name='dc.date'], meta[name='dcterms.date']",
+        ]);
+        meta_data.section = first_meta_content(&[
+            "meta[property='article:section']",
+            "meta[name='parsely-section']",
+        ]);
+        // The Content-Language header may list several languages so only the first is used
+        meta_data.lang = self
+            .root_node
+            .select_first("html")
+            .ok()
+            .and_then(|html| html.attributes.borrow().get("lang").map(str::to_owned))
+            .or_else(|| {
+                first_meta_content(&[
+                    "meta[http-equiv='content-language'], meta[http-equiv='Content-Language']",
+                ])
+            })
+            .and_then(|lang| lang.split(',').next().map(|lang| lang.trim().to_owned()))
+            .filter(|lang| !lang.is_empty());
+        meta_data.favicon = self
+            .root_node
+            .select_first("link[rel~='icon'][href]")
+            .ok()
+            .and_then(|link| link.attributes.borrow().get("href").map(str::to_owned))
+            .map(|href| href.trim().to_owned())
+            .filter(|href| !href.is_empty());
 
         Self::unescape_html_entities(&mut meta_data.title);
         if meta_data.byline.is_some() {
@@ -524,37 +650,84 @@ impl Readability {
             Self::unescape_html_entities(&mut meta_data.site_name.as_mut().unwrap());
         }
 
+        if meta_data.og_title.is_some() {
+            Self::unescape_html_entities(&mut meta_data.og_title.as_mut().unwrap());
+        }
+
+        if meta_data.section.is_some() {
+            Self::unescape_html_entities(&mut meta_data.section.as_mut().unwrap());
+        }
+
         meta_data
     }
 
     /// Converts some of the common HTML entities in string to their corresponding characters.
     fn unescape_html_entities(value: &mut String) {
-        if !value.is_empty() {
-            // TODO: Extract this
-            let mut html_escape_map: HashMap<&str, &str> = HashMap::new();
-            html_escape_map.insert("lt", "<");
-            html_escape_map.insert("gt", ">");
-            html_escape_map.insert("amp", "&");
-            html_escape_map.insert("quot", "\"");
-            html_escape_map.insert("apos", "'");
-            let mut new_value = regexes::REPLACE_HTML_ESCAPE_REGEX
-                .replace_all(&value, |captures: &regex::Captures| {
-                    html_escape_map[&captures[1]].to_string()
-                })
-                .to_string();
-            new_value = regexes::REPLACE_HEX_REGEX
-                .replace_all(&new_value, |captures: &regex::Captures| {
-                    let num = if let Some(hex_capture) = captures.get(1) {
-                        u16::from_str_radix(hex_capture.as_str(), 16)
-                    } else if let Some(dec_capture) = captures.get(2) {
-                        u16::from_str(dec_capture.as_str())
-                    } else {
-                        unreachable!("Unable to match any of the captures");
-                    };
-                    String::from_utf16_lossy(&[num.unwrap()])
-                })
-                .to_string();
-            *value = new_value;
+        if !value.contains('&') {
+            return;
+        }
+        let new_value = regexes::REPLACE_HTML_ESCAPE_REGEX
+            .replace_all(&value, |captures: &regex::Captures| {
+                match &captures[1] {
+                    "lt" => "<",
+                    "gt" => ">",
+                    "amp" => "&",
+                    "quot" => "\"",
+                    "apos" => "'",
+                    "nbsp" => "\u{a0}",
+                    "ndash" => "–",
+                    "mdash" => "—",
+                    "hellip" => "…",
+                    "lsquo" => "‘",
+                    "rsquo" => "’",
+                    "ldquo" => "“",
+                    "rdquo" => "”",
+                    "laquo" => "«",
+                    "raquo" => "»",
+                    "bull" => "•",
+                    "middot" => "·",
+                    "copy" => "©",
+                    "reg" => "®",
+                    "trade" => "™",
+                    _ => unreachable!("Unable to match any of the entities"),
+                }
+                .to_string()
+            })
+            .to_string();
+        let new_value = regexes::REPLACE_HEX_REGEX
+            .replace_all(&new_value, |captures: &regex::Captures| {
+                let num = if let Some(hex_capture) = captures.get(1) {
+                    u32::from_str_radix(hex_capture.as_str(), 16)
+                } else if let Some(dec_capture) = captures.get(2) {
+                    u32::from_str(dec_capture.as_str())
+                } else {
+                    unreachable!("Unable to match any of the captures");
+                };
+                // Code points that are out of range are replaced like browsers do
+                num.ok()
+                    .and_then(std::char::from_u32)
+                    .unwrap_or(std::char::REPLACEMENT_CHARACTER)
+                    .to_string()
+            })
+            .to_string();
+        *value = new_value;
+    }
+
+    /// Decodes the entities left in the text of the article. These are usually double escaped
+    /// in the source e.g `&amp;#8217;` and would otherwise be displayed as is. The text of code
+    /// blocks is left untouched since entities in it are likely to be intentional. The characters
+    /// significant to XML are escaped again when the article is serialized.
+    fn unescape_text_entities(&mut self) {
+        if let Some(article_node) = &mut self.article_node {
+            for text_node in article_node.inclusive_descendants().text_nodes() {
+                let is_in_code = text_node.as_node().ancestors().elements().any(|elem| {
+                    matches!(elem.name.local.as_ref(), "code" | "pre" | "kbd" | "samp")
+                });
+                if is_in_code {
+                    continue;
+                }
+                Self::unescape_html_entities(&mut text_node.borrow_mut());
+            }
         }
     }
 
@@ -623,8 +796,12 @@ impl Readability {
     /// Removes the class="" attribute from every element in the given subtree, except those that
     /// match CLASSES_TO_PRESERVE and the classesToPreserve array from the options object.
     fn clean_classes(&mut self) {
-        // TODO: This should accessed from Self
-        let classes_to_preserve: HashSet<&str> = HashSet::new();
+        let classes_to_preserve: HashSet<&str> = self
+            .options
+            .classes_to_preserve
+            .iter()
+            .map(String::as_str)
+            .collect();
         if let Some(article_node) = &mut self.article_node {
             for elem in article_node.inclusive_descendants().elements() {
                 let mut elem_attrs = elem.attributes.borrow_mut();
@@ -632,7 +809,9 @@ impl Readability {
                     let filtered_class: String = class_list
                         .split_whitespace()
                         .filter(|class| classes_to_preserve.contains(class))
-                        .fold("".to_string(), |acc, x| acc + " " + x);
+                        .fold("".to_string(), |acc, x| acc + " " + x)
+                        .trim_start()
+                        .to_string();
                     if filtered_class.is_empty() {
                         elem_attrs.remove("class");
                     } else {
@@ -776,9 +955,11 @@ impl Readability {
     /// Run any post-process modifications to article content as necessary.
     fn post_process_content(&mut self, url: &str) {
         self.fix_relative_uris(url);
-        // TODO: Add flag check
-        self.clean_classes();
+        if !self.options.keep_classes {
+            self.clean_classes();
+        }
         self.clean_readability_attrs();
+        self.unescape_text_entities();
     }
 
     /// Converts an inline CSS string to a [HashMap] of property and value(s)
@@ -1322,6 +1503,7 @@ impl Readability {
             attrs.get("readability-data-table") == Some("true")
         };
         let get_char_count = |node_ref: &NodeRef| node_ref.text_contents().matches(",").count();
+        let cited_ids = Self::get_cited_ids(node_ref);
 
         let mut nodes = node_ref
             .descendants()
@@ -1344,6 +1526,11 @@ impl Readability {
         while let Some(node_data_ref) = next_node {
             next_node = nodes.next();
             let node = node_data_ref.as_node();
+            // Reference lists are kept so that the citations linking to them still work even
+            // though they are dense with links and often have classes such as footnotes
+            if Self::has_cited_list_items(node, &cited_ids) {
+                continue;
+            }
             let weight = self.get_class_weight(node);
             // Remove all elements with negative class weights
             if weight < 0 {
@@ -1400,6 +1587,47 @@ impl Readability {
         }
     }
 
+    /// Returns the ids that links within the node point to, such as those of in-text citations
+    fn get_cited_ids(node_ref: &NodeRef) -> HashSet<String> {
+        node_ref
+            .select("a[href^='#']")
+            .unwrap()
+            .filter_map(|link_ref| {
+                let attrs = link_ref.attributes.borrow();
+                attrs
+                    .get("href")
+                    .map(|href| href.trim_start_matches('#').to_string())
+            })
+            .filter(|id| !id.is_empty())
+            .collect()
+    }
+
+    /// Checks whether the node holds list items that are linked to from the article, which is the
+    /// case for reference lists and footnotes
+    fn has_cited_list_items(node_ref: &NodeRef, cited_ids: &HashSet<String>) -> bool {
+        if cited_ids.is_empty() {
+            return false;
+        }
+        node_ref
+            .inclusive_descendants()
+            .select("li")
+            .unwrap()
+            .any(|li_ref| {
+                li_ref
+                    .as_node()
+                    .inclusive_descendants()
+                    .elements()
+                    .any(|elem| {
+                        let attrs = elem.attributes.borrow();
+                        attrs.get("id").map_or(false, |id| cited_ids.contains(id))
+                            || (&elem.name.local == "a"
+                                && attrs
+                                    .get("name")
+                                    .map_or(false, |name| cited_ids.contains(name)))
+                    })
+            })
+    }
+
     /// Clean a node of all elements of type "tag". (Unless it's a YouTube or Vimeo video)
     fn clean(node_ref: &mut NodeRef, tag_name: &str) {
         // Can be changed to a HashSet
@@ -1476,6 +1704,13 @@ impl Readability {
         }
     }
 
+    /// The number of characters an article needs for its content to be accepted
+    fn char_threshold(&self) -> usize {
+        self.options
+            .char_threshold
+            .unwrap_or(DEFAULT_CHAR_THRESHOLD)
+    }
+
     /// Prepare the article node for display. Clean out any inline styles, iframes,
     /// forms, strip extraneous <p> tags, etc.
     fn prep_article(&mut self, node_ref: &mut NodeRef) {
@@ -1491,10 +1726,11 @@ impl Readability {
         Self::clean(node_ref, "link");
         Self::clean(node_ref, "aside");
 
+        let char_threshold = self.char_threshold();
         node_ref.children().for_each(|mut node| {
             Self::clean_matched_nodes(&mut node, |node: &NodeRef, match_string| {
                 regexes::is_match_share_elems(match_string)
-                    && node.text_contents().len() < DEFAULT_CHAR_THRESHOLD
+                    && node.text_contents().len() < char_threshold
             });
         });
 
@@ -1735,6 +1971,10 @@ impl Readability {
             }
 
             let mut candidates: Vec<NodeRef> = Vec::new();
+            let min_paragraph_length = self
+                .options
+                .min_paragraph_length
+                .unwrap_or(DEFAULT_MIN_PARAGRAPH_LENGTH);
             elements_to_score
                 .iter()
                 .filter(|node_ref| {
@@ -1742,7 +1982,7 @@ impl Readability {
                     parent.is_some() && parent.unwrap().as_element().is_some()
                 })
                 .map(|node_ref| (node_ref, Self::get_inner_text(&node_ref, None)))
-                .filter(|(_, inner_text)| inner_text.len() >= 25)
+                .filter(|(_, inner_text)| inner_text.len() >= min_paragraph_length)
                 .map(|(node_ref, inner_text)| {
                     (inner_text, Self::get_node_ancestors(&node_ref, Some(3)))
                 })
@@ -1800,7 +2040,10 @@ impl Readability {
                         *readability_score = candidate_score.to_string();
                     }
                 }
-                let nb_top_candidates = 5;
+                let nb_top_candidates = self
+                    .options
+                    .n_top_candidates
+                    .unwrap_or(DEFAULT_N_TOP_CANDIDATES);
                 for i in 0..nb_top_candidates {
                     let top_candidate = top_candidates.get(i);
                     let top_candidate_score = top_candidate
@@ -1980,6 +2223,7 @@ impl Readability {
                     .map(|score| score.parse::<f32>().unwrap())
                     .unwrap()
             };
+            self.metadata.content_score = Some(top_candidate_score);
 
             let sibling_score_threshold = (10.0_f32).max(top_candidate_score * 0.2);
             parent_of_top_candidate = top_candidate.parent().unwrap();
@@ -2026,8 +2270,12 @@ impl Readability {
                         && (sibling_score.unwrap() + content_bonus) >= sibling_score_threshold
                     {
                         append = true;
-                    } else if sibling.as_element().map(|elem| elem.name.local.as_ref()) == Some("p")
-                    {
+                    } else if matches!(
+                        sibling.as_element().map(|elem| elem.name.local.as_ref()),
+                        Some("p") | Some("dl")
+                    ) {
+                        // Definition lists are not scored since their text is in <dd> elements
+                        // so they are checked like paragraphs
                         let link_density = Self::get_link_density(&sibling);
                         let node_content = Self::get_inner_text(&sibling, None);
                         let node_length = node_content.len();
@@ -2080,7 +2328,7 @@ impl Readability {
 
             let text_length = Self::get_inner_text(&article_content, Some(true)).len();
             let mut parse_successful = true;
-            if text_length < DEFAULT_CHAR_THRESHOLD {
+            if text_length < self.char_threshold() {
                 parse_successful = false;
                 if self.flag_is_active(FLAG_STRIP_UNLIKELYS) {
                     self.remove_flag(FLAG_STRIP_UNLIKELYS);
@@ -2150,6 +2398,22 @@ pub struct MetaData {
     excerpt: Option<String>,
     site_name: Option<String>,
     title: String,
+    /// The og:title of the page, which sites usually set to the headline without their name
+    og_title: Option<String>,
+    /// The text of the page's `<title>` element
+    page_title: Option<String>,
+    /// The image that represents the article, such as the one shown in link previews
+    lead_image: Option<String>,
+    /// When the article was published, as written in the page
+    published_time: Option<String>,
+    /// The section of the site the article was published in, such as "Technology"
+    section: Option<String>,
+    /// The language the page declares it is written in
+    lang: Option<String>,
+    /// The URL of the site's icon as written in the page
+    favicon: Option<String>,
+    /// The content score of the element that was picked as the article's content
+    content_score: Option<f32>,
 }
 
 impl MetaData {
@@ -2159,6 +2423,14 @@ impl MetaData {
             excerpt: None,
             site_name: None,
             title: "".into(),
+            og_title: None,
+            page_title: None,
+            lead_image: None,
+            published_time: None,
+            section: None,
+            lang: None,
+            favicon: None,
+            content_score: None,
         }
     }
 
@@ -2166,9 +2438,53 @@ impl MetaData {
         &self.title
     }
 
+    pub fn set_title(&mut self, title: String) {
+        self.title = title;
+    }
+
     pub fn byline(&self) -> Option<&String> {
         self.byline.as_ref()
     }
+
+    pub fn excerpt(&self) -> Option<&String> {
+        self.excerpt.as_ref()
+    }
+
+    pub fn site_name(&self) -> Option<&String> {
+        self.site_name.as_ref()
+    }
+
+    pub fn og_title(&self) -> Option<&String> {
+        self.og_title.as_ref()
+    }
+
+    pub fn page_title(&self) -> Option<&String> {
+        self.page_title.as_ref()
+    }
+
+    pub fn lead_image(&self) -> Option<&String> {
+        self.lead_image.as_ref()
+    }
+
+    pub fn published_time(&self) -> Option<&String> {
+        self.published_time.as_ref()
+    }
+
+    pub fn section(&self) -> Option<&String> {
+        self.section.as_ref()
+    }
+
+    pub fn lang(&self) -> Option<&String> {
+        self.lang.as_ref()
+    }
+
+    pub fn favicon(&self) -> Option<&String> {
+        self.favicon.as_ref()
+    }
+
+    pub fn content_score(&self) -> Option<f32> {
+        self.content_score
+    }
 }
 
 #[cfg(test)]
@@ -2240,6 +2556,31 @@ mod test {
         assert_eq!(0, noscript_elems.count());
     }
 
+    #[test]
+    fn test_preserve_latex_scripts() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <p>Inline <script type="math/tex">x^2</script> math</p>
+                <p><script type="math/tex; mode=display">a < b</script></p>
+                <script>console.log("Not math")</script>
+            </body>
+        </html>
+        "#;
+        let mut readability = Readability::new(html_str);
+        readability.preserve_latex_scripts();
+        readability.remove_scripts();
+        assert_eq!(0, readability.root_node.select("script").unwrap().count());
+
+        let mut p_elems = readability.root_node.select("p").unwrap();
+        assert_eq!(
+            "Inline \\(x^2\\) math",
+            p_elems.next().unwrap().text_contents()
+        );
+        assert_eq!("\\[a < b\\]", p_elems.next().unwrap().text_contents());
+    }
+
     #[test]
     fn test_next_element() {
         let html_str = r#"
@@ -3282,6 +3623,33 @@ characters. For that reason, this <p> tag could not be a byline because it's too
         assert_eq!(false, doc.root_node.select_first("div#footer").is_ok());
     }
 
+    #[test]
+    fn test_clean_conditionally_keeps_cited_lists() {
+        let html_str = r##"
+        <!DOCTYPE html>
+        <html>
+            <body>
+                <p>Salami is cured for weeks<a href="#cite-1">[1]</a>.</p>
+                <div class="footnotes" id="references">
+                    <ol>
+                        <li id="cite-1"><a href="https://example.com/curing">Curing meats</a></li>
+                    </ol>
+                </div>
+                <div class="footnotes" id="uncited">
+                    <ol>
+                        <li id="cite-2"><a href="https://example.com/smoking">Smoking meats</a></li>
+                    </ol>
+                </div>
+            </body>
+        </html>
+        "##;
+        let doc = Readability::new(html_str);
+        let body = doc.root_node.select_first("body").unwrap();
+        doc.clean_conditionally(&mut body.as_node().clone(), "div");
+        assert_eq!(true, doc.root_node.select_first("div#references").is_ok());
+        assert_eq!(false, doc.root_node.select_first("div#uncited").is_ok());
+    }
+
     #[test]
     fn test_clean() {
         let html_str = r#"
@@ -3719,6 +4087,41 @@ characters. For that reason, this <p> tag could not be a byline because it's too
         input = "&#x0158;&#x016d;&#x0161;&#x0163;".to_string();
         Readability::unescape_html_entities(&mut input);
         assert_eq!("Řŭšţ", input);
+        input = "Salami&rsquo;s &ldquo;Guide&rdquo; &mdash; &#128512; &#x110000;".to_string();
+        Readability::unescape_html_entities(&mut input);
+        assert_eq!("Salami’s “Guide” — 😀 \u{fffd}", input);
+    }
+
+    #[test]
+    fn test_unescape_text_entities() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Tom &amp;amp; Jerry&amp;#8217;s Greatest Cartoons &amp;lt;3</title></head>
+            <body>
+                <p>It&amp;#8217;s &amp;lt;b&amp;gt; &amp;amp; more&amp;hellip;</p>
+                <pre>&amp;lt;p&amp;gt;</pre>
+            </body>
+        </html>
+        "#;
+        let mut doc = Readability::new(html_str);
+        assert_eq!(
+            "Tom & Jerry’s Greatest Cartoons <3",
+            doc.get_article_metadata().title()
+        );
+
+        doc.article_node = doc
+            .root_node
+            .select_first("body")
+            .ok()
+            .map(|node_ref| node_ref.as_node().clone());
+        doc.unescape_text_entities();
+        let p = doc.root_node.select_first("p").unwrap();
+        assert_eq!("It’s <b> & more…", p.text_contents());
+        // The decoded characters are escaped again when serialized
+        assert_eq!("<p>It’s &lt;b&gt; &amp; more…</p>", p.as_node().to_string());
+        let pre = doc.root_node.select_first("pre").unwrap();
+        assert_eq!("&lt;p&gt;", pre.text_contents());
     }
 
     #[test]
@@ -3741,12 +4144,14 @@ characters. For that reason, this <p> tag could not be a byline because it's too
         result.byline = Some("Foo Coder".to_string());
         result.excerpt = Some("A post on how hard it is to work with text.".to_string());
         result.title = "Blog on the difficulty of using utf-8".to_string();
+        result.page_title = Some("Foo Coder / Blog on the difficulty of using utf-8".to_string());
         assert_eq!(result, doc.get_article_metadata());
 
         html_str = r#"
         <!DOCTYPE html>
         <html>
             <head>
+                <meta http-equiv="Content-Language" content="de-DE, en" />
                 <meta http-equiv="Content-Type" content="text/html; charset=utf-8" />
                 <meta name="viewport" content="width=device-width, initial-scale=1, maximum-scale=1" user-scalable="no" />
                 <meta name="title" content="A Long Title" />
@@ -3757,6 +4162,12 @@ characters. For that reason, this <p> tag could not be a byline because it's too
                 <meta name="author" content="F&#x00f6;o Coder" />
                 <meta name="dc:creator" content="F&#x00f6;o Coder" />
                 <meta name="twitter:card" content="summary_large_image" />
+                <meta property="og:image:width" content="1200" />
+                <meta property="og:image" content="https://example.com/hero.jpg" />
+                <meta property="article:published_time" content="2021-06-01T08:00:00Z" />
+                <meta property="article:section" content="Science &amp; Tech" />
+                <meta name="twitter:image" content="https://example.com/card.jpg" />
+                <link rel="shortcut icon" href="/favicon.png" />
                 <title>The Longest Title</title>
             </head>
         </html>
@@ -3767,6 +4178,13 @@ characters. For that reason, this <p> tag could not be a byline because it's too
         result.excerpt = Some("Foo bar baz boß".to_string());
         result.site_name = Some("Blog Place".to_string());
         result.title = "A Longer Title".to_string();
+        result.og_title = Some("A Longer Title".to_string());
+        result.page_title = Some("The Longest Title".to_string());
+        result.lead_image = Some("https://example.com/hero.jpg".to_string());
+        result.published_time = Some("2021-06-01T08:00:00Z".to_string());
+        result.section = Some("Science & Tech".to_string());
+        result.lang = Some("de-DE".to_string());
+        result.favicon = Some("/favicon.png".to_string());
         assert_eq!(result, doc.get_article_metadata());
     }
 
@@ -3886,6 +4304,38 @@ characters. For that reason, this <p> tag could not be a byline because it's too
                     !node_attrs.contains("class")
                 })
         );
+
+        let mut doc = Readability::new(html_str);
+        doc.options.classes_to_preserve = vec!["a".into(), "e".into()];
+        doc.article_node = doc
+            .root_node
+            .select_first("body")
+            .ok()
+            .map(|node_ref| node_ref.as_node().clone());
+        doc.clean_classes();
+
+        let classes = doc
+            .root_node
+            .select("p, div, ul, li")
+            .unwrap()
+            .map(|node_elem| {
+                let node_attrs = node_elem.attributes.borrow();
+                node_attrs.get("class").map(ToOwned::to_owned)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                Some("a".to_string()),
+                Some("e".to_string()),
+                Some("a".to_string()),
+                Some("e".to_string()),
+                Some("a".to_string()),
+                Some("a".to_string()),
+                Some("e".to_string()),
+                Some("e".to_string())
+            ],
+            classes
+        );
     }
 
     #[test]
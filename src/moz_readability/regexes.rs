@@ -124,9 +124,9 @@ lazy_static! {
     pub static ref REPLACE_WHITESPACE_REGEX: Regex = Regex::new(r"\s").unwrap();
     pub static ref REPLACE_DOT_REGEX: Regex = Regex::new(r"\.").unwrap();
     pub static ref REPLACE_HTML_ESCAPE_REGEX: Regex =
-        Regex::new("&(quot|amp|apos|lt|gt);").unwrap();
+        Regex::new("&(quot|amp|apos|lt|gt|nbsp|ndash|mdash|hellip|lsquo|rsquo|ldquo|rdquo|laquo|raquo|bull|middot|copy|reg|trade);").unwrap();
     pub static ref REPLACE_HEX_REGEX: Regex =
-        Regex::new(r"(?i)&#(?:x([0-9a-z]{1,4})|([0-9]{1,4}));").unwrap();
+        Regex::new(r"(?i)&#(?:x([0-9a-f]{1,6})|([0-9]{1,7}));").unwrap();
     pub static ref REPLACE_START_SEPARATOR_REGEX: Regex =
         Regex::new(r"(?i)(?P<start>.*)[\|\-\\/>»] .*").unwrap();
     pub static ref REPLACE_END_SEPARATOR_REGEX: Regex =
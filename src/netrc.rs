@@ -0,0 +1,173 @@
+use std::{fmt, fs, path::Path};
+
+use url::Url;
+
+use crate::errors::{ErrorKind, PaperoniError};
+
+/// The credentials of a machine, or of the default entry, in a netrc file
+#[derive(Clone)]
+struct NetrcEntry {
+    /// The host the credentials are used for. The default entry has none
+    machine: Option<String>,
+    login: Option<String>,
+    password: Option<String>,
+}
+
+/// The credentials read from a netrc file that are sent as Basic auth to matching hosts
+#[derive(Clone, Default)]
+pub struct Netrc {
+    entries: Vec<NetrcEntry>,
+}
+
+// The credentials are left out so that they are not written to the logs
+impl fmt::Debug for Netrc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(
+                self.entries
+                    .iter()
+                    .map(|entry| entry.machine.as_deref().unwrap_or("default")),
+            )
+            .finish()
+    }
+}
+
+impl Netrc {
+    pub fn from_file(path: &Path) -> Result<Self, PaperoniError> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            PaperoniError::from(ErrorKind::NetrcError(format!(
+                "Unable to read {:?}: {}",
+                path, err
+            )))
+        })?;
+        Self::parse(&content).map_err(|mut err| {
+            err.set_article_source(&path.to_string_lossy());
+            err
+        })
+    }
+
+    /// Parses the `machine`, `default`, `login` and `password` tokens of a netrc file. Macro
+    /// definitions and lines starting with `#` are skipped.
+    pub fn parse(content: &str) -> Result<Self, PaperoniError> {
+        let netrc_error = |msg: String| PaperoniError::from(ErrorKind::NetrcError(msg));
+        let mut tokens = Vec::new();
+        let mut is_in_macdef = false;
+        for line in content.lines() {
+            // A macro definition ends at the first empty line
+            if is_in_macdef {
+                is_in_macdef = !line.trim().is_empty();
+                continue;
+            }
+            if line.trim_start().starts_with('#') {
+                continue;
+            }
+            let mut line_tokens = line.split_whitespace();
+            while let Some(token) = line_tokens.next() {
+                if token == "macdef" {
+                    line_tokens.next();
+                    is_in_macdef = true;
+                    break;
+                }
+                tokens.push(token);
+            }
+        }
+
+        let mut entries: Vec<NetrcEntry> = Vec::new();
+        let mut tokens = tokens.into_iter();
+        while let Some(token) = tokens.next() {
+            match token {
+                "machine" => {
+                    let machine = tokens
+                        .next()
+                        .ok_or_else(|| netrc_error("A machine has no name".to_owned()))?;
+                    entries.push(NetrcEntry {
+                        machine: Some(machine.to_lowercase()),
+                        login: None,
+                        password: None,
+                    });
+                }
+                "default" => entries.push(NetrcEntry {
+                    machine: None,
+                    login: None,
+                    password: None,
+                }),
+                "login" | "password" | "account" => {
+                    let value = tokens
+                        .next()
+                        .ok_or_else(|| netrc_error(format!("The {} has no value", token)))?;
+                    let entry = entries.last_mut().ok_or_else(|| {
+                        netrc_error(format!("The {} is not part of a machine entry", token))
+                    })?;
+                    match token {
+                        "login" => entry.login = Some(value.to_owned()),
+                        "password" => entry.password = Some(value.to_owned()),
+                        _ => (),
+                    }
+                }
+                _ => return Err(netrc_error(format!("Unexpected token {:?}", token))),
+            }
+        }
+        Ok(Self { entries })
+    }
+
+    /// Returns the value of the Authorization header for a request to `url`. Only the entry of
+    /// the URL's host is used, falling back to the default entry.
+    pub fn authorization(&self, url: &Url) -> Option<String> {
+        let host = url.host_str()?.to_lowercase();
+        let entry = self
+            .entries
+            .iter()
+            .find(|entry| entry.machine.as_deref() == Some(host.as_str()))
+            .or_else(|| self.entries.iter().find(|entry| entry.machine.is_none()))?;
+        let login = entry.login.as_deref()?;
+        let credentials = format!("{}:{}", login, entry.password.as_deref().unwrap_or(""));
+        Some(format!("Basic {}", base64::encode(credentials)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_netrc_authorization() {
+        let netrc = Netrc::parse(
+            "# Credentials for paywalled sites
+            machine news.example.org login reader password s3cret#1
+            macdef init
+            cd /pub
+            login ignored
+
+            machine Blog.example.org
+                login writer
+            default login anonymous password guest",
+        )
+        .unwrap();
+        let authorization = |url: &str| netrc.authorization(&Url::parse(url).unwrap());
+        assert_eq!(
+            Some(format!("Basic {}", base64::encode("reader:s3cret#1"))),
+            authorization("https://news.example.org/story")
+        );
+        assert_eq!(
+            Some(format!("Basic {}", base64::encode("writer:"))),
+            authorization("https://blog.example.org/post")
+        );
+        assert_eq!(
+            Some(format!("Basic {}", base64::encode("anonymous:guest"))),
+            authorization("https://example.com/")
+        );
+        assert_eq!(
+            None,
+            Netrc::parse("machine news.example.org login reader")
+                .unwrap()
+                .authorization(&Url::parse("https://example.com/").unwrap())
+        );
+    }
+
+    #[test]
+    fn test_netrc_parse_errors() {
+        assert!(Netrc::parse("login reader password s3cret").is_err());
+        assert!(Netrc::parse("machine news.example.org login").is_err());
+        assert!(Netrc::parse("machine news.example.org user reader").is_err());
+    }
+}
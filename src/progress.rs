@@ -0,0 +1,124 @@
+use std::io::{self, Write};
+
+use serde::Serialize;
+
+use crate::cli::{AppConfig, ProgressFormat};
+use crate::errors::PaperoniError;
+
+/// The state changes of an article that are reported with `--progress json`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProgressStage {
+    /// The article is about to be downloaded
+    Started,
+    /// The HTML of the article has been downloaded
+    Downloaded,
+    /// The content of the article has been extracted
+    Extracted,
+    /// The article has been added to an export
+    Generated,
+    /// The article could not be downloaded, extracted or exported
+    Failed,
+}
+
+#[derive(Debug, Serialize)]
+struct ProgressEvent<'a> {
+    event: ProgressStage,
+    url: &'a str,
+    /// Position of the URL in the list of URLs being downloaded. It is left out for URLs that
+    /// are not in the list, such as those of redirected articles.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    index: Option<usize>,
+    total: usize,
+    /// Path of the export for generated events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a str>,
+    /// Reason for failed events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+}
+
+/// Prints a progress event as a JSON line to stdout when JSON progress is enabled. Stdout is
+/// flushed straight away so that consumers receive the events as they happen.
+pub fn emit_progress(app_config: &AppConfig, stage: ProgressStage, url: &str) {
+    write_event(app_config, stage, url, None, None);
+}
+
+/// Emits a generated event for an article that was exported to `path`
+pub fn emit_generated(app_config: &AppConfig, url: &str, path: &str) {
+    write_event(app_config, ProgressStage::Generated, url, Some(path), None);
+}
+
+/// Emits a failed event for an article with the error as the reason
+pub fn emit_failed(app_config: &AppConfig, url: &str, error: &PaperoniError) {
+    write_event(
+        app_config,
+        ProgressStage::Failed,
+        url,
+        None,
+        Some(error.kind().to_string()),
+    );
+}
+
+fn write_event(
+    app_config: &AppConfig,
+    stage: ProgressStage,
+    url: &str,
+    path: Option<&str>,
+    reason: Option<String>,
+) {
+    if !matches!(app_config.progress_format, ProgressFormat::Json) {
+        return;
+    }
+    let event = ProgressEvent {
+        event: stage,
+        url,
+        index: app_config
+            .urls
+            .iter()
+            .position(|article_url| article_url == url),
+        total: app_config.urls.len(),
+        path,
+        reason,
+    };
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    if let Ok(line) = serde_json::to_string(&event) {
+        // Errors are ignored as there is nothing to report them to once stdout is closed
+        let _ = writeln!(stdout, "{}", line).and_then(|_| stdout.flush());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProgressEvent, ProgressStage};
+
+    #[test]
+    fn test_progress_event_serialization() {
+        let event = ProgressEvent {
+            event: ProgressStage::Generated,
+            url: "http://example.org/salami",
+            index: Some(2),
+            total: 3,
+            path: Some("./Salami.epub"),
+            reason: None,
+        };
+        assert_eq!(
+            r#"{"event":"generated","url":"http://example.org/salami","index":2,"total":3,"path":"./Salami.epub"}"#,
+            serde_json::to_string(&event).unwrap()
+        );
+
+        let event = ProgressEvent {
+            event: ProgressStage::Failed,
+            url: "http://example.org/eggs",
+            index: None,
+            total: 3,
+            path: None,
+            reason: Some("Request failed".to_owned()),
+        };
+        assert_eq!(
+            r#"{"event":"failed","url":"http://example.org/eggs","total":3,"reason":"Request failed"}"#,
+            serde_json::to_string(&event).unwrap()
+        );
+    }
+}
@@ -0,0 +1,262 @@
+use serde::{de::DeserializeOwned, Deserialize};
+use url::Url;
+
+use crate::errors::{ErrorKind, PaperoniError};
+use crate::http::HTMLResource;
+
+/// A post on a Mastodon instance whose thread is downloaded with the instance's public API
+/// instead of extracting the page, which is rendered with JavaScript
+#[derive(Debug, PartialEq)]
+pub struct MastodonStatus {
+    /// The scheme and host of the instance
+    origin: String,
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct Status {
+    id: String,
+    in_reply_to_id: Option<String>,
+    created_at: String,
+    content: String,
+    #[serde(default)]
+    spoiler_text: String,
+    account: Account,
+    #[serde(default)]
+    media_attachments: Vec<MediaAttachment>,
+}
+
+#[derive(Deserialize)]
+struct Account {
+    id: String,
+    acct: String,
+    #[serde(default)]
+    display_name: String,
+}
+
+#[derive(Deserialize)]
+struct MediaAttachment {
+    #[serde(rename = "type")]
+    kind: String,
+    url: Option<String>,
+    preview_url: Option<String>,
+    description: Option<String>,
+}
+
+/// The posts before and after a status in its conversation
+#[derive(Deserialize)]
+struct Context {
+    ancestors: Vec<Status>,
+    descendants: Vec<Status>,
+}
+
+impl MastodonStatus {
+    /// Recognizes the URLs of posts such as https://mastodon.social/@user/123 and
+    /// https://mastodon.social/users/user/statuses/123
+    pub fn from_url(url: &str) -> Option<Self> {
+        let url = Url::parse(url).ok()?;
+        let segments = url.path_segments()?.collect::<Vec<_>>();
+        let id = match segments.as_slice() {
+            [user, id] if user.starts_with('@') => *id,
+            ["users", _, "statuses", id] => *id,
+            _ => return None,
+        };
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        Some(Self {
+            origin: url.origin().ascii_serialization(),
+            id: id.to_owned(),
+        })
+    }
+
+    /// Downloads the post along with the posts of its author that it replies to and that reply
+    /// to it, and returns them as an HTML document for the extractor
+    pub async fn fetch_thread(
+        &self,
+        url: &str,
+        client: &surf::Client,
+    ) -> Result<HTMLResource, PaperoniError> {
+        let status_url = format!("{}/api/v1/statuses/{}", self.origin, self.id);
+        let status: Status = fetch_json(&status_url, client).await?;
+        let context: Context = fetch_json(&format!("{}/context", status_url), client).await?;
+        let host = Url::parse(&self.origin)?
+            .host_str()
+            .unwrap_or_default()
+            .to_owned();
+        Ok(HTMLResource {
+            final_url: url.to_owned(),
+            status: Some(200),
+            html: thread_html(&thread_statuses(status, context), &host),
+        })
+    }
+}
+
+async fn fetch_json<T: DeserializeOwned>(
+    url: &str,
+    client: &surf::Client,
+) -> Result<T, PaperoniError> {
+    let mut res = client.get(url).await?;
+    if !res.status().is_success() {
+        return Err(ErrorKind::HTTPError(format!(
+            "Non-success HTTP status code ({}) from {}",
+            res.status(),
+            url
+        ))
+        .into());
+    }
+    let body = res.body_string().await?;
+    serde_json::from_str(&body).map_err(|err| {
+        ErrorKind::HTTPError(format!("Invalid response from {}: {}", url, err)).into()
+    })
+}
+
+/// Returns the posts of the thread in order. The thread is the status along with the run of
+/// posts by the same author that it replies to and the replies the author added under it.
+/// Replies by other accounts are left out.
+fn thread_statuses(status: Status, context: Context) -> Vec<Status> {
+    let author_id = status.account.id.clone();
+    let mut thread = context
+        .ancestors
+        .into_iter()
+        .rev()
+        .take_while(|ancestor| ancestor.account.id == author_id)
+        .collect::<Vec<_>>();
+    thread.reverse();
+    thread.push(status);
+    // Descendants are listed depth first so a reply always comes after the post it replies to
+    for descendant in context.descendants {
+        let is_self_reply = descendant.account.id == author_id
+            && thread
+                .iter()
+                .any(|post| descendant.in_reply_to_id.as_ref() == Some(&post.id));
+        if is_self_reply {
+            thread.push(descendant);
+        }
+    }
+    thread
+}
+
+fn thread_html(thread: &[Status], host: &str) -> String {
+    let first = &thread[0];
+    let author = if first.account.display_name.trim().is_empty() {
+        format!("@{}", first.account.acct)
+    } else {
+        first.account.display_name.clone()
+    };
+    let posts = thread
+        .iter()
+        .map(|status| {
+            let warning = if status.spoiler_text.is_empty() {
+                String::new()
+            } else {
+                format!("<p><strong>{}</strong></p>", escape(&status.spoiler_text))
+            };
+            let media = status
+                .media_attachments
+                .iter()
+                .filter_map(|attachment| {
+                    let src = match attachment.kind.as_str() {
+                        "image" => attachment.url.as_ref(),
+                        _ => attachment.preview_url.as_ref(),
+                    }?;
+                    Some(format!(
+                        r#"<figure><img src="{}" alt="{}"></figure>"#,
+                        escape(src),
+                        escape(attachment.description.as_deref().unwrap_or_default())
+                    ))
+                })
+                .collect::<String>();
+            format!(
+                r#"<div class="paperoni-post">{}{}{}</div>"#,
+                warning, status.content, media
+            )
+        })
+        .collect::<String>();
+    format!(
+        r#"<!DOCTYPE html><html><head><title>Thread by {author}</title><meta name="author" content="{author}"><meta property="article:published_time" content="{date}"><meta property="og:site_name" content="{site}"></head><body><article>{posts}</article></body></html>"#,
+        author = escape(&author),
+        date = escape(&first.created_at),
+        site = escape(host),
+        posts = posts
+    )
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace("&", "&amp;")
+        .replace("<", "&lt;")
+        .replace(">", "&gt;")
+        .replace("\"", "&quot;")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mastodon_status_from_url() {
+        let status = |origin: &str, id: &str| {
+            Some(MastodonStatus {
+                origin: origin.to_owned(),
+                id: id.to_owned(),
+            })
+        };
+        assert_eq!(
+            status("https://mastodon.social", "109372"),
+            MastodonStatus::from_url("https://mastodon.social/@jane/109372")
+        );
+        assert_eq!(
+            status("https://fosstodon.org", "42"),
+            MastodonStatus::from_url("https://fosstodon.org/users/jane/statuses/42")
+        );
+        assert_eq!(
+            None,
+            MastodonStatus::from_url("https://medium.com/@jane/a-post-1234")
+        );
+        assert_eq!(
+            None,
+            MastodonStatus::from_url("https://mastodon.social/@jane")
+        );
+        assert_eq!(
+            None,
+            MastodonStatus::from_url("https://example.com/blog/42")
+        );
+    }
+
+    #[test]
+    fn test_thread_html() {
+        let status_json = |id: &str, reply_to: &str, account: &str, content: &str| {
+            format!(
+                r#"{{"id":"{}","in_reply_to_id":{},"created_at":"2022-11-20T10:00:00.000Z","content":"{}","spoiler_text":"","account":{{"id":"{}","acct":"{}","display_name":""}},"media_attachments":[]}}"#,
+                id, reply_to, content, account, account
+            )
+        };
+        let status: Status =
+            serde_json::from_str(&status_json("2", r#""1""#, "jane", "<p>Second post</p>"))
+                .unwrap();
+        let context: Context = serde_json::from_str(&format!(
+            r#"{{"ancestors":[{}],"descendants":[{},{}]}}"#,
+            status_json("1", "null", "jane", "<p>First post</p>"),
+            status_json("3", r#""2""#, "bob", "<p>Nice thread</p>"),
+            status_json("4", r#""2""#, "jane", "<p>Third post</p>"),
+        ))
+        .unwrap();
+        let thread = thread_statuses(status, context);
+        assert_eq!(
+            vec!["1", "2", "4"],
+            thread
+                .iter()
+                .map(|post| post.id.as_str())
+                .collect::<Vec<_>>()
+        );
+
+        let html = thread_html(&thread, "mastodon.social");
+        assert!(html.contains("<title>Thread by @jane</title>"));
+        assert!(html.contains(r#"<meta property="og:site_name" content="mastodon.social">"#));
+        assert!(html.contains(
+            r#"<div class="paperoni-post"><p>First post</p></div><div class="paperoni-post"><p>Second post</p></div><div class="paperoni-post"><p>Third post</p></div>"#
+        ));
+        assert!(!html.contains("Nice thread"));
+    }
+}
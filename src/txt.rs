@@ -0,0 +1,351 @@
+use std::{collections::HashSet, fs};
+
+use comfy_table::{Attribute, Cell, CellAlignment, Color, ContentArrangement, Table};
+use indicatif::{ProgressBar, ProgressStyle};
+use kuchiki::{NodeData, NodeRef};
+use log::{debug, error};
+
+use crate::{
+    catalog::{add_metadata_row, MetadataRow},
+    cli::AppConfig,
+    errors::PaperoniError,
+    extractor::Article,
+};
+
+lazy_static! {
+    static ref WHITESPACE_REGEX: regex::Regex = regex::Regex::new(r"\s+").unwrap();
+    static ref BLANK_LINES_REGEX: regex::Regex = regex::Regex::new(r"\n{3,}").unwrap();
+}
+
+/// Elements whose content is separated from the surrounding text by a blank line
+const BLOCK_ELEMENTS: [&str; 21] = [
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "dd",
+    "div",
+    "dl",
+    "dt",
+    "figcaption",
+    "figure",
+    "footer",
+    "header",
+    "main",
+    "nav",
+    "ol",
+    "p",
+    "section",
+    "table",
+    "tbody",
+    "thead",
+    "ul",
+];
+
+pub fn generate_txt_exports(
+    articles: Vec<Article>,
+    app_config: &AppConfig,
+    successful_articles_table: &mut Table,
+    exported_files: &mut Vec<String>,
+    metadata_rows: &mut Vec<MetadataRow>,
+) -> Result<(), Vec<PaperoniError>> {
+    if articles.is_empty() {
+        return Ok(());
+    }
+
+    let bar = if app_config.can_disable_progress_bar {
+        ProgressBar::hidden()
+    } else {
+        let enabled_bar = ProgressBar::new(articles.len() as u64);
+        let style = ProgressStyle::default_bar().template(
+            "{spinner:.cyan} [{elapsed_precise}] {bar:40.white} {:>8} txt {pos}/{len:7} {msg:.green}",
+        );
+        enabled_bar.set_style(style);
+        if !articles.is_empty() {
+            enabled_bar.set_message("Generating text files");
+        }
+        enabled_bar
+    };
+
+    let mut errors: Vec<PaperoniError> = Vec::new();
+
+    match app_config.merged {
+        Some(ref name) => {
+            successful_articles_table.set_header(vec![Cell::new("Table of Contents")
+                .add_attribute(Attribute::Bold)
+                .set_alignment(CellAlignment::Center)
+                .fg(Color::Green)]);
+
+            debug!("Creating {:?}", name);
+            let mut merged_text = String::new();
+            for article in &articles {
+                if !merged_text.is_empty() {
+                    merged_text.push_str("\n\n");
+                }
+                merged_text.push_str(&article_to_text(article));
+                bar.inc(1);
+                successful_articles_table.add_row(vec![article.metadata().title()]);
+            }
+
+            if let Err(err) = fs::write(name, merged_text) {
+                error!("Failed to write articles to file: {}", name);
+                let mut paperoni_err: PaperoniError = err.into();
+                paperoni_err.set_article_source(name);
+                errors.push(paperoni_err);
+                bar.finish_with_message("txt generation failed");
+                return Err(errors);
+            }
+
+            bar.finish_with_message("Generated txt file\n");
+            debug!("Created {:?}", name);
+            for article in &articles {
+                add_metadata_row(metadata_rows, app_config, article, name);
+            }
+            if app_config.can_print_to_stdout() {
+                println!("Created {:?}", name);
+            }
+        }
+        None => {
+            successful_articles_table
+                .set_header(vec![Cell::new("Downloaded articles")
+                    .add_attribute(Attribute::Bold)
+                    .set_alignment(CellAlignment::Center)
+                    .fg(Color::Green)])
+                .set_content_arrangement(ContentArrangement::Dynamic);
+
+            let mut file_names: HashSet<String> = HashSet::new();
+
+            for article in articles.iter() {
+                let output_dir = app_config.output_directory.as_deref().unwrap_or(".");
+                let title = app_config.numbered_file_stem(
+                    article.file_stem(app_config.is_including_url_in_title),
+                    article.url_index + 1,
+                );
+                let mut file_name = format!("{}/{}.txt", output_dir, title);
+                if file_names.contains(&file_name) {
+                    debug!("Article name {:?} already exists", file_name);
+                    file_name = format!("{}/{}_{}.txt", output_dir, title, file_names.len());
+                }
+                file_names.insert(file_name.clone());
+
+                debug!("Creating {:?}", file_name);
+                if let Err(err) = fs::write(&file_name, article_to_text(article)) {
+                    let mut paperoni_err: PaperoniError = err.into();
+                    paperoni_err.set_article_source(&article.url);
+                    errors.push(paperoni_err);
+                } else {
+                    debug!("Created {:?}", file_name);
+                    add_metadata_row(metadata_rows, app_config, article, &file_name);
+                    exported_files.push(file_name);
+                }
+
+                bar.inc(1);
+                successful_articles_table.add_row(vec![article.metadata().title()]);
+            }
+            bar.finish_with_message("Generated txt files\n");
+        }
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Converts an article to plain text starting with its underlined title and source
+fn article_to_text(article: &Article) -> String {
+    let title = article.metadata().title();
+    let content_ref = article
+        .node_ref()
+        .select_first("body")
+        .map(|body_ref| body_ref.as_node().clone())
+        .unwrap_or_else(|_| article.node_ref().clone());
+    format!(
+        "{}\n{}\n\nSource: {}\n\n{}\n",
+        title,
+        "=".repeat(title.chars().count()),
+        article.url,
+        node_to_text(&content_ref)
+    )
+}
+
+/// Converts the text in a node to plain text. Block elements are separated by blank lines,
+/// headings are underlined, list items are prefixed with markers and images are replaced with
+/// their alt text.
+fn node_to_text(node_ref: &NodeRef) -> String {
+    let mut text = String::new();
+    write_text(node_ref, &mut text);
+    BLANK_LINES_REGEX
+        .replace_all(text.trim(), "\n\n")
+        .into_owned()
+}
+
+fn write_text(node_ref: &NodeRef, text: &mut String) {
+    let element = match node_ref.data() {
+        NodeData::Text(content) => {
+            let content = content.borrow();
+            let content = WHITESPACE_REGEX.replace_all(&content, " ");
+            if text.is_empty() || text.ends_with('\n') || text.ends_with(' ') {
+                text.push_str(content.trim_start());
+            } else {
+                text.push_str(&content);
+            }
+            return;
+        }
+        NodeData::Element(element) => element,
+        _ => {
+            write_children_text(node_ref, text);
+            return;
+        }
+    };
+
+    let tag_name = &*element.name.local;
+    match tag_name {
+        "head" | "script" | "style" | "noscript" | "template" => (),
+        "br" => end_line(text),
+        "img" => {
+            if let Some(alt) = element.attributes.borrow().get("alt") {
+                if !alt.trim().is_empty() {
+                    text.push_str(&format!("[{}]", alt.trim()));
+                }
+            }
+        }
+        "pre" => {
+            end_block(text);
+            text.push_str(node_ref.text_contents().trim_end());
+            end_block(text);
+        }
+        "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+            let mut heading = String::new();
+            write_children_text(node_ref, &mut heading);
+            let heading = heading.trim().replace('\n', " ");
+            if !heading.is_empty() {
+                let underline = if tag_name == "h1" || tag_name == "h2" {
+                    "="
+                } else {
+                    "-"
+                };
+                end_block(text);
+                text.push_str(&heading);
+                text.push('\n');
+                text.push_str(&underline.repeat(heading.chars().count()));
+                end_block(text);
+            }
+        }
+        "li" => {
+            end_line(text);
+            let is_ordered = node_ref
+                .parent()
+                .and_then(|parent| parent.as_element().map(|el| &el.name.local == "ol"))
+                .unwrap_or(false);
+            if is_ordered {
+                let position = node_ref
+                    .preceding_siblings()
+                    .filter(|sibling| {
+                        sibling
+                            .as_element()
+                            .map_or(false, |el| &el.name.local == "li")
+                    })
+                    .count()
+                    + 1;
+                text.push_str(&format!("{}. ", position));
+            } else {
+                text.push_str("- ");
+            }
+            write_children_text(node_ref, text);
+            end_line(text);
+        }
+        "tr" => {
+            end_line(text);
+            write_children_text(node_ref, text);
+            end_line(text);
+        }
+        "td" | "th" => {
+            write_children_text(node_ref, text);
+            text.push(' ');
+        }
+        "hr" => {
+            end_block(text);
+            text.push_str("* * *");
+            end_block(text);
+        }
+        _ if BLOCK_ELEMENTS.contains(&tag_name) => {
+            end_block(text);
+            write_children_text(node_ref, text);
+            end_block(text);
+        }
+        _ => write_children_text(node_ref, text),
+    }
+}
+
+fn write_children_text(node_ref: &NodeRef, text: &mut String) {
+    for child in node_ref.children() {
+        write_text(&child, text);
+    }
+}
+
+/// Starts a new line unless the text is already at the start of one
+fn end_line(text: &mut String) {
+    let trimmed_len = text.trim_end_matches(' ').len();
+    text.truncate(trimmed_len);
+    if !(text.is_empty() || text.ends_with('\n')) {
+        text.push('\n');
+    }
+}
+
+/// Separates the next block from the text with a blank line
+fn end_block(text: &mut String) {
+    end_line(text);
+    if !(text.is_empty() || text.ends_with("\n\n")) {
+        text.push('\n');
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use kuchiki::traits::*;
+
+    use super::node_to_text;
+
+    #[test]
+    fn test_node_to_text() {
+        let html_str = r#"
+        <!DOCTYPE html>
+        <html>
+            <head><title>Pepperoni</title></head>
+            <body>
+                <h1>Pepperoni</h1>
+                <p>Pepperoni is an American   variety of
+                    <em>salami</em>.</p>
+                <img src="pepperoni.jpg" alt="Slices of pepperoni">
+                <h3>Ingredients</h3>
+                <ol>
+                    <li>Pork</li>
+                    <li>Beef</li>
+                </ol>
+                <ul><li>Paprika</li></ul>
+                <pre>fn main() {
+    cure();
+}</pre>
+                <p>First line<br>Second line</p>
+                <script>console.log("ignored")</script>
+            </body>
+        </html>
+        "#;
+        let doc = kuchiki::parse_html().one(html_str);
+        let body = doc.select_first("body").unwrap();
+
+        assert_eq!(
+            "Pepperoni\n=========\n\n\
+            Pepperoni is an American variety of salami.\n\n\
+            [Slices of pepperoni]\n\n\
+            Ingredients\n-----------\n\n\
+            1. Pork\n2. Beef\n\n\
+            - Paprika\n\n\
+            fn main() {\n    cure();\n}\n\n\
+            First line\nSecond line",
+            node_to_text(body.as_node())
+        );
+    }
+}